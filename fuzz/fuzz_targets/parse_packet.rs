@@ -0,0 +1,32 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/protocol/mod.rs"]
+mod protocol;
+
+use protocol::byte_packet_buffer::{BytePacketBuffer, STREAM_MAX_SIZE};
+use protocol::dns_packet::DnsPacket;
+
+fuzz_target!(|data: &[u8]| {
+    // Whatever the input, reading may fail but must never panic
+    let _ = DnsPacket::from_buffer_strict(&mut BytePacketBuffer::from_bytes(data));
+    let Ok(mut packet) = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(data)) else {
+        return;
+    };
+
+    // Anything that was read has to survive being written and read back
+    let mut buffer = BytePacketBuffer::with_size(STREAM_MAX_SIZE);
+    if packet.write(&mut buffer).is_err() {
+        return;
+    }
+    let written = &buffer.buf[..buffer.pos()];
+    let reread = DnsPacket::from_buffer_strict(&mut BytePacketBuffer::from_bytes(written))
+        .expect("a written packet reads back");
+    assert_eq!(packet.questions.len(), reread.questions.len());
+    assert_eq!(packet.answers, reread.answers);
+    assert_eq!(packet.authorities, reread.authorities);
+    assert_eq!(packet.resources, reread.resources);
+    assert_eq!(packet.edns, reread.edns);
+});
@@ -0,0 +1,38 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/protocol/mod.rs"]
+mod protocol;
+
+use protocol::byte_packet_buffer::{BytePacketBuffer, STREAM_MAX_SIZE};
+use protocol::dns_record::DnsRecord;
+use protocol::query_type::QueryType;
+
+fuzz_target!(|data: &[u8]| {
+    // The first two bytes pick the type, the rest is the zone file text
+    let Some((qtype, text)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let Ok(text) = std::str::from_utf8(text) else {
+        return;
+    };
+    let qtype = QueryType::from_num(u16::from_be_bytes(*qtype));
+    let Ok(record) = DnsRecord::from_text("fuzz.example", qtype, text, 300) else {
+        return;
+    };
+
+    // A record that was accepted has to be writable, and read back the same
+    let mut buffer = BytePacketBuffer::with_size(STREAM_MAX_SIZE);
+    record.write(&mut buffer).expect("an accepted record can be written");
+    buffer.seek(0).unwrap();
+    let reread = DnsRecord::read(&mut buffer).expect("a written record reads back");
+    assert_eq!(record, reread);
+
+    // And so does its presentation format
+    let text = record.rdata_text();
+    let reparsed = DnsRecord::from_text("fuzz.example", qtype, &text, 300)
+        .expect("presentation format parses back");
+    assert_eq!(record, reparsed);
+});
@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use ring::digest::{digest, SHA256};
+use serde_derive::{Deserialize, Serialize};
+
+/// What we know about a downloaded list besides its contents
+#[derive(Serialize, Deserialize)]
+pub struct CachedList {
+    pub source: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// The last successful download of each list, kept in a directory so the lists are there
+/// at start even if the network isn't
+pub struct ListCache {
+    dir: PathBuf,
+}
+
+impl ListCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The copy of the list downloaded from `source`, if there is one
+    pub async fn load(&self, source: &str) -> Option<(String, CachedList)> {
+        let (content_path, meta_path) = self.paths(source);
+        let meta = tokio::fs::read(&meta_path).await.ok()?;
+        let meta: CachedList = serde_json::from_slice(&meta).ok()?;
+        // Two sources sharing a file name would take a lot of bad luck, but check anyway
+        if meta.source != source {
+            return None;
+        }
+        let content = tokio::fs::read_to_string(&content_path).await.ok()?;
+        Some((content, meta))
+    }
+
+    /// Keep `content` as the latest copy of its list, replacing the old one in one go
+    pub async fn store(&self, content: &str, meta: &CachedList) -> std::io::Result<PathBuf> {
+        let (content_path, meta_path) = self.paths(&meta.source);
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let meta = serde_json::to_vec(meta)?;
+        for (path, data) in [(&content_path, content.as_bytes()), (&meta_path, &meta)] {
+            let partial = path.with_extension("partial");
+            tokio::fs::write(&partial, data).await?;
+            tokio::fs::rename(&partial, path).await?;
+        }
+        Ok(content_path)
+    }
+
+    /// Where the contents and the details of a list go, named after a hash of its source
+    fn paths(&self, source: &str) -> (PathBuf, PathBuf) {
+        let hash: String = digest(&SHA256, source.as_bytes()).as_ref()[..16]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        (
+            self.dir.join(format!("{}.txt", hash)),
+            self.dir.join(format!("{}.json", hash)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(source: &str, etag: Option<&str>) -> CachedList {
+        CachedList {
+            source: source.to_string(),
+            etag: etag.map(str::to_string),
+            last_modified: Some("Thu, 01 Jan 2026 00:00:00 GMT".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_round_trip() {
+        let dir = std::env::temp_dir().join(format!("mindns-list-cache-{}", std::process::id()));
+        let cache = ListCache::new(dir.clone());
+        let source = "https://lists.example/ads.txt";
+        assert!(cache.load(source).await.is_none());
+
+        cache
+            .store("ads.example\n", &meta(source, Some("\"v1\"")))
+            .await
+            .unwrap();
+        cache
+            .store("tracker.example\n", &meta(source, Some("\"v2\"")))
+            .await
+            .unwrap();
+        let (content, loaded) = cache.load(source).await.unwrap();
+        assert_eq!(content, "tracker.example\n");
+        assert_eq!(loaded.source, source);
+        assert_eq!(loaded.etag.as_deref(), Some("\"v2\""));
+        assert_eq!(
+            loaded.last_modified.as_deref(),
+            Some("Thu, 01 Jan 2026 00:00:00 GMT")
+        );
+        assert!(cache
+            .load("https://lists.example/other.txt")
+            .await
+            .is_none());
+
+        // A copy whose details name another source isn't handed out for this one
+        let other = "https://lists.example/other.txt";
+        let (content_path, meta_path) = cache.paths(source);
+        let (other_content, other_meta) = cache.paths(other);
+        std::fs::rename(content_path, other_content).unwrap();
+        std::fs::rename(meta_path, other_meta).unwrap();
+        assert!(cache.load(other).await.is_none());
+        assert!(cache.load(source).await.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
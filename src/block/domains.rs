@@ -0,0 +1,42 @@
+use std::collections::HashSet;
+
+/// Domain names, each standing either for itself or for itself and everything below it
+///
+/// Looking a host up takes one hash lookup for each of its labels, no matter how many
+/// names are in the set, which matters for public lists with a million entries.
+#[derive(Default)]
+pub struct DomainSet {
+    exact: HashSet<Box<str>>,
+    subdomains: HashSet<Box<str>>,
+}
+
+impl DomainSet {
+    /// Add `domain`, along with all of its subdomains if `subdomains` is set, returns
+    /// whether it wasn't in the set yet
+    pub fn insert(&mut self, domain: &str, subdomains: bool) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase().into();
+        match subdomains {
+            true => self.subdomains.insert(domain),
+            false => self.exact.insert(domain),
+        }
+    }
+
+    /// Whether `host` is in the set, given in lowercase
+    pub fn contains(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        if self.exact.contains(host) {
+            return true;
+        }
+        // Walk up one whole label at a time, so `ads.com` never matches `myads.com`
+        let mut suffix = host;
+        loop {
+            if self.subdomains.contains(suffix) {
+                return true;
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => return false,
+            }
+        }
+    }
+}
@@ -1,172 +1,414 @@
-use std::{collections::HashSet, sync::Arc};
+mod cache;
+mod domains;
+mod rules;
+#[cfg(test)]
+mod tests;
 
-use tokio::sync::RwLock;
-use tracing::info;
+use std::{
+    collections::HashSet,
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-pub struct BlockRegex {
-    source: String,
-    regex: regex::Regex,
+use regex::RegexSet;
+use reqwest::{header, Client, StatusCode};
+use tokio::sync::{Mutex, RwLock};
+use tracing::{debug, info, warn};
+
+use self::{
+    cache::{CachedList, ListCache},
+    domains::DomainSet,
+    rules::{parse_list, Rule},
+};
+
+/// How many regexes are compiled into one set at most, as a set that grows too large
+/// fails to compile as a whole
+const REGEX_SET_SIZE: usize = 1000;
+
+/// Regexes compiled into a few sets, so a host is checked against all of them in a few
+/// passes
+#[derive(Default)]
+pub struct BlockRegexes {
+    sources: Vec<String>,
+    known: HashSet<String>,
+    sets: Vec<RegexSet>,
 }
 
-impl BlockRegex {
-    pub fn new(source: &str) -> Result<BlockRegex, regex::Error> {
-        let regex = regex::Regex::new(source)?;
-        Ok(BlockRegex {
-            source: source.to_string(),
-            regex,
-        })
+impl BlockRegexes {
+    /// Add regexes and compile the sets again, invalid ones are left out
+    ///
+    /// Hosts are matched in lowercase, so the regexes are lowercased as well, except for
+    /// escapes such as `\D` whose meaning depends on the case.
+    pub fn extend(&mut self, sources: Vec<String>) {
+        for source in sources {
+            let source = lowercase_regex(&source);
+            if self.known.contains(&source) {
+                continue;
+            }
+            match regex::Regex::new(&source) {
+                Ok(_) => {
+                    self.known.insert(source.clone());
+                    self.sources.push(source);
+                }
+                Err(err) => warn!("Ignoring block regex {}: {}", source, err),
+            }
+        }
+        self.sets.clear();
+        for chunk in self.sources.chunks(REGEX_SET_SIZE) {
+            compile_sets(chunk, &mut self.sets);
+        }
+    }
+
+    pub fn is_match(&self, host: &str) -> bool {
+        self.sets.iter().any(|set| set.is_match(host))
+    }
+}
+
+/// Compile `sources` into as few sets as fit, only a regex that is too large on its own
+/// is left out
+fn compile_sets(sources: &[String], sets: &mut Vec<RegexSet>) {
+    match RegexSet::new(sources) {
+        Ok(set) => sets.push(set),
+        Err(err) if sources.len() == 1 => warn!("Ignoring block regex {}: {}", sources[0], err),
+        Err(_) => {
+            let (first, second) = sources.split_at(sources.len() / 2);
+            compile_sets(first, sets);
+            compile_sets(second, sets);
+        }
     }
 }
 
-impl PartialEq for BlockRegex {
-    fn eq(&self, other: &Self) -> bool {
-        self.source == other.source
+/// The regex in lowercase, leaving the letter after a backslash alone
+fn lowercase_regex(source: &str) -> String {
+    let mut lowercase = String::with_capacity(source.len());
+    let mut escaped = false;
+    for c in source.chars() {
+        lowercase.push(match escaped {
+            true => c,
+            false => c.to_ascii_lowercase(),
+        });
+        escaped = !escaped && c == '\\';
     }
+    lowercase
 }
 
-impl Eq for BlockRegex {}
+/// Everything the block lists say, built in one go so it can be swapped in as a whole
+#[derive(Default)]
+pub struct BlockRules {
+    blocks: DomainSet,
+    allows: DomainSet,
+    regex: BlockRegexes,
+    allow_regex: BlockRegexes,
+}
 
-impl std::hash::Hash for BlockRegex {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.source.hash(state);
+impl BlockRules {
+    /// The rules of every list given as its name and parsed rules, an exception in any
+    /// list lets a host through that any other list blocks
+    pub fn build<'a>(lists: impl IntoIterator<Item = (&'a str, &'a [Rule])>) -> Self {
+        let mut rules = BlockRules::default();
+        let mut regexes = Vec::new();
+        let mut allow_regexes = Vec::new();
+        for (name, list) in lists {
+            let mut blocked = 0;
+            for rule in list {
+                match rule {
+                    Rule::Block { host, subdomains } => {
+                        rules.blocks.insert(host, *subdomains);
+                        blocked += 1;
+                    }
+                    Rule::Allow { host, subdomains } => {
+                        rules.allows.insert(host, *subdomains);
+                    }
+                    Rule::BlockRegex(regex) => {
+                        regexes.push(regex.clone());
+                        blocked += 1;
+                    }
+                    Rule::AllowRegex(regex) => allow_regexes.push(regex.clone()),
+                }
+            }
+            info!("Blocked {} hosts from {}", blocked, name);
+        }
+        rules.regex.extend(regexes);
+        rules.allow_regex.extend(allow_regexes);
+        rules
+    }
+
+    /// Whether queries for `host`, given in lowercase, should be blocked
+    pub fn is_blocked(&self, host: &str) -> bool {
+        let blocked = self.blocks.contains(host) || self.regex.is_match(host);
+        blocked && !(self.allows.contains(host) || self.allow_regex.is_match(host))
     }
 }
 
+/// How long downloading a single list may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A block list, with the last copy of it that could be loaded
+#[derive(Clone)]
+struct BlockList {
+    source: String,
+    /// The rules of the last copy that could be loaded, kept when loading the list fails
+    /// later on so they stay in effect
+    rules: Option<Arc<Vec<Rule>>>,
+    /// What the server said about that copy, so it only has to send a newer one
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// When a list file was changed when it was read
+    modified: Option<SystemTime>,
+    origin: Option<ListOrigin>,
+}
+
+impl BlockList {
+    fn is_file(&self) -> bool {
+        !self.source.starts_with("http")
+    }
+}
+
+/// Where the copy of a list in use came from
+#[derive(Clone, Copy)]
+enum ListOrigin {
+    File,
+    Network,
+    Cache,
+}
+
+impl fmt::Display for ListOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListOrigin::File => write!(f, "file"),
+            ListOrigin::Network => write!(f, "network"),
+            ListOrigin::Cache => write!(f, "cache"),
+        }
+    }
+}
+
+enum Fetched {
+    Changed(String),
+    Unchanged,
+}
+
 #[derive(Clone)]
 pub struct Blocker {
     data: Arc<BlockerData>,
 }
 
 impl Blocker {
-    pub fn new(lists: Vec<String>) -> Self {
+    /// Downloaded lists are kept in `cache_dir`, if given, and fetched through `client`
+    pub fn new(lists: Vec<String>, cache_dir: Option<PathBuf>, client: Client) -> Self {
         Self {
-            data: Arc::new(BlockerData::new(lists)),
+            data: Arc::new(BlockerData::new(lists, cache_dir, client)),
         }
     }
 
-    pub async fn block(&self, host: &str, subdomains: bool) {
-        self.data.blocks.write().await.push(BlockedDomain {
-            host: host.to_string(),
-            subdomains,
-        });
+    /// Whether queries for `host`, given in lowercase, should be blocked
+    pub async fn is_blocked(&self, host: &str) -> bool {
+        let rules = self.data.rules.read().await.clone();
+        rules.is_blocked(host)
     }
 
-    pub async fn unblock(&self, host: &str) {
-        self.data.allows.write().await.push(host.to_string());
+    /// Load the lists from files and from the copies downloaded before, and put them in
+    /// effect, so hosts are blocked before the network is there
+    pub async fn load_cache(&self) {
+        let mut lists = self.data.lists.lock().await;
+        for list in lists.iter_mut() {
+            if list.is_file() {
+                match self.fetch(list).await {
+                    Ok(Fetched::Changed(content)) => list.rules = Some(parse(content).await),
+                    Ok(Fetched::Unchanged) => {}
+                    Err(err) => warn!("Failed to load block list: {}", err),
+                }
+                list.origin = list.rules.is_some().then_some(ListOrigin::File);
+            } else if let Some(cache) = &self.data.cache {
+                match cache.load(&list.source).await {
+                    Some((content, meta)) => {
+                        list.rules = Some(parse(content).await);
+                        list.etag = meta.etag;
+                        list.last_modified = meta.last_modified;
+                        list.origin = Some(ListOrigin::Cache);
+                    }
+                    None => info!("No copy of block list {} in the cache yet", list.source),
+                }
+            }
+        }
+        drop(lists);
+        self.swap_rules().await;
     }
 
-    pub async fn is_blocked(&self, host: &str) -> bool {
-        let blocked = if self.data.blocks.read().await.iter().any(|b| {
-            if b.subdomains {
-                host.ends_with(&b.host)
-            } else {
-                host == b.host
+    /// Load every list that changed since the last time, and swap in new rules if any did
+    ///
+    /// Downloads can take minutes, so they work on a copy of the lists, which are only
+    /// locked again to put the new copies in.
+    pub async fn process_lists(&self) {
+        let mut lists = self.data.lists.lock().await.clone();
+        let mut changed = false;
+        for list in lists.iter_mut() {
+            match self.fetch(list).await {
+                Ok(Fetched::Changed(content)) => {
+                    list.origin = Some(match list.is_file() {
+                        true => ListOrigin::File,
+                        false => ListOrigin::Network,
+                    });
+                    if let (Some(cache), false) = (&self.data.cache, list.is_file()) {
+                        let meta = CachedList {
+                            source: list.source.clone(),
+                            etag: list.etag.clone(),
+                            last_modified: list.last_modified.clone(),
+                        };
+                        match cache.store(&content, &meta).await {
+                            Ok(path) => {
+                                debug!("Cached block list {} at {}", list.source, path.display())
+                            }
+                            Err(err) => {
+                                warn!("Failed to cache block list {}: {}", list.source, err)
+                            }
+                        }
+                    }
+                    list.rules = Some(parse(content).await);
+                    changed = true;
+                }
+                Ok(Fetched::Unchanged) => debug!("Block list {} is unchanged", list.source),
+                Err(err) if list.rules.is_some() => {
+                    warn!(
+                        "Failed to refresh block list, keeping the last copy: {}",
+                        err
+                    )
+                }
+                Err(err) => warn!("Failed to load block list: {}", err),
             }
-        }) {
-            true
-        } else {
-            self.data
-                .regex
-                .read()
-                .await
-                .iter()
-                .any(|r| r.regex.is_match(host))
-        };
-        if !blocked {
-            return false;
         }
-        !self.data
-            .allows
-            .read()
+        if changed {
+            *self.data.lists.lock().await = lists;
+            self.swap_rules().await;
+        }
+    }
+
+    /// Build the rules from the lists as they are now, and put them in effect
+    async fn swap_rules(&self) {
+        let lists: Vec<(String, Arc<Vec<Rule>>)> = self
+            .data
+            .lists
+            .lock()
             .await
             .iter()
-            .all(|a| !host.ends_with(a))
+            .filter_map(|list| {
+                let name = format!("{} ({})", list.source, list.origin?);
+                Some((name, list.rules.clone()?))
+            })
+            .collect();
+        // Indexing a million rules takes a while, queries keep using the old rules meanwhile
+        let rules = tokio::task::spawn_blocking(move || {
+            BlockRules::build(
+                lists
+                    .iter()
+                    .map(|(source, rules)| (source.as_str(), rules.as_slice())),
+            )
+        })
+        .await;
+        match rules {
+            Ok(rules) => *self.data.rules.write().await = Arc::new(rules),
+            Err(err) => warn!("Failed to build the block rules: {}", err),
+        }
     }
 
-    async fn parse_hosts(&self, content: &str) -> u64 {
-        let mut blocked = 0;
-        for line in content.lines() {
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
-            }
+    /// Load the lists again every `interval`, lists that didn't change aren't downloaded
+    /// again
+    pub async fn refresh(self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            debug!("Refreshing block lists");
+            self.process_lists().await;
+        }
+    }
 
-            if let Some(host) = line.strip_prefix("||") {
-                let host = host.split_once("^").map(|(host, _)| host).unwrap_or(host);
-                self.block(host, true).await;
-                blocked += 1;
-            } else if let Some(host) = line.strip_prefix("@@") {
-                if let Some(host) = host.strip_prefix("||") {
-                    let host = host.split_once("^").map(|(host, _)| host).unwrap_or(host);
-                    self.unblock(host).await;
-                } else {
-                    let host = host.split_once("^").map(|(host, _)| host).unwrap_or(host);
-                    self.unblock(host).await;
-                }
-            } else if line.starts_with("/") {
-                let regex = &line[1..line.len() - 1];
-                let compiled = BlockRegex::new(regex);
-                if let Ok(compiled) = compiled {
-                    self.data.regex.write().await.insert(compiled);
-                }
-            } else if let Some(host) = line.strip_prefix("127.0.0.1") {
-                self.block(host.trim(), false).await;
-                blocked += 1;
-            } else if line.starts_with("!") || line.starts_with("#") {
-                // ignore comments
-            } else if !line.contains(" ") {
-                let host = line.split_once("^").map(|(host, _)| host).unwrap_or(line);
-                self.block(host, false).await;
-                blocked += 1;
-            } else {
-                eprintln!("Unknown line: {}", line);
+    async fn fetch(&self, list: &mut BlockList) -> Result<Fetched, String> {
+        if list.is_file() {
+            let modified = tokio::fs::metadata(&list.source)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            if list.rules.is_some() && modified.is_some() && modified == list.modified {
+                return Ok(Fetched::Unchanged);
             }
+            let content = tokio::fs::read_to_string(&list.source)
+                .await
+                .map_err(|err| format!("{}: {}", list.source, err))?;
+            list.modified = modified;
+            return Ok(Fetched::Changed(content));
         }
-        blocked
-    }
 
-    pub async fn process_lists(&self) {
-        for list in &self.data.lists {
-            if list.starts_with("http") {
-                let response = reqwest::get(list).await;
-                if let Ok(response) = response {
-                    let content = response.text().await;
-                    if let Ok(content) = content {
-                        let blocked = self.parse_hosts(&content).await;
-                        info!("Blocked {} hosts from {}", blocked, list);
-                    }
-                }
-            } else {
-                let content = tokio::fs::read_to_string(list).await;
-                if let Ok(content) = content {
-                    let blocked = self.parse_hosts(&content).await;
-                    info!("Blocked {} hosts from {}", blocked, list);
-                }
+        let mut request = self.data.client.get(&list.source).timeout(FETCH_TIMEOUT);
+        if list.rules.is_some() {
+            if let Some(etag) = &list.etag {
+                request = request.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &list.last_modified {
+                request = request.header(header::IF_MODIFIED_SINCE, last_modified);
             }
         }
+        let response = request
+            .send()
+            .await
+            .map_err(|err| format!("{}: {}", list.source, err))?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Fetched::Unchanged);
+        }
+        if !response.status().is_success() {
+            return Err(format!("{} answered {}", list.source, response.status()));
+        }
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(header::ETAG);
+        let last_modified = header(header::LAST_MODIFIED);
+        let content = response
+            .text()
+            .await
+            .map_err(|err| format!("{}: {}", list.source, err))?;
+        list.etag = etag;
+        list.last_modified = last_modified;
+        Ok(Fetched::Changed(content))
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
-pub struct BlockedDomain {
-    host: String,
-    subdomains: bool,
+/// Parse the lines of a list off the runtime threads, a million of them take a while
+async fn parse(content: String) -> Arc<Vec<Rule>> {
+    tokio::task::spawn_blocking(move || Arc::new(parse_list(&content)))
+        .await
+        .unwrap_or_default()
 }
 
 pub struct BlockerData {
-    lists: Vec<String>,
-    blocks: RwLock<Vec<BlockedDomain>>,
-    allows: RwLock<Vec<String>>,
-    regex: RwLock<HashSet<BlockRegex>>,
+    lists: Mutex<Vec<BlockList>>,
+    rules: RwLock<Arc<BlockRules>>,
+    client: Client,
+    cache: Option<ListCache>,
 }
 
 impl BlockerData {
-    pub fn new(lists: Vec<String>) -> Self {
+    pub fn new(lists: Vec<String>, cache_dir: Option<PathBuf>, client: Client) -> Self {
         Self {
-            lists,
-            blocks: RwLock::new(Vec::new()),
-            allows: RwLock::new(Vec::new()),
-            regex: RwLock::new(HashSet::new()),
+            lists: Mutex::new(
+                lists
+                    .into_iter()
+                    .map(|source| BlockList {
+                        source,
+                        rules: None,
+                        etag: None,
+                        last_modified: None,
+                        modified: None,
+                        origin: None,
+                    })
+                    .collect(),
+            ),
+            rules: RwLock::new(Arc::new(BlockRules::default())),
+            client,
+            cache: cache_dir.map(ListCache::new),
         }
     }
 }
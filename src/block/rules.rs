@@ -0,0 +1,172 @@
+use std::net::IpAddr;
+
+use tracing::debug;
+
+/// Names hosts files map to themselves, which are never meant to be blocked
+const HOSTS_LOCAL_NAMES: [&str; 8] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "0.0.0.0",
+];
+
+/// What a line of a block list asks for
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rule {
+    /// Block `host`, and every name below it if `subdomains` is set
+    Block { host: String, subdomains: bool },
+    /// Let `host` through even if it is blocked, and every name below it if `subdomains`
+    /// is set
+    Allow { host: String, subdomains: bool },
+    /// Block every host the regex finds a match in
+    BlockRegex(String),
+    /// Let every host through the regex finds a match in
+    AllowRegex(String),
+}
+
+/// Parse a line of a hosts file, a plain list of domains, or an adblock style list
+///
+/// - `0.0.0.0 ads.example` blocks exactly the names after the address, like a hosts file
+/// - `ads.example` blocks exactly that name
+/// - `||ads.example^` blocks the name and every name below it, but not `myads.example`
+/// - `|ads.example^` or `|ads.example|` blocks exactly that name
+/// - Any other pattern is matched the way adblockers do, where `*` stands for anything,
+///   `^` and a trailing `|` for the end of the name, a leading `|` for its start, and a
+///   leading `||` for the start of any of its labels
+/// - `/regex/` blocks every name the regex finds a match in
+/// - `@@` in front of any of the above lets the names through instead
+///
+/// Comments, cosmetic rules and empty lines give no rules, and lines that make no sense
+/// as a name filter give an error.
+pub fn parse_line(line: &str) -> Result<Vec<Rule>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('!') || line.starts_with('#') || line.starts_with('[') {
+        return Ok(Vec::new());
+    }
+
+    if let Some(hosts) = hosts_line(line) {
+        return Ok(hosts);
+    }
+
+    let (allow, pattern) = match line.strip_prefix("@@") {
+        Some(pattern) => (true, pattern),
+        None => (false, line),
+    };
+
+    // Element hiding and other rules that only make sense in a browser
+    if pattern.contains('#') {
+        return Ok(Vec::new());
+    }
+
+    if pattern.len() > 1 && pattern.starts_with('/') && pattern.ends_with('/') {
+        let regex = pattern[1..pattern.len() - 1].to_string();
+        return Ok(vec![match allow {
+            true => Rule::AllowRegex(regex),
+            false => Rule::BlockRegex(regex),
+        }]);
+    }
+
+    let pattern = match pattern.split_once('$') {
+        Some((pattern, "important")) => pattern,
+        // Modifiers that narrow a rule down to some clients or types can't be ignored
+        // without blocking too much
+        Some(_) => return Err(format!("Unsupported modifiers in {}", line)),
+        None => pattern,
+    };
+    if pattern.is_empty() {
+        return Err(format!("Empty pattern in {}", line));
+    }
+
+    Ok(vec![match (domain_pattern(pattern), allow) {
+        (Some((host, subdomains)), false) => Rule::Block { host, subdomains },
+        (Some((host, subdomains)), true) => Rule::Allow { host, subdomains },
+        (None, false) => Rule::BlockRegex(pattern_regex(pattern)),
+        (None, true) => Rule::AllowRegex(pattern_regex(pattern)),
+    }])
+}
+
+/// The rules of every line of a list, lines that can't be parsed are skipped
+pub fn parse_list(content: &str) -> Vec<Rule> {
+    let mut rules = Vec::new();
+    for line in content.lines() {
+        match parse_line(line) {
+            Ok(parsed) => rules.extend(parsed),
+            Err(err) => debug!("Skipping block list line: {}", err),
+        }
+    }
+    rules
+}
+
+/// The names of a hosts file line, such as `0.0.0.0 ads.example tracker.example`
+fn hosts_line(line: &str) -> Option<Vec<Rule>> {
+    let line = line.split_once('#').map_or(line, |(line, _)| line);
+    let mut fields = line.split_whitespace();
+    let address = fields.next()?;
+    // Link local addresses come with a zone, such as fe80::1%lo0
+    let address = address
+        .split_once('%')
+        .map_or(address, |(address, _)| address);
+    address.parse::<IpAddr>().ok()?;
+    Some(
+        fields
+            .filter(|host| !HOSTS_LOCAL_NAMES.contains(&host.to_ascii_lowercase().as_str()))
+            .map(|host| Rule::Block {
+                host: host.to_string(),
+                subdomains: false,
+            })
+            .collect(),
+    )
+}
+
+/// The domain an adblock pattern stands for, if it means nothing more than a single
+/// domain or a domain with everything below it
+fn domain_pattern(pattern: &str) -> Option<(String, bool)> {
+    let (host, subdomains) = if let Some(rest) = pattern.strip_prefix("||") {
+        (rest.strip_suffix('^')?, true)
+    } else if let Some(rest) = pattern.strip_prefix('|') {
+        (
+            rest.strip_suffix('^').or_else(|| rest.strip_suffix('|'))?,
+            false,
+        )
+    } else {
+        (pattern, false)
+    };
+    // A plain pattern ending in ^ matches the end of any name, not only whole labels
+    let is_domain = !host.is_empty()
+        && !host.starts_with('.')
+        && host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    is_domain.then(|| (host.to_string(), subdomains))
+}
+
+/// A regex that finds the names an adblock pattern matches
+fn pattern_regex(pattern: &str) -> String {
+    let (mut regex, rest) = if let Some(rest) = pattern.strip_prefix("||") {
+        (String::from(r"(?:^|\.)"), rest)
+    } else if let Some(rest) = pattern.strip_prefix('|') {
+        (String::from("^"), rest)
+    } else {
+        (String::new(), pattern)
+    };
+    let (rest, anchored_end) = match rest.strip_suffix('|') {
+        Some(rest) => (rest, true),
+        None => (rest, false),
+    };
+    for c in rest.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            // The only separator left in a bare name is its end
+            '^' => regex.push('$'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    if anchored_end {
+        regex.push('$');
+    }
+    regex
+}
@@ -0,0 +1,476 @@
+use std::{
+    collections::VecDeque,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use reqwest::Client;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use super::{
+    rules::{parse_line, parse_list, Rule},
+    BlockRules, Blocker,
+};
+
+fn block(host: &str, subdomains: bool) -> Rule {
+    Rule::Block {
+        host: host.to_string(),
+        subdomains,
+    }
+}
+
+fn allow(host: &str, subdomains: bool) -> Rule {
+    Rule::Allow {
+        host: host.to_string(),
+        subdomains,
+    }
+}
+
+/// Whether each host is blocked by a list made of `lines`
+fn blocked(lines: &str, hosts: &[&str]) -> Vec<bool> {
+    let rules = BlockRules::build([("test", parse_list(lines).as_slice())]);
+    hosts.iter().map(|host| rules.is_blocked(host)).collect()
+}
+
+#[test]
+fn hosts_lines_block_exactly_the_names() {
+    assert_eq!(
+        parse_line("0.0.0.0 ads.example"),
+        Ok(vec![block("ads.example", false)])
+    );
+    assert_eq!(
+        parse_line("127.0.0.1\tads.example tracker.example # both"),
+        Ok(vec![
+            block("ads.example", false),
+            block("tracker.example", false)
+        ])
+    );
+    assert_eq!(
+        parse_line(":: ads.example"),
+        Ok(vec![block("ads.example", false)])
+    );
+}
+
+#[test]
+fn hosts_lines_leave_local_names_alone() {
+    assert_eq!(parse_line("127.0.0.1 localhost"), Ok(vec![]));
+    assert_eq!(parse_line("255.255.255.255 broadcasthost"), Ok(vec![]));
+    assert_eq!(parse_line("fe80::1%lo0 localhost"), Ok(vec![]));
+    assert_eq!(parse_line("0.0.0.0 0.0.0.0"), Ok(vec![]));
+}
+
+#[test]
+fn plain_domains_block_exactly_the_name() {
+    assert_eq!(
+        parse_line("ads.example"),
+        Ok(vec![block("ads.example", false)])
+    );
+}
+
+#[test]
+fn domain_anchors() {
+    assert_eq!(
+        parse_line("||ads.example^"),
+        Ok(vec![block("ads.example", true)])
+    );
+    assert_eq!(
+        parse_line("|ads.example^"),
+        Ok(vec![block("ads.example", false)])
+    );
+    assert_eq!(
+        parse_line("|ads.example|"),
+        Ok(vec![block("ads.example", false)])
+    );
+    assert_eq!(
+        parse_line("||ads.example^$important"),
+        Ok(vec![block("ads.example", true)])
+    );
+}
+
+#[test]
+fn other_patterns_become_regexes() {
+    assert_eq!(
+        parse_line("||ads.example"),
+        Ok(vec![Rule::BlockRegex(r"(?:^|\.)ads\.example".to_string())])
+    );
+    assert_eq!(
+        parse_line("ads.example^"),
+        Ok(vec![Rule::BlockRegex(r"ads\.example$".to_string())])
+    );
+    assert_eq!(
+        parse_line("|ads*.example^"),
+        Ok(vec![Rule::BlockRegex(r"^ads.*\.example$".to_string())])
+    );
+    assert_eq!(
+        parse_line("/^ad[0-9]+\\./"),
+        Ok(vec![Rule::BlockRegex(r"^ad[0-9]+\.".to_string())])
+    );
+}
+
+#[test]
+fn exceptions() {
+    assert_eq!(
+        parse_line("@@||ok.example^"),
+        Ok(vec![allow("ok.example", true)])
+    );
+    assert_eq!(
+        parse_line("@@|ok.example^"),
+        Ok(vec![allow("ok.example", false)])
+    );
+    assert_eq!(
+        parse_line("@@ok.example"),
+        Ok(vec![allow("ok.example", false)])
+    );
+    assert_eq!(
+        parse_line("@@/^ok/"),
+        Ok(vec![Rule::AllowRegex("^ok".to_string())])
+    );
+}
+
+#[test]
+fn comments_and_browser_rules_are_skipped() {
+    for line in [
+        "",
+        "   ",
+        "! comment",
+        "# comment",
+        "[Adblock Plus 2.0]",
+        "example.com##.banner",
+        "example.com#@#.banner",
+    ] {
+        assert_eq!(parse_line(line), Ok(vec![]), "{:?}", line);
+    }
+}
+
+#[test]
+fn narrowing_modifiers_are_rejected() {
+    assert!(parse_line("||ads.example^$client=192.168.1.2").is_err());
+    assert!(parse_line("||ads.example^$dnstype=AAAA").is_err());
+}
+
+#[test]
+fn subdomain_rules_match_whole_labels() {
+    assert_eq!(
+        blocked(
+            "||ads.com^",
+            &[
+                "ads.com",
+                "x.ads.com",
+                "a.b.ads.com",
+                "myads.com",
+                "ads.com.evil",
+                "com"
+            ]
+        ),
+        [true, true, true, false, false, false]
+    );
+}
+
+#[test]
+fn exact_rules_match_only_the_name() {
+    for list in ["ads.com", "|ads.com^", "|ads.com|", "0.0.0.0 ads.com"] {
+        assert_eq!(
+            blocked(list, &["ads.com", "x.ads.com", "myads.com"]),
+            [true, false, false],
+            "{}",
+            list
+        );
+    }
+}
+
+#[test]
+fn unanchored_patterns_match_anywhere() {
+    assert_eq!(
+        blocked(
+            "ads.com^",
+            &["ads.com", "x.ads.com", "myads.com", "ads.com.evil"]
+        ),
+        [true, true, true, false]
+    );
+    assert_eq!(
+        blocked(
+            "||ads.com",
+            &["ads.com", "x.ads.com", "myads.com", "ads.community"]
+        ),
+        [true, true, false, true]
+    );
+    assert_eq!(
+        blocked("|ad*^", &["ad", "ads.com", "x.ads.com"]),
+        [true, true, false]
+    );
+}
+
+#[test]
+fn names_match_without_a_trailing_dot() {
+    assert_eq!(
+        blocked("||ads.com^\nexact.com", &["x.ads.com.", "exact.com."]),
+        [true, true]
+    );
+}
+
+#[test]
+fn exceptions_win_over_blocks() {
+    let list = "||ads.com^\n@@||ok.ads.com^\n@@|only.ads.com^\n@@/^re\\./";
+    assert_eq!(
+        blocked(
+            list,
+            &[
+                "x.ads.com",
+                "ok.ads.com",
+                "a.ok.ads.com",
+                "only.ads.com",
+                "a.only.ads.com",
+                "re.ads.com",
+                "notok.ads.com",
+            ]
+        ),
+        [true, false, false, false, true, false, true]
+    );
+}
+
+#[test]
+fn exceptions_match_whole_labels() {
+    assert_eq!(
+        blocked("||com^\n@@||ok.com^", &["ok.com", "notok.com"]),
+        [false, true]
+    );
+}
+
+#[test]
+fn regexes_find_matches_anywhere() {
+    assert_eq!(
+        blocked(
+            "/tracker/\n/[/",
+            &["tracker.com", "mytracker.net", "clean.com"]
+        ),
+        [true, true, false]
+    );
+}
+
+#[test]
+fn regexes_are_lowercased_like_hosts() {
+    assert_eq!(
+        blocked(
+            "/^Ads[0-9]\\./\n/^\\D+\\.Example$/",
+            &["ads1.com", "ads.com", "tracker.example", "1.example"]
+        ),
+        [true, false, true, false]
+    );
+}
+
+#[test]
+fn regexes_that_dont_fit_in_one_set_are_kept() {
+    // Each of these compiles, but two of them are too large for one set
+    let rules = BlockRules::build([(
+        "test",
+        parse_list("/^ads/\n/\\w{150}x/\n/\\w{150}y/\n/^tracker/\n/^ads/\n").as_slice(),
+    )]);
+    assert_eq!(rules.regex.sources.len(), 4);
+    assert!(rules.regex.sets.len() > 1);
+    assert!(rules.is_blocked("ads.example"));
+    assert!(rules.is_blocked("tracker.example"));
+    assert!(rules.is_blocked(&format!("{}y", "a".repeat(150))));
+    assert!(!rules.is_blocked("clean.example"));
+}
+
+/// How the list server answers a request
+struct Reply {
+    status: u16,
+    headers: &'static [(&'static str, &'static str)],
+    body: &'static str,
+}
+
+/// A block list served over HTTP on a local port, answering with the replies it is given
+/// in turn
+struct ListServer {
+    url: String,
+    replies: Arc<Mutex<VecDeque<Reply>>>,
+    /// The head of every request, in lowercase
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl ListServer {
+    async fn start() -> ListServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.txt", listener.local_addr().unwrap());
+        let replies: Arc<Mutex<VecDeque<Reply>>> = Arc::default();
+        let requests: Arc<Mutex<Vec<String>>> = Arc::default();
+        let (queue, log) = (replies.clone(), requests.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await.unwrap() {
+                        0 => break,
+                        size => head.extend_from_slice(&buf[..size]),
+                    }
+                }
+                log.lock()
+                    .unwrap()
+                    .push(String::from_utf8_lossy(&head).to_lowercase());
+
+                let reply = queue.lock().unwrap().pop_front().unwrap();
+                let mut response = format!(
+                    "HTTP/1.1 {} Whatever\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    reply.status,
+                    reply.body.len()
+                );
+                for (name, value) in reply.headers {
+                    response.push_str(&format!("{}: {}\r\n", name, value));
+                }
+                response.push_str("\r\n");
+                response.push_str(reply.body);
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        ListServer {
+            url,
+            replies,
+            requests,
+        }
+    }
+
+    fn reply(
+        &self,
+        status: u16,
+        headers: &'static [(&'static str, &'static str)],
+        body: &'static str,
+    ) {
+        self.replies.lock().unwrap().push_back(Reply {
+            status,
+            headers,
+            body,
+        });
+    }
+
+    fn request(&self, index: usize) -> String {
+        self.requests.lock().unwrap()[index].clone()
+    }
+
+    fn blocker(&self, cache_dir: Option<PathBuf>) -> Blocker {
+        let client = Client::builder().no_proxy().build().unwrap();
+        Blocker::new(vec![self.url.clone()], cache_dir, client)
+    }
+}
+
+const VERSIONED: &[(&str, &str)] = &[
+    ("ETag", "\"v1\""),
+    ("Last-Modified", "Thu, 01 Jan 2026 00:00:00 GMT"),
+];
+
+#[tokio::test]
+async fn downloads_dont_hold_the_lists() {
+    // Accepts connections, but never answers
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/list.txt", listener.local_addr().unwrap());
+    let client = Client::builder().no_proxy().build().unwrap();
+    let blocker = Blocker::new(vec![url], None, client);
+
+    let refresh = tokio::spawn({
+        let blocker = blocker.clone();
+        async move { blocker.process_lists().await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!refresh.is_finished());
+    assert!(blocker.data.lists.try_lock().is_ok());
+    refresh.abort();
+}
+
+fn is_conditional(request: &str) -> bool {
+    request.contains("if-none-match") || request.contains("if-modified-since")
+}
+
+#[tokio::test]
+async fn downloaded_lists_are_swapped_in() {
+    let server = ListServer::start().await;
+    let blocker = server.blocker(None);
+    assert!(!blocker.is_blocked("ads.example").await);
+
+    server.reply(200, VERSIONED, "ads.example\n");
+    blocker.process_lists().await;
+    assert!(blocker.is_blocked("ads.example").await);
+
+    // A new copy replaces the old one as a whole
+    server.reply(200, &[], "tracker.example\n");
+    blocker.process_lists().await;
+    assert!(!blocker.is_blocked("ads.example").await);
+    assert!(blocker.is_blocked("tracker.example").await);
+}
+
+#[tokio::test]
+async fn unchanged_lists_are_only_asked_for_once_there_is_a_copy() {
+    let server = ListServer::start().await;
+    let blocker = server.blocker(None);
+
+    server.reply(200, VERSIONED, "ads.example\n");
+    blocker.process_lists().await;
+    assert!(!is_conditional(&server.request(0)));
+
+    server.reply(304, &[], "");
+    blocker.process_lists().await;
+    let request = server.request(1);
+    assert!(request.contains("if-none-match: \"v1\"\r\n"), "{}", request);
+    assert!(
+        request.contains("if-modified-since: thu, 01 jan 2026 00:00:00 gmt\r\n"),
+        "{}",
+        request
+    );
+    assert!(blocker.is_blocked("ads.example").await);
+}
+
+#[tokio::test]
+async fn failed_downloads_keep_the_last_copy() {
+    let server = ListServer::start().await;
+    let blocker = server.blocker(None);
+
+    server.reply(200, VERSIONED, "ads.example\n");
+    blocker.process_lists().await;
+    server.reply(500, &[], "");
+    blocker.process_lists().await;
+    assert!(blocker.is_blocked("ads.example").await);
+}
+
+#[tokio::test]
+async fn failed_first_downloads_block_nothing_yet() {
+    let server = ListServer::start().await;
+    let blocker = server.blocker(None);
+
+    server.reply(500, VERSIONED, "ads.example\n");
+    blocker.process_lists().await;
+    assert!(!blocker.is_blocked("ads.example").await);
+
+    // Without a copy there is nothing to compare against
+    server.reply(200, &[], "ads.example\n");
+    blocker.process_lists().await;
+    assert!(!is_conditional(&server.request(1)));
+    assert!(blocker.is_blocked("ads.example").await);
+}
+
+#[tokio::test]
+async fn cached_copies_are_used_before_the_network() {
+    let dir = std::env::temp_dir().join(format!("mindns-block-cache-{}", std::process::id()));
+    let server = ListServer::start().await;
+    server.reply(200, VERSIONED, "ads.example\n");
+    server.blocker(Some(dir.clone())).process_lists().await;
+
+    // The next start blocks from the cache before anything is downloaded
+    let blocker = server.blocker(Some(dir.clone()));
+    blocker.load_cache().await;
+    assert!(blocker.is_blocked("ads.example").await);
+    assert_eq!(server.requests.lock().unwrap().len(), 1);
+
+    // And only asks for a newer copy than the one it has
+    server.reply(304, &[], "");
+    blocker.process_lists().await;
+    assert!(server.request(1).contains("if-none-match: \"v1\"\r\n"));
+    assert!(blocker.is_blocked("ads.example").await);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
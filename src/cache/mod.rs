@@ -0,0 +1,340 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use tracing::trace;
+
+use crate::{
+    config::CacheSettings,
+    protocol::{
+        dns_packet::DnsPacket, dns_question::DnsQuestion, dns_record::DnsRecord,
+        query_type::QueryType, result_code::ResultCode,
+    },
+};
+
+/// Answers are cached per name, type and class, names are compared case-insensitively
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    name: String,
+    qtype: QueryType,
+    class: u16,
+}
+
+impl From<&DnsQuestion> for CacheKey {
+    fn from(question: &DnsQuestion) -> Self {
+        Self {
+            name: question.name.to_ascii_lowercase(),
+            qtype: question.qtype,
+            class: question.class,
+        }
+    }
+}
+
+struct CacheEntry {
+    packet: DnsPacket,
+    inserted: Instant,
+    expires: Instant,
+}
+
+#[derive(Clone)]
+pub struct Cache {
+    data: Arc<CacheData>,
+}
+
+pub struct CacheData {
+    settings: CacheSettings,
+    entries: Option<Mutex<LruCache<CacheKey, CacheEntry>>>,
+}
+
+impl Cache {
+    pub fn new(settings: CacheSettings) -> Self {
+        let entries = NonZeroUsize::new(settings.size).map(|size| Mutex::new(LruCache::new(size)));
+        Self {
+            data: Arc::new(CacheData { settings, entries }),
+        }
+    }
+
+    /// Look up a cached answer, with every TTL counted down by the time it spent in the cache
+    pub fn get(&self, question: &DnsQuestion) -> Option<DnsPacket> {
+        self.get_at(question, Instant::now())
+    }
+
+    fn get_at(&self, question: &DnsQuestion, now: Instant) -> Option<DnsPacket> {
+        let entries = self.data.entries.as_ref()?;
+        let key = CacheKey::from(question);
+        let mut entries = entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+
+        if now >= entry.expires {
+            entries.pop(&key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(entry.inserted).as_secs() as u32;
+        let mut packet = entry.packet.clone();
+        for rec in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            rec.set_ttl(rec.ttl().saturating_sub(elapsed));
+        }
+        Some(packet)
+    }
+
+    /// Cache an upstream response to `question`, if it can be cached
+    pub fn insert(&self, question: &DnsQuestion, packet: &DnsPacket) {
+        self.insert_at(question, packet, Instant::now())
+    }
+
+    fn insert_at(&self, question: &DnsQuestion, packet: &DnsPacket, inserted: Instant) {
+        let Some(entries) = self.data.entries.as_ref() else {
+            return;
+        };
+        let Some(ttl) = self.cache_ttl(packet) else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        // Keep record TTLs within the lifetime of the entry, so none of them count down
+        // past zero while it is still being served. Negative answers carry the negative
+        // TTL on their SOA.
+        let mut packet = packet.clone();
+        let negative = packet.answers.is_empty();
+        let max_ttl = self.data.settings.max_ttl.max(ttl);
+        for rec in packet
+            .answers
+            .iter_mut()
+            .chain(packet.authorities.iter_mut())
+            .chain(packet.resources.iter_mut())
+        {
+            if negative {
+                rec.set_ttl(ttl);
+            } else {
+                rec.set_ttl(rec.ttl().clamp(ttl, max_ttl));
+            }
+        }
+
+        trace!(
+            "Caching {:?} {} for {}s",
+            question.qtype,
+            question.name,
+            ttl
+        );
+        entries.lock().unwrap().put(
+            CacheKey::from(question),
+            CacheEntry {
+                packet,
+                inserted,
+                expires: inserted + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+
+    /// How long a response may be cached for, `None` if it must not be cached at all
+    fn cache_ttl(&self, packet: &DnsPacket) -> Option<u32> {
+        let settings = &self.data.settings;
+        if packet.header.truncated_message {
+            return None;
+        }
+        match packet.header.rescode {
+            ResultCode::NOERROR if !packet.answers.is_empty() => {
+                let ttl = packet.answers.iter().map(|rec| rec.ttl()).min()?;
+                Some(ttl.clamp(settings.min_ttl, settings.max_ttl.max(settings.min_ttl)))
+            }
+            // NODATA and NXDOMAIN are cached for the lesser of the SOA TTL and its minimum
+            // field (RFC 2308), a negative answer without a SOA is not cached
+            ResultCode::NOERROR | ResultCode::NXDOMAIN => {
+                packet.authorities.iter().find_map(|rec| match rec {
+                    DnsRecord::SOA { minimum, ttl, .. } => {
+                        Some((*minimum).min(*ttl).min(settings.negative_max_ttl))
+                    }
+                    _ => None,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn cache(size: usize) -> Cache {
+        Cache::new(CacheSettings {
+            size,
+            min_ttl: 0,
+            max_ttl: 86400,
+            negative_max_ttl: 3600,
+        })
+    }
+
+    fn question(name: &str, qtype: QueryType) -> DnsQuestion {
+        DnsQuestion::new(name.to_string(), qtype)
+    }
+
+    fn answer(name: &str, ttl: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.answers.push(DnsRecord::A {
+            domain: name.to_string(),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl,
+        });
+        packet
+    }
+
+    fn negative(rescode: ResultCode, soa_ttl: u32, minimum: u32) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = rescode;
+        packet.authorities.push(DnsRecord::SOA {
+            domain: "example".to_string(),
+            m_name: "ns.example".to_string(),
+            r_name: "admin.example".to_string(),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum,
+            ttl: soa_ttl,
+        });
+        packet
+    }
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn ttls_count_down_until_the_entry_expires() {
+        let cache = cache(10);
+        let q = question("www.example", QueryType::A);
+        let t0 = Instant::now();
+        cache.insert_at(&q, &answer("www.example", 300), t0);
+
+        let hit = cache.get_at(&q, t0 + secs(100)).unwrap();
+        assert_eq!(hit.answers[0].ttl(), 200);
+        let hit = cache.get_at(&q, t0 + secs(299)).unwrap();
+        assert_eq!(hit.answers[0].ttl(), 1);
+        assert!(cache.get_at(&q, t0 + secs(300)).is_none());
+    }
+
+    #[test]
+    fn zero_ttls_and_truncated_answers_are_not_cached() {
+        let cache = cache(10);
+        let q = question("www.example", QueryType::A);
+        let t0 = Instant::now();
+        cache.insert_at(&q, &answer("www.example", 0), t0);
+        assert!(cache.get_at(&q, t0).is_none());
+
+        let mut truncated = answer("www.example", 300);
+        truncated.header.truncated_message = true;
+        cache.insert_at(&q, &truncated, t0);
+        assert!(cache.get_at(&q, t0).is_none());
+    }
+
+    #[test]
+    fn negative_answers_last_as_long_as_the_soa_minimum() {
+        let cache = cache(10);
+        let q = question("nx.example", QueryType::A);
+        let t0 = Instant::now();
+        cache.insert_at(&q, &negative(ResultCode::NXDOMAIN, 3600, 60), t0);
+
+        let hit = cache.get_at(&q, t0 + secs(10)).unwrap();
+        assert_eq!(hit.header.rescode, ResultCode::NXDOMAIN);
+        // The SOA carries the negative TTL, counted down like any other
+        assert_eq!(hit.authorities[0].ttl(), 50);
+        assert!(cache.get_at(&q, t0 + secs(60)).is_none());
+    }
+
+    #[test]
+    fn negative_answers_last_no_longer_than_the_soa_ttl() {
+        let cache = cache(10);
+        let q = question("www.example", QueryType::AAAA);
+        let t0 = Instant::now();
+        // NODATA, the name exists without any records of the type
+        cache.insert_at(&q, &negative(ResultCode::NOERROR, 30, 600), t0);
+        assert!(cache.get_at(&q, t0 + secs(29)).is_some());
+        assert!(cache.get_at(&q, t0 + secs(30)).is_none());
+    }
+
+    #[test]
+    fn negative_answers_are_capped() {
+        let cache = cache(10);
+        let q = question("nx.example", QueryType::A);
+        let t0 = Instant::now();
+        cache.insert_at(&q, &negative(ResultCode::NXDOMAIN, 86400, 86400), t0);
+        assert!(cache.get_at(&q, t0 + secs(3599)).is_some());
+        assert!(cache.get_at(&q, t0 + secs(3600)).is_none());
+    }
+
+    #[test]
+    fn negative_answers_without_a_soa_are_not_cached() {
+        let cache = cache(10);
+        let q = question("nx.example", QueryType::A);
+        let mut packet = DnsPacket::new();
+        packet.header.rescode = ResultCode::NXDOMAIN;
+        let t0 = Instant::now();
+        cache.insert_at(&q, &packet, t0);
+        assert!(cache.get_at(&q, t0).is_none());
+    }
+
+    #[test]
+    fn entries_are_keyed_by_name_type_and_class() {
+        let cache = cache(10);
+        let t0 = Instant::now();
+        cache.insert_at(
+            &question("WWW.Example", QueryType::A),
+            &answer("WWW.Example", 300),
+            t0,
+        );
+
+        // Names compare case-insensitively
+        assert!(cache
+            .get_at(&question("www.example", QueryType::A), t0)
+            .is_some());
+        assert!(cache
+            .get_at(&question("www.example", QueryType::AAAA), t0)
+            .is_none());
+        let mut chaos = question("www.example", QueryType::A);
+        chaos.class = 3;
+        assert!(cache.get_at(&chaos, t0).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let cache = cache(2);
+        let (a, b, c) = (
+            question("a.example", QueryType::A),
+            question("b.example", QueryType::A),
+            question("c.example", QueryType::A),
+        );
+        let t0 = Instant::now();
+        cache.insert_at(&a, &answer("a.example", 300), t0);
+        cache.insert_at(&b, &answer("b.example", 300), t0);
+        // Using a makes b the oldest
+        assert!(cache.get_at(&a, t0).is_some());
+        cache.insert_at(&c, &answer("c.example", 300), t0);
+
+        assert!(cache.get_at(&a, t0).is_some());
+        assert!(cache.get_at(&b, t0).is_none());
+        assert!(cache.get_at(&c, t0).is_some());
+    }
+
+    #[test]
+    fn a_size_of_zero_turns_caching_off() {
+        let cache = cache(0);
+        let q = question("www.example", QueryType::A);
+        let t0 = Instant::now();
+        cache.insert_at(&q, &answer("www.example", 300), t0);
+        assert!(cache.get_at(&q, t0).is_none());
+    }
+}
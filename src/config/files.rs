@@ -1,20 +1,80 @@
 use serde_derive::Deserialize;
 
-use crate::rewrites::RewriteRule;
+use crate::{
+    dns::pool::UpstreamStrategy,
+    dnssec::{signer::Denial, ROOT_TRUST_ANCHORS},
+    rewrites::RewriteRule,
+};
 
-use super::{BlockSettings, Config, MirrorSettings, ServerSettings};
+use super::{
+    BlockSettings, CacheSettings, Config, DnssecSettings, ForwardRule, HttpsSettings,
+    MirrorSettings, ServerSettings, SignedZone, TlsSettings,
+};
 
 #[derive(Clone, Default, Deserialize)]
 pub struct ServerSettingsFile {
     port: Option<u16>,
     bind: Option<String>,
+    tcp_idle_timeout: Option<u64>,
+    strict_parsing: Option<bool>,
+    tls: Option<TlsSettingsFile>,
+    https: Option<HttpsSettingsFile>,
 }
 
 impl From<ServerSettingsFile> for ServerSettings {
     fn from(val: ServerSettingsFile) -> Self {
+        if val.tcp_idle_timeout == Some(0) {
+            panic!("The TCP idle timeout must be at least a second");
+        }
         Self {
             port: val.port.unwrap_or(53),
             bind: val.bind.unwrap_or("0.0.0.0".to_string()),
+            tcp_idle_timeout: val.tcp_idle_timeout.unwrap_or(10),
+            strict_parsing: val.strict_parsing.unwrap_or(true),
+            tls: val.tls.unwrap_or_default().into(),
+            https: val.https.unwrap_or_default().into(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct TlsSettingsFile {
+    enabled: Option<bool>,
+    port: Option<u16>,
+    cert: Option<String>,
+    key: Option<String>,
+    secret: Option<String>,
+}
+
+impl From<TlsSettingsFile> for TlsSettings {
+    fn from(val: TlsSettingsFile) -> Self {
+        let enabled = val.enabled.unwrap_or(false);
+        if enabled && val.secret.is_none() && (val.cert.is_none() || val.key.is_none()) {
+            panic!("A certificate and key, or a secret, must be provided if TLS is enabled");
+        }
+        Self {
+            enabled,
+            port: val.port.unwrap_or(853),
+            cert: val.cert.map(Into::into),
+            key: val.key.map(Into::into),
+            secret: val.secret,
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct HttpsSettingsFile {
+    enabled: Option<bool>,
+    port: Option<u16>,
+    path: Option<String>,
+}
+
+impl From<HttpsSettingsFile> for HttpsSettings {
+    fn from(val: HttpsSettingsFile) -> Self {
+        Self {
+            enabled: val.enabled.unwrap_or(false),
+            port: val.port.unwrap_or(8053),
+            path: val.path.unwrap_or("/dns-query".to_string()),
         }
     }
 }
@@ -23,16 +83,49 @@ impl From<ServerSettingsFile> for ServerSettings {
 pub struct MirrorSettingsFile {
     enabled: Option<bool>,
     servers: Vec<String>,
+    strategy: Option<UpstreamStrategy>,
+    randomize_case: Option<bool>,
+    forward: Option<Vec<ForwardRuleFile>>,
 }
 
 impl From<MirrorSettingsFile> for MirrorSettings {
     fn from(val: MirrorSettingsFile) -> Self {
-        if matches!(val.enabled, Some(true) if val.servers.is_empty()) {
+        let forward: Vec<ForwardRule> = val
+            .forward
+            .unwrap_or_default()
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        // Forwarding only some domains is fine, the rest then has nowhere to go
+        if matches!(val.enabled, Some(true) if val.servers.is_empty() && forward.is_empty()) {
             panic!("Mirror servers must be provided if mirror is enabled");
         }
         Self {
             enabled: val.enabled.unwrap_or(true),
             servers: val.servers,
+            strategy: val.strategy.unwrap_or_default(),
+            randomize_case: val.randomize_case.unwrap_or(false),
+            forward,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ForwardRuleFile {
+    domain: String,
+    servers: Vec<String>,
+    strategy: Option<UpstreamStrategy>,
+}
+
+impl From<ForwardRuleFile> for ForwardRule {
+    fn from(val: ForwardRuleFile) -> Self {
+        if val.servers.is_empty() {
+            panic!("Servers must be provided to forward {}", val.domain);
+        }
+        Self {
+            domain: val.domain,
+            servers: val.servers,
+            strategy: val.strategy,
         }
     }
 }
@@ -41,6 +134,8 @@ impl From<MirrorSettingsFile> for MirrorSettings {
 pub struct BlockSettingsFile {
     enabled: Option<bool>,
     lists: Vec<String>,
+    refresh_interval: Option<u64>,
+    cache_dir: Option<String>,
 }
 
 impl From<BlockSettingsFile> for BlockSettings {
@@ -51,6 +146,82 @@ impl From<BlockSettingsFile> for BlockSettings {
         Self {
             enabled: val.enabled.unwrap_or(true),
             lists: val.lists,
+            refresh_interval: val.refresh_interval.unwrap_or(86400),
+            cache_dir: val.cache_dir.map(Into::into),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct CacheSettingsFile {
+    size: Option<usize>,
+    min_ttl: Option<u32>,
+    max_ttl: Option<u32>,
+    negative_max_ttl: Option<u32>,
+}
+
+impl From<CacheSettingsFile> for CacheSettings {
+    fn from(val: CacheSettingsFile) -> Self {
+        Self {
+            size: val.size.unwrap_or(10000),
+            min_ttl: val.min_ttl.unwrap_or(0),
+            max_ttl: val.max_ttl.unwrap_or(86400),
+            negative_max_ttl: val.negative_max_ttl.unwrap_or(3600),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct DnssecSettingsFile {
+    validate: Option<bool>,
+    trust_anchors: Option<Vec<String>>,
+    zones: Option<Vec<SignedZoneFile>>,
+}
+
+impl From<DnssecSettingsFile> for DnssecSettings {
+    fn from(val: DnssecSettingsFile) -> Self {
+        if matches!(&val.trust_anchors, Some(anchors) if anchors.is_empty()) {
+            panic!("Trust anchors can't be empty, leave them out to use the root KSKs");
+        }
+        Self {
+            validate: val.validate.unwrap_or(false),
+            trust_anchors: val.trust_anchors.unwrap_or_else(|| {
+                ROOT_TRUST_ANCHORS
+                    .iter()
+                    .map(|anchor| anchor.to_string())
+                    .collect()
+            }),
+            zones: val
+                .zones
+                .unwrap_or_default()
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SignedZoneFile {
+    name: String,
+    key: Option<String>,
+    secret: Option<String>,
+    denial: Option<Denial>,
+}
+
+impl From<SignedZoneFile> for SignedZone {
+    fn from(val: SignedZoneFile) -> Self {
+        if val.key.is_none() && val.secret.is_none() {
+            panic!(
+                "A key or a secret must be provided for signed zone {}",
+                val.name
+            );
+        }
+        Self {
+            name: val.name,
+            key: val.key.map(Into::into),
+            secret: val.secret,
+            denial: val.denial.unwrap_or_default(),
         }
     }
 }
@@ -60,6 +231,8 @@ pub struct ConfigFile {
     server: Option<ServerSettingsFile>,
     mirror: Option<MirrorSettingsFile>,
     block: Option<BlockSettingsFile>,
+    cache: Option<CacheSettingsFile>,
+    dnssec: Option<DnssecSettingsFile>,
     rewrites: Vec<RewriteRule>,
 }
 
@@ -69,7 +242,21 @@ impl From<ConfigFile> for Config {
             server: val.server.unwrap_or_default().into(),
             mirror: val.mirror.unwrap_or_default().into(),
             block: val.block.unwrap_or_default().into(),
+            cache: val.cache.unwrap_or_default().into(),
+            dnssec: val.dnssec.unwrap_or_default().into(),
             rewrites: val.rewrites,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "TCP idle timeout")]
+    fn zero_tcp_idle_timeout_is_rejected() {
+        let file: ServerSettingsFile = serde_yaml::from_str("tcp_idle_timeout: 0").unwrap();
+        let _: ServerSettings = file.into();
+    }
+}
@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use files::ConfigFile;
 
-use crate::rewrites::RewriteRule;
+use crate::{dns::pool::UpstreamStrategy, dnssec::signer::Denial, rewrites::RewriteRule};
 
 mod files;
 
@@ -10,18 +10,91 @@ mod files;
 pub struct ServerSettings {
     pub port: u16,
     pub bind: String,
+    pub tcp_idle_timeout: u64,
+    /// Answer queries that don't add up with FORMERR
+    pub strict_parsing: bool,
+    pub tls: TlsSettings,
+    pub https: HttpsSettings,
+}
+
+#[derive(Clone)]
+pub struct HttpsSettings {
+    pub enabled: bool,
+    pub port: u16,
+    pub path: String,
+}
+
+#[derive(Clone)]
+pub struct TlsSettings {
+    pub enabled: bool,
+    pub port: u16,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub secret: Option<String>,
 }
 
 #[derive(Clone)]
 pub struct MirrorSettings {
     pub enabled: bool,
     pub servers: Vec<String>,
+    pub strategy: UpstreamStrategy,
+    /// Send names to plain DNS upstreams in random mixed case (DNS 0x20)
+    pub randomize_case: bool,
+    /// Domains that go to other servers than the default ones, the longest match wins
+    pub forward: Vec<ForwardRule>,
+}
+
+/// Queries for a domain and everything below it that go to their own upstreams
+#[derive(Clone)]
+pub struct ForwardRule {
+    /// Such as `cluster.local`, `*.in-addr.arpa` is read as `in-addr.arpa`
+    pub domain: String,
+    pub servers: Vec<String>,
+    /// The strategy of the mirror by default
+    pub strategy: Option<UpstreamStrategy>,
 }
 
 #[derive(Clone)]
 pub struct BlockSettings {
     pub enabled: bool,
     pub lists: Vec<String>,
+    /// Seconds between loading the lists again, never if 0
+    pub refresh_interval: u64,
+    /// Where the last download of each list is kept, to start with when the network isn't
+    /// there yet
+    pub cache_dir: Option<PathBuf>,
+}
+
+#[derive(Clone)]
+pub struct CacheSettings {
+    pub size: usize,
+    pub min_ttl: u32,
+    pub max_ttl: u32,
+    pub negative_max_ttl: u32,
+}
+
+#[derive(Clone)]
+pub struct DnssecSettings {
+    /// Check the signatures of upstream answers, answering SERVFAIL for bogus ones
+    pub validate: bool,
+    /// DS records of the root zone in presentation format, the current root KSKs by
+    /// default
+    pub trust_anchors: Vec<String>,
+    /// Local zones answered from the rewrites and signed on the fly
+    pub zones: Vec<SignedZone>,
+}
+
+/// A local zone whose answers are signed on the fly
+#[derive(Clone)]
+pub struct SignedZone {
+    pub name: String,
+    /// PKCS#8 PEM file with an ECDSA P-256 or Ed25519 private key
+    pub key: Option<PathBuf>,
+    /// Kubernetes secret holding the key instead, as `namespace/name` or just `name`
+    pub secret: Option<String>,
+    /// How names that aren't there are denied, compact NSEC records (RFC 9824) by default
+    /// or NSEC3 white lies
+    pub denial: Denial,
 }
 
 #[derive(Clone)]
@@ -29,6 +102,8 @@ pub struct Config {
     pub server: ServerSettings,
     pub mirror: MirrorSettings,
     pub block: BlockSettings,
+    pub cache: CacheSettings,
+    pub dnssec: DnssecSettings,
     pub rewrites: Vec<RewriteRule>,
 }
 
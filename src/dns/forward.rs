@@ -0,0 +1,223 @@
+use std::sync::Arc;
+
+use reqwest::Client;
+use tracing::{debug, info};
+
+use crate::{
+    config::{ForwardRule, MirrorSettings},
+    dnssec::names,
+    protocol::{dns_packet::DnsPacket, query_type::QueryType, Result},
+};
+
+use super::pool::UpstreamPool;
+
+/// Sends every query to the upstreams of the most specific domain it falls under, and
+/// everything else to the default upstreams
+#[derive(Clone)]
+pub struct Forwarder {
+    data: Arc<ForwarderData>,
+}
+
+pub struct ForwarderData {
+    /// Sorted with the longest domain first, so the first match is the closest one
+    rules: Vec<(String, UpstreamPool)>,
+    default: UpstreamPool,
+}
+
+impl Forwarder {
+    pub fn new(settings: &MirrorSettings, client: &Client) -> Result<Self> {
+        let default = UpstreamPool::new(
+            &settings.servers,
+            settings.strategy,
+            settings.randomize_case,
+            client,
+        )?;
+        let mut rules = settings
+            .forward
+            .iter()
+            .map(|rule| {
+                let pool = UpstreamPool::new(
+                    &rule.servers,
+                    rule.strategy.unwrap_or(settings.strategy),
+                    settings.randomize_case,
+                    client,
+                )?;
+                info!("Forwarding {} to {}", rule.domain, rule.servers.join(", "));
+                Ok((rule_domain(rule), pool))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        rules.sort_by_key(|(domain, _)| std::cmp::Reverse(names::label_count(domain)));
+        Ok(Self {
+            data: Arc::new(ForwarderData { rules, default }),
+        })
+    }
+
+    /// Whether a rule covers `qname`, rather than the default upstreams
+    pub fn has_rule(&self, qname: &str) -> bool {
+        self.rule(qname).is_some()
+    }
+
+    fn rule(&self, qname: &str) -> Option<&(String, UpstreamPool)> {
+        self.data
+            .rules
+            .iter()
+            .find(|(domain, _)| names::is_subdomain(qname, domain))
+    }
+
+    /// Forward a query to the upstreams responsible for `qname`
+    pub async fn query(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        match self.rule(qname) {
+            Some((domain, pool)) => {
+                debug!("Forwarding {} by the rule for {}", qname, domain);
+                pool.query(qname, qtype).await
+            }
+            None => self.data.default.query(qname, qtype).await,
+        }
+    }
+}
+
+/// The domain a rule applies to, `*.in-addr.arpa` means the same as `in-addr.arpa`
+fn rule_domain(rule: &ForwardRule) -> String {
+    let domain = rule.domain.strip_prefix("*.").unwrap_or(&rule.domain);
+    names::lowercase(domain.trim_end_matches('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::*;
+    use crate::{
+        dns::pool::UpstreamStrategy,
+        networking::framing::{read_message, write_message},
+        protocol::{byte_packet_buffer::BytePacketBuffer, dns_record::DnsRecord},
+    };
+
+    /// The answer of a server that tells who it is by the address it gives out
+    fn answer(query: &[u8], id: u8) -> Vec<u8> {
+        let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(query)).unwrap();
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.answers.push(DnsRecord::A {
+            domain: query.questions[0].name.clone(),
+            addr: Ipv4Addr::new(192, 0, 2, id),
+            ttl: 300,
+        });
+        response.questions = query.questions;
+        let mut out = BytePacketBuffer::new();
+        response.write(&mut out).unwrap();
+        out.buf[..out.pos].to_vec()
+    }
+
+    async fn udp_server(id: u8) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (size, client) = socket.recv_from(&mut buf).await.unwrap();
+                let response = answer(&buf[..size], id);
+                socket.send_to(&response, client).await.unwrap();
+            }
+        });
+        addr.to_string()
+    }
+
+    async fn tcp_server(id: u8) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                while let Ok(Some(query)) = read_message(&mut stream).await {
+                    write_message(&mut stream, &answer(&query, id))
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+        format!("tcp://{}", addr)
+    }
+
+    fn rule(domain: &str, server: String) -> ForwardRule {
+        ForwardRule {
+            domain: domain.to_string(),
+            servers: vec![server],
+            strategy: None,
+        }
+    }
+
+    fn forwarder(default: String, forward: Vec<ForwardRule>) -> Forwarder {
+        let settings = MirrorSettings {
+            enabled: true,
+            servers: vec![default],
+            strategy: UpstreamStrategy::Failover,
+            randomize_case: false,
+            forward,
+        };
+        Forwarder::new(&settings, &Client::new()).unwrap()
+    }
+
+    /// Which server answered `qname`
+    async fn answered_by(forwarder: &Forwarder, qname: &str) -> u8 {
+        let response = forwarder.query(qname, QueryType::A).await.unwrap();
+        match response.answers[0] {
+            DnsRecord::A { addr, .. } => addr.octets()[3],
+            ref rec => panic!("{:?}", rec),
+        }
+    }
+
+    #[test]
+    fn rule_domains_are_plain_lowercase_names() {
+        for (domain, expected) in [
+            ("*.in-addr.arpa", "in-addr.arpa"),
+            ("Corp.Example.", "corp.example"),
+            ("*.Corp.Example", "corp.example"),
+            ("cluster.local", "cluster.local"),
+        ] {
+            assert_eq!(
+                rule_domain(&rule(domain, String::new())),
+                expected,
+                "{}",
+                domain
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn the_longest_matching_rule_wins() {
+        let forwarder = forwarder(
+            udp_server(1).await,
+            vec![
+                rule("corp.example", udp_server(2).await),
+                rule("*.a.corp.example", udp_server(3).await),
+            ],
+        );
+        for (qname, id) in [
+            ("corp.example", 2),
+            ("www.corp.example", 2),
+            ("a.corp.example", 3),
+            ("www.A.Corp.Example", 3),
+            ("b.corp.example", 2),
+            ("notcorp.example", 1),
+            ("example", 1),
+        ] {
+            assert_eq!(answered_by(&forwarder, qname).await, id, "{}", qname);
+        }
+        assert!(forwarder.has_rule("x.a.corp.example"));
+        assert!(!forwarder.has_rule("example.com"));
+    }
+
+    #[tokio::test]
+    async fn rules_can_forward_over_tcp() {
+        let forwarder = forwarder(
+            udp_server(1).await,
+            vec![rule("home.arpa", tcp_server(4).await)],
+        );
+        assert_eq!(answered_by(&forwarder, "router.home.arpa").await, 4);
+        assert_eq!(answered_by(&forwarder, "www.example").await, 1);
+    }
+}
@@ -0,0 +1,225 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::{header, Client, Url};
+
+use crate::protocol::{
+    byte_packet_buffer::BytePacketBuffer, dns_packet::DnsPacket, query_type::QueryType, Result,
+};
+
+use super::{is_response_to, query_packet, QueryFlags, LOOKUP_TIMEOUT};
+
+/// The media type of a wire format DNS message (RFC 8484)
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+/// An upstream reached over DNS over HTTPS (RFC 8484)
+///
+/// Queries are sent with POST, unless the URL is a template ending in `{?dns}`, in which case
+/// they are sent with GET as a base64url `dns` parameter. The client keeps its connections
+/// pooled, and uses HTTP/2 when the server offers it.
+pub struct HttpsUpstream {
+    url: Url,
+    get: bool,
+    client: Client,
+}
+
+impl HttpsUpstream {
+    /// `client` is shared with everything else that talks HTTP, so all of it goes through
+    /// the same connection pool
+    pub fn new(template: &str, client: Client) -> std::result::Result<Self, String> {
+        let (url, get) = match template.strip_suffix("{?dns}") {
+            Some(url) => (url, true),
+            None => (template, false),
+        };
+        let url =
+            Url::parse(url).map_err(|err| format!("Invalid upstream URL {}: {}", url, err))?;
+        Ok(Self { url, get, client })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    pub async fn query(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let mut packet = query_packet(qname, qtype, QueryFlags::RECURSIVE);
+        // A zero id lets HTTP caches share answers between clients (RFC 8484 section 4.1)
+        packet.header.id = 0;
+
+        let mut req_buffer = BytePacketBuffer::new();
+        packet.write(&mut req_buffer)?;
+        let body = &req_buffer.buf[0..req_buffer.pos];
+
+        let request = if self.get {
+            let mut url = self.url.clone();
+            url.query_pairs_mut()
+                .append_pair("dns", &URL_SAFE_NO_PAD.encode(body));
+            self.client.get(url)
+        } else {
+            self.client
+                .post(self.url.clone())
+                .header(header::CONTENT_TYPE, DNS_MESSAGE)
+                .body(body.to_vec())
+        };
+        let response = request
+            .header(header::ACCEPT, DNS_MESSAGE)
+            .timeout(LOOKUP_TIMEOUT)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !content_type.starts_with(DNS_MESSAGE) {
+            return Err(format!("{} answered with {}", self.url, content_type).into());
+        }
+
+        let data = response.bytes().await?;
+        let mut res_buffer = BytePacketBuffer::from_bytes(&data);
+        let response = DnsPacket::from_buffer(&mut res_buffer)?;
+        if !is_response_to(&packet, &response) {
+            return Err(format!("{} answered a different question", self.url).into());
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::protocol::dns_record::DnsRecord;
+
+    /// A request as the server got it
+    struct Request {
+        head: String,
+        body: Vec<u8>,
+    }
+
+    /// A DoH server on a local port that answers with `content_type`, for `answer_name`
+    /// when given instead of the name asked for, and the requests it got
+    async fn server(
+        content_type: &'static str,
+        answer_name: Option<&'static str>,
+    ) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+        let requests: Arc<Mutex<Vec<Request>>> = Arc::default();
+        let log = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut data = Vec::new();
+                let mut buf = [0; 1024];
+                let end = loop {
+                    let size = stream.read(&mut buf).await.unwrap();
+                    data.extend_from_slice(&buf[..size]);
+                    if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&data[..end]).to_string();
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map_or(0, |length| length.trim().parse().unwrap());
+                while data.len() < end + length {
+                    let size = stream.read(&mut buf).await.unwrap();
+                    data.extend_from_slice(&buf[..size]);
+                }
+                let body = data[end..].to_vec();
+
+                // Without padding, or it doesn't decode
+                let query = match head.split_once("?dns=") {
+                    Some((_, rest)) => URL_SAFE_NO_PAD
+                        .decode(rest.split_whitespace().next().unwrap())
+                        .unwrap(),
+                    None => body.clone(),
+                };
+                let query =
+                    DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&query)).unwrap();
+                log.lock().unwrap().push(Request { head, body });
+
+                let mut response = DnsPacket::new();
+                response.header.id = query.header.id;
+                response.header.response = true;
+                response.questions = query.questions;
+                if let Some(name) = answer_name {
+                    response.questions[0].name = name.to_string();
+                }
+                response.answers.push(DnsRecord::A {
+                    domain: response.questions[0].name.clone(),
+                    addr: [192, 0, 2, 1].into(),
+                    ttl: 300,
+                });
+                let mut out = BytePacketBuffer::new();
+                response.write(&mut out).unwrap();
+                let mut reply = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    content_type, out.pos
+                )
+                .into_bytes();
+                reply.extend_from_slice(&out.buf[..out.pos]);
+                stream.write_all(&reply).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn client() -> Client {
+        Client::builder().no_proxy().build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn queries_are_posted_by_default() {
+        let (url, requests) = server(DNS_MESSAGE, None).await;
+        let upstream = HttpsUpstream::new(&url, client()).unwrap();
+        let response = upstream.query("www.example", QueryType::A).await.unwrap();
+        assert_eq!(response.answers[0].domain(), "www.example");
+
+        let requests = requests.lock().unwrap();
+        let request = &requests[0];
+        assert!(request.head.starts_with("POST /dns-query HTTP/1.1\r\n"));
+        assert!(request
+            .head
+            .contains("content-type: application/dns-message\r\n"));
+        assert!(request.head.contains("accept: application/dns-message\r\n"));
+        let query =
+            DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&request.body)).unwrap();
+        assert_eq!(query.header.id, 0);
+        assert_eq!(query.questions[0].name, "www.example");
+    }
+
+    #[tokio::test]
+    async fn templates_get_queries_as_a_parameter() {
+        let (url, requests) = server(DNS_MESSAGE, None).await;
+        let upstream = HttpsUpstream::new(&format!("{}{{?dns}}", url), client()).unwrap();
+        assert_eq!(upstream.url().as_str(), url);
+        let response = upstream.query("www.example", QueryType::A).await.unwrap();
+        assert_eq!(response.answers[0].domain(), "www.example");
+
+        let requests = requests.lock().unwrap();
+        let request = &requests[0];
+        assert!(request.head.starts_with("GET /dns-query?dns="));
+        assert!(request.head.contains("accept: application/dns-message\r\n"));
+        assert!(request.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_have_to_be_dns_messages_for_the_question() {
+        let (url, _) = server("text/html", None).await;
+        let upstream = HttpsUpstream::new(&url, client()).unwrap();
+        assert!(upstream.query("www.example", QueryType::A).await.is_err());
+
+        let (url, _) = server(DNS_MESSAGE, Some("evil.example")).await;
+        let upstream = HttpsUpstream::new(&url, client()).unwrap();
+        assert!(upstream.query("www.example", QueryType::A).await.is_err());
+    }
+}
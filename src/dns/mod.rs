@@ -1,118 +1,364 @@
-use std::net::{Ipv4Addr, UdpSocket};
+pub mod forward;
+pub mod https;
+pub mod pool;
+pub mod resolver;
+pub mod stream;
+pub mod tls;
+pub mod upstream;
 
-use tracing::debug;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
 
-use crate::{
-    protocol::{
-        byte_packet_buffer::BytePacketBuffer, dns_packet::DnsPacket, dns_question::DnsQuestion,
-        query_type::QueryType, result_code::ResultCode, Result,
-    },
-    Cache,
+use tokio::io::Interest;
+use tokio::net::{TcpStream, UdpSocket};
+use tracing::{debug, trace};
+
+use self::stream::exchange_stream;
+use crate::protocol::{
+    byte_packet_buffer::BytePacketBuffer, dns_packet::DnsPacket, dns_question::DnsQuestion,
+    edns::Edns, query_type::QueryType, Result,
 };
 
-fn lookup(
+/// How long to wait for an upstream server to answer
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest UDP response we are prepared to receive from an upstream server
+const UPSTREAM_RECV_SIZE: usize = 4096;
+
+/// How a query is asked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueryFlags {
+    /// Whether the server should resolve the name for us, which iterative queries to
+    /// root, TLD and authoritative servers don't ask for (RFC 1034 section 4.3.1)
+    pub recursion_desired: bool,
+    /// Whether the letters of the name are sent in random case, which the answer then has
+    /// to echo exactly (DNS 0x20), only done over UDP where answers are easy to forge
+    pub randomize_case: bool,
+}
+
+impl QueryFlags {
+    /// A query for a server that resolves names for its clients
+    pub const RECURSIVE: QueryFlags = QueryFlags {
+        recursion_desired: true,
+        randomize_case: false,
+    };
+
+    /// A query for a server that answers from its own zones
+    pub const ITERATIVE: QueryFlags = QueryFlags {
+        recursion_desired: false,
+        randomize_case: false,
+    };
+
+    pub fn with_random_case(self, randomize_case: bool) -> Self {
+        Self {
+            randomize_case,
+            ..self
+        }
+    }
+}
+
+/// An answer that got the id and question right, but not the exact spelling of the name
+///
+/// Anyone who can only guess is far more likely to get the case wrong than a server
+/// that echoes the name, so the exchange is abandoned rather than trusted.
+#[derive(Debug)]
+pub struct CaseMismatch {
+    pub server: SocketAddr,
+    pub qname: String,
+}
+
+impl fmt::Display for CaseMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} didn't echo the case of {}", self.server, self.qname)
+    }
+}
+
+impl std::error::Error for CaseMismatch {}
+
+/// Send a single query to `server` over UDP and wait for the matching answer
+///
+/// Every exchange uses its own socket, so the OS picks a random source port for it, and a
+/// random query id. Anything that arrives with the wrong id or question is dropped rather
+/// than accepted, which makes spoofing an answer a lot harder than guessing a fixed port
+/// and id. An answer that comes back truncated is asked for again over TCP.
+///
+/// With `flags.randomize_case`, an answer that doesn't spell the name exactly the way it
+/// was sent fails with [`CaseMismatch`], the answer itself uses the spelling of `qname`.
+pub async fn exchange(
     qname: &str,
     qtype: QueryType,
-    server: (Ipv4Addr, u16),
-    cache: &Cache,
+    server: SocketAddr,
+    flags: QueryFlags,
 ) -> Result<DnsPacket> {
-    if let Some(data) = cache.get(qname) {
-        let (_, (fetched, packet)) = data.pair();
-        let ttl = packet.answers.first().map(|x| x.ttl()).unwrap_or(0);
-        if fetched.elapsed().unwrap().as_secs() < ttl as u64 {
-            return Ok(packet.clone());
+    let bind: SocketAddr = if server.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+
+    let sent = match flags.randomize_case {
+        true => randomize_case(qname),
+        false => qname.to_string(),
+    };
+    let mut packet = query_packet(&sent, qtype, flags);
+
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+    socket.send(&req_buffer.buf[0..req_buffer.pos]).await?;
+
+    let answer = async {
+        let mut buf = [0; UPSTREAM_RECV_SIZE];
+        loop {
+            let size = recv(&socket, &mut buf).await?;
+            let mut res_buffer = BytePacketBuffer::from_bytes(&buf[..size]);
+            let Ok(response) = DnsPacket::from_buffer(&mut res_buffer) else {
+                trace!("Dropping unparsable response from {}", server);
+                continue;
+            };
+            if !is_response_to(&packet, &response) {
+                debug!("Dropping mismatched response from {} for {}", server, qname);
+                continue;
+            }
+            if flags.randomize_case && !echoes_case(&packet, &response) {
+                return Err(CaseMismatch {
+                    server,
+                    qname: sent.clone(),
+                }
+                .into());
+            }
+            return Ok::<_, crate::protocol::Error>(response);
         }
+    };
+    let mut response = match tokio::time::timeout(LOOKUP_TIMEOUT, answer).await {
+        Ok(answer) => answer?,
+        Err(_) => {
+            return Err(format!("Timed out waiting for {} to answer {}", server, qname).into())
+        }
+    };
+    if response.header.truncated_message {
+        debug!("Truncated answer from {}, retrying over TCP", server);
+        return exchange_tcp(qname, qtype, server, flags).await;
     }
+    restore_case(&mut response, &sent, qname);
+    Ok(response)
+}
 
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+/// Receive on a connected socket, also waking up for errors such as an ICMP port
+/// unreachable, which otherwise only show up when the lookup times out
+async fn recv(socket: &UdpSocket, buf: &mut [u8]) -> std::io::Result<usize> {
+    loop {
+        let ready = socket.ready(Interest::READABLE | Interest::ERROR).await?;
+        if ready.is_error() {
+            return Err(socket
+                .take_error()?
+                .unwrap_or_else(|| std::io::ErrorKind::ConnectionRefused.into()));
+        }
+        match socket.try_recv(buf) {
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Send a single query to `server` over a fresh TCP connection
+pub async fn exchange_tcp(
+    qname: &str,
+    qtype: QueryType,
+    server: SocketAddr,
+    flags: QueryFlags,
+) -> Result<DnsPacket> {
+    let mut stream = tokio::time::timeout(LOOKUP_TIMEOUT, TcpStream::connect(server))
+        .await
+        .map_err(|_| format!("Timed out connecting to {}", server))??;
+    exchange_stream(&mut stream, qname, qtype, flags).await
+}
 
+/// Build a query for a single question, with a random id, advertising EDNS so answers
+/// larger than 512 bytes can still come back over UDP
+///
+/// DNSSEC records are always asked for, so answers can be validated or passed on to
+/// clients that want them.
+pub fn query_packet(qname: &str, qtype: QueryType, flags: QueryFlags) -> DnsPacket {
     let mut packet = DnsPacket::new();
 
-    packet.header.id = 6666;
+    packet.header.id = rand::random();
     packet.header.questions = 1;
-    packet.header.recursion_desired = true;
+    packet.header.recursion_desired = flags.recursion_desired;
     packet
         .questions
         .push(DnsQuestion::new(qname.to_string(), qtype));
+    let mut edns = Edns::new();
+    edns.dnssec_ok = true;
+    packet.edns = Some(edns);
 
-    let mut req_buffer = BytePacketBuffer::new();
-    packet.write(&mut req_buffer)?;
-    socket.send_to(&req_buffer.buf[0..req_buffer.pos], server)?;
-
-    let mut res_buffer = BytePacketBuffer::new();
-    // Add timeout to recv_from.
-    socket.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
-    socket.recv_from(&mut res_buffer.buf)?;
-
-    let packet = DnsPacket::from_buffer(&mut res_buffer);
-    if let Ok(packet) = &packet {
-        if !packet.answers.is_empty() {
-            cache.insert(
-                qname.to_string(),
-                (std::time::SystemTime::now(), packet.clone()),
-            );
-        }
-    }
     packet
 }
 
-pub fn recursive_lookup(
-    dns_server: &str,
-    qname: &str,
-    qtype: QueryType,
-    cache: &Cache,
-) -> Result<DnsPacket> {
-    // For now we're always starting with *a.root-servers.net*.
-    let mut ns = dns_server.parse::<Ipv4Addr>().unwrap();
-
-    // Since it might take an arbitrary number of steps, we enter an unbounded loop.
-    loop {
-        debug!("Attempting {:?} {} with ns {}", qtype, qname, ns);
+/// Check that `response` answers `query`, with the same id and question
+pub fn is_response_to(query: &DnsPacket, response: &DnsPacket) -> bool {
+    response.header.response
+        && response.header.id == query.header.id
+        && response.questions.len() == query.questions.len()
+        && response
+            .questions
+            .iter()
+            .zip(query.questions.iter())
+            .all(|(a, b)| a.qtype == b.qtype && a.name.eq_ignore_ascii_case(&b.name))
+}
 
-        // The next step is to send the query to the active server.
-        let ns_copy = ns;
+/// Check that `response` spells the question exactly the way `query` did
+fn echoes_case(query: &DnsPacket, response: &DnsPacket) -> bool {
+    response
+        .questions
+        .iter()
+        .zip(query.questions.iter())
+        .all(|(a, b)| a.name == b.name)
+}
 
-        let server = (ns_copy, 53);
-        let response = lookup(qname, qtype, server, cache)?;
+/// Flip the case of every letter at random, so a forged answer also has to guess the
+/// spelling of the name (DNS 0x20)
+fn randomize_case(qname: &str) -> String {
+    qname
+        .chars()
+        .map(|c| match rand::random() {
+            true => c.to_ascii_uppercase(),
+            false => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
 
-        // If there are entries in the answer section, and no errors, we are done!
-        if !response.answers.is_empty() && response.header.rescode == ResultCode::NOERROR {
-            return Ok(response);
+/// Put the name back the way the client spelled it, wherever the answer echoes `sent`
+fn restore_case(response: &mut DnsPacket, sent: &str, qname: &str) {
+    if sent == qname {
+        return;
+    }
+    for question in response.questions.iter_mut() {
+        if question.name.eq_ignore_ascii_case(sent) {
+            question.name = qname.to_string();
         }
-
-        // We might also get a `NXDOMAIN` reply, which is the authoritative name servers
-        // way of telling us that the name doesn't exist.
-        if response.header.rescode == ResultCode::NXDOMAIN {
-            return Ok(response);
+    }
+    for rec in response
+        .answers
+        .iter_mut()
+        .chain(response.authorities.iter_mut())
+        .chain(response.resources.iter_mut())
+    {
+        if rec.domain().eq_ignore_ascii_case(sent) {
+            rec.set_domain(qname);
         }
+    }
+}
 
-        // Otherwise, we'll try to find a new nameserver based on NS and a corresponding A
-        // record in the additional section. If this succeeds, we can switch name server
-        // and retry the loop.
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns;
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
 
-            continue;
-        }
+    use tokio::net::TcpListener;
 
-        // If not, we'll have to resolve the ip of a NS record. If no NS records exist,
-        // we'll go with what the last server told us.
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(x) => x,
-            None => return Ok(response),
+    use super::*;
+    use crate::{
+        networking::framing::{read_message, write_message},
+        protocol::dns_record::DnsRecord,
+    };
+
+    /// An answer to `query` giving out `addr`, which `spoil` can then get wrong
+    fn answer(query: &DnsPacket, addr: Ipv4Addr, spoil: impl FnOnce(&mut DnsPacket)) -> Vec<u8> {
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.questions = query.questions.clone();
+        response.answers.push(DnsRecord::A {
+            domain: query.questions[0].name.clone(),
+            addr,
+            ttl: 300,
+        });
+        spoil(&mut response);
+        let mut out = BytePacketBuffer::new();
+        response.write(&mut out).unwrap();
+        out.buf[..out.pos].to_vec()
+    }
+
+    fn parse(data: &[u8]) -> DnsPacket {
+        DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(data)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_to_other_queries_are_dropped() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (size, client) = socket.recv_from(&mut buf).await.unwrap();
+            let query = parse(&buf[..size]);
+            let forgeries = [
+                answer(&query, Ipv4Addr::new(192, 0, 2, 66), |r| {
+                    r.header.id = r.header.id.wrapping_add(1)
+                }),
+                answer(&query, Ipv4Addr::new(192, 0, 2, 66), |r| {
+                    r.questions[0].name = "evil.example".to_string()
+                }),
+                answer(&query, Ipv4Addr::new(192, 0, 2, 66), |r| {
+                    r.questions[0].qtype = QueryType::AAAA
+                }),
+                answer(&query, Ipv4Addr::new(192, 0, 2, 66), |r| {
+                    r.header.response = false
+                }),
+                b"garbage".to_vec(),
+            ];
+            for forgery in forgeries {
+                socket.send_to(&forgery, client).await.unwrap();
+            }
+            let response = answer(&query, Ipv4Addr::new(192, 0, 2, 1), |_| {});
+            socket.send_to(&response, client).await.unwrap();
+        });
+
+        let response = exchange("www.example", QueryType::A, server, QueryFlags::RECURSIVE)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.answers,
+            vec![DnsRecord::A {
+                domain: "www.example".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 300,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_answers_are_asked_for_again_over_tcp() {
+        // The same port for both, as a server would have it
+        let (socket, listener) = loop {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            if let Ok(listener) = TcpListener::bind(socket.local_addr().unwrap()).await {
+                break (socket, listener);
+            }
         };
+        let server = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (size, client) = socket.recv_from(&mut buf).await.unwrap();
+            let query = parse(&buf[..size]);
+            let truncated = answer(&query, Ipv4Addr::new(192, 0, 2, 66), |r| {
+                r.header.truncated_message = true;
+                r.answers.clear();
+            });
+            socket.send_to(&truncated, client).await.unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let query = parse(&read_message(&mut stream).await.unwrap().unwrap());
+            let response = answer(&query, Ipv4Addr::new(192, 0, 2, 1), |_| {});
+            write_message(&mut stream, &response).await.unwrap();
+        });
 
-        // Here we go down the rabbit hole by starting _another_ lookup sequence in the
-        // midst of our current one. Hopefully, this will give us the IP of an appropriate
-        // name server.
-        let recursive_response = recursive_lookup(dns_server, new_ns_name, QueryType::A, cache)?;
-
-        // Finally, we pick a random ip from the result, and restart the loop. If no such
-        // record is available, we again return the last result we got.
-        if let Some(new_ns) = recursive_response.get_random_a() {
-            ns = new_ns;
-        } else {
-            return Ok(response);
-        }
+        let response = exchange("www.example", QueryType::A, server, QueryFlags::RECURSIVE)
+            .await
+            .unwrap();
+        assert!(!response.header.truncated_message);
+        assert_eq!(response.answers.len(), 1);
     }
 }
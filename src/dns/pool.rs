@@ -0,0 +1,492 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future::select_ok;
+use reqwest::Client;
+use serde_derive::Deserialize;
+use tracing::{debug, warn};
+
+use crate::protocol::{
+    dns_packet::DnsPacket, query_type::QueryType, result_code::ResultCode, Result,
+};
+
+use super::{upstream::Upstream, CaseMismatch};
+
+/// How long an upstream is skipped after its first failure, doubled for each one after
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+
+/// The longest an upstream is skipped for, no matter how often it failed
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// How often a query is asked again after answers that didn't echo the case of the name
+const MAX_CASE_MISMATCHES: usize = 3;
+
+/// How upstream servers are picked for each query
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamStrategy {
+    /// Always try the servers in the order they are configured
+    #[default]
+    Failover,
+    /// Spread queries evenly, starting at the next server each time
+    RoundRobin,
+    /// Prefer the server with the lowest measured round trip time
+    Fastest,
+    /// Send the query to every server at once and take the first answer
+    Parallel,
+}
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    retry_after: Option<Instant>,
+    rtt: Option<Duration>,
+}
+
+struct UpstreamState {
+    upstream: Upstream,
+    health: Mutex<Health>,
+    randomize_case: bool,
+}
+
+impl UpstreamState {
+    fn is_healthy(&self, now: Instant) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .retry_after
+            .is_none_or(|retry_after| now >= retry_after)
+    }
+
+    fn rtt(&self) -> Duration {
+        self.health.lock().unwrap().rtt.unwrap_or_default()
+    }
+
+    fn record_success(&self, rtt: Duration) {
+        let mut health = self.health.lock().unwrap();
+        health.failures = 0;
+        health.retry_after = None;
+        // Smooth the round trip time so a single slow answer doesn't reorder everything
+        health.rtt = Some(match health.rtt {
+            Some(old) => (old * 7 + rtt * 3) / 10,
+            None => rtt,
+        });
+    }
+
+    fn record_failure(&self) {
+        let mut health = self.health.lock().unwrap();
+        health.failures += 1;
+        let backoff = BACKOFF_BASE
+            .saturating_mul(1 << (health.failures - 1).min(16))
+            .min(BACKOFF_MAX);
+        health.retry_after = Some(Instant::now() + backoff);
+        warn!(
+            "Upstream {} failed {} times in a row, backing off for {:?}",
+            self.upstream, health.failures, backoff
+        );
+    }
+
+    async fn query(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let mut tries = 0;
+        let (start, response) = loop {
+            tries += 1;
+            let start = Instant::now();
+            match self.upstream.query(qname, qtype, self.randomize_case).await {
+                // Someone is probably forging answers, which says nothing about the
+                // server, so start over with a new id, port and spelling
+                Err(err) if err.is::<CaseMismatch>() && tries < MAX_CASE_MISMATCHES => {
+                    debug!("{}, asking again", err);
+                }
+                Err(err) if err.is::<CaseMismatch>() => {
+                    return Err(format!("{}: {}", self.upstream, err).into())
+                }
+                response => break (start, response),
+            }
+        };
+        match response {
+            Ok(response) => {
+                self.record_success(start.elapsed());
+                // The server is fine, but it could not answer this one, so let another try
+                if matches!(
+                    response.header.rescode,
+                    ResultCode::SERVFAIL | ResultCode::REFUSED
+                ) {
+                    return Err(format!(
+                        "{} answered {:?}",
+                        self.upstream, response.header.rescode
+                    )
+                    .into());
+                }
+                Ok(response)
+            }
+            Err(err) => {
+                self.record_failure();
+                Err(format!("{}: {}", self.upstream, err).into())
+            }
+        }
+    }
+}
+
+/// A set of upstream servers, with health tracking for each
+#[derive(Clone)]
+pub struct UpstreamPool {
+    data: Arc<UpstreamPoolData>,
+}
+
+pub struct UpstreamPoolData {
+    upstreams: Vec<UpstreamState>,
+    strategy: UpstreamStrategy,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    /// `randomize_case` turns on DNS 0x20 for upstreams over plain UDP and for recursive
+    /// resolution, the encrypted ones don't need it
+    pub fn new(
+        servers: &[String],
+        strategy: UpstreamStrategy,
+        randomize_case: bool,
+        client: &Client,
+    ) -> Result<Self> {
+        let upstreams = servers
+            .iter()
+            .map(|server| {
+                let upstream = Upstream::parse(server, client)?;
+                Ok(UpstreamState {
+                    randomize_case: randomize_case
+                        && matches!(upstream, Upstream::Udp(_) | Upstream::Recursive(_)),
+                    upstream,
+                    health: Mutex::new(Health::default()),
+                })
+            })
+            .collect::<std::result::Result<Vec<_>, String>>()?;
+        Ok(Self {
+            data: Arc::new(UpstreamPoolData {
+                upstreams,
+                strategy,
+                next: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    /// Forward a query, trying other upstreams when one fails
+    /// an error is only returned once every upstream has failed
+    pub async fn query(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        let (healthy, backing_off) = self.candidates();
+        // Servers that are backing off are still tried as a last resort
+        for group in [healthy, backing_off] {
+            if group.is_empty() {
+                continue;
+            }
+            if self.data.strategy == UpstreamStrategy::Parallel {
+                let race = group
+                    .iter()
+                    .map(|upstream| Box::pin(upstream.query(qname, qtype)));
+                match select_ok(race).await {
+                    Ok((response, _)) => return Ok(response),
+                    Err(err) => debug!("All upstreams failed {} in parallel: {}", qname, err),
+                }
+                continue;
+            }
+            for upstream in group {
+                match upstream.query(qname, qtype).await {
+                    Ok(response) => return Ok(response),
+                    Err(err) => debug!("Upstream lookup for {} failed: {}", qname, err),
+                }
+            }
+        }
+        Err(format!("No upstream could answer {}", qname).into())
+    }
+
+    /// The upstreams to try in order, split into healthy ones and ones backing off
+    fn candidates(&self) -> (Vec<&UpstreamState>, Vec<&UpstreamState>) {
+        let upstreams = &self.data.upstreams;
+        let mut ordered: Vec<&UpstreamState> = match self.data.strategy {
+            UpstreamStrategy::Failover | UpstreamStrategy::Parallel => upstreams.iter().collect(),
+            UpstreamStrategy::RoundRobin => {
+                let start = self.data.next.fetch_add(1, Ordering::Relaxed) % upstreams.len().max(1);
+                upstreams[start..]
+                    .iter()
+                    .chain(&upstreams[..start])
+                    .collect()
+            }
+            UpstreamStrategy::Fastest => {
+                let mut ordered: Vec<_> = upstreams.iter().collect();
+                // Servers without a measurement sort first, so every server gets measured
+                ordered.sort_by_key(|upstream| upstream.rtt());
+                ordered
+            }
+        };
+        let now = Instant::now();
+        let backing_off = ordered
+            .iter()
+            .filter(|upstream| !upstream.is_healthy(now))
+            .copied()
+            .collect();
+        ordered.retain(|upstream| upstream.is_healthy(now));
+        (ordered, backing_off)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::dns::LOOKUP_TIMEOUT;
+    use crate::protocol::{byte_packet_buffer::BytePacketBuffer, dns_record::DnsRecord};
+
+    /// A server on a local socket that answers every name with the same address, and
+    /// the names it was asked for. The first `lowercase` answers spell the question in
+    /// lowercase, like a forger that only got the id right, and every answer waits for
+    /// `delay`.
+    async fn server(lowercase: usize, delay: Duration) -> (String, Arc<Mutex<Vec<String>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let log = asked.clone();
+        let answered = AtomicUsize::new(0);
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (size, client) = socket.recv_from(&mut buf).await.unwrap();
+                let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&buf[..size]))
+                    .unwrap();
+                let mut question = query.questions[0].clone();
+                log.lock().unwrap().push(question.name.clone());
+                if answered.fetch_add(1, Ordering::Relaxed) < lowercase {
+                    question.name = question.name.to_ascii_lowercase();
+                }
+
+                let mut response = DnsPacket::new();
+                response.header.id = query.header.id;
+                response.header.response = true;
+                response.answers.push(DnsRecord::A {
+                    domain: question.name.clone(),
+                    addr: [192, 0, 2, 1].into(),
+                    ttl: 300,
+                });
+                response.questions.push(question);
+                tokio::time::sleep(delay).await;
+                let mut out = BytePacketBuffer::new();
+                response.write(&mut out).unwrap();
+                socket.send_to(&out.buf[..out.pos], client).await.unwrap();
+            }
+        });
+        (addr.to_string(), asked)
+    }
+
+    fn new_pool(
+        servers: &[String],
+        strategy: UpstreamStrategy,
+        randomize_case: bool,
+    ) -> UpstreamPool {
+        UpstreamPool::new(servers, strategy, randomize_case, &Client::new()).unwrap()
+    }
+
+    /// A server that gets queries but never answers them
+    async fn silent() -> (String, UdpSocket) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        (socket.local_addr().unwrap().to_string(), socket)
+    }
+
+    /// An address nothing listens on, where queries are refused straight away
+    fn closed() -> String {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap().to_string()
+    }
+
+    fn failures(pool: &UpstreamPool, index: usize) -> u32 {
+        pool.data.upstreams[index].health.lock().unwrap().failures
+    }
+
+    fn count(asked: &Mutex<Vec<String>>) -> usize {
+        asked.lock().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn failover_moves_on_from_servers_that_stop_answering() {
+        let (silent, _socket) = silent().await;
+        let (addr, asked) = server(0, Duration::ZERO).await;
+        let pool = new_pool(&[silent, addr], UpstreamStrategy::Failover, false);
+
+        let start = Instant::now();
+        pool.query("example.com", QueryType::A).await.unwrap();
+        assert!(start.elapsed() >= LOOKUP_TIMEOUT);
+        assert_eq!(failures(&pool, 0), 1);
+        assert_eq!(count(&asked), 1);
+
+        // The silent server is backing off, so the next query goes straight on
+        let start = Instant::now();
+        pool.query("example.com", QueryType::A).await.unwrap();
+        assert!(start.elapsed() < LOOKUP_TIMEOUT);
+        assert_eq!(count(&asked), 2);
+    }
+
+    #[tokio::test]
+    async fn round_robin_starts_at_the_next_server() {
+        let mut servers = Vec::new();
+        let mut logs = Vec::new();
+        for _ in 0..3 {
+            let (addr, asked) = server(0, Duration::ZERO).await;
+            servers.push(addr);
+            logs.push(asked);
+        }
+        let pool = new_pool(&servers, UpstreamStrategy::RoundRobin, false);
+        for i in 0..6 {
+            pool.query("example.com", QueryType::A).await.unwrap();
+            assert_eq!(count(&logs[i % 3]), i / 3 + 1);
+        }
+        assert!(logs.iter().all(|asked| count(asked) == 2));
+    }
+
+    #[tokio::test]
+    async fn fastest_prefers_the_lowest_round_trip_time() {
+        let (slow, slow_asked) = server(0, Duration::from_millis(50)).await;
+        let (fast, fast_asked) = server(0, Duration::ZERO).await;
+        let pool = new_pool(&[slow, fast], UpstreamStrategy::Fastest, false);
+
+        // Both get measured first, then the fast one gets everything
+        for _ in 0..5 {
+            pool.query("example.com", QueryType::A).await.unwrap();
+        }
+        assert_eq!(count(&slow_asked), 1);
+        assert_eq!(count(&fast_asked), 4);
+        assert!(pool.data.upstreams[0].rtt() >= Duration::from_millis(50));
+        assert!(pool.data.upstreams[1].rtt() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn round_trip_times_are_smoothed() {
+        let state = UpstreamState {
+            upstream: Upstream::Udp("127.0.0.1:53".parse().unwrap()),
+            health: Mutex::new(Health::default()),
+            randomize_case: false,
+        };
+        state.record_success(Duration::from_millis(100));
+        assert_eq!(state.rtt(), Duration::from_millis(100));
+        state.record_success(Duration::ZERO);
+        assert_eq!(state.rtt(), Duration::from_millis(70));
+        state.record_success(Duration::from_millis(170));
+        assert_eq!(state.rtt(), Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn parallel_takes_the_first_answer() {
+        let (slow, slow_asked) = server(0, Duration::from_secs(2)).await;
+        let (fast, fast_asked) = server(0, Duration::ZERO).await;
+        let pool = new_pool(&[slow, closed(), fast], UpstreamStrategy::Parallel, false);
+
+        let start = Instant::now();
+        pool.query("example.com", QueryType::A).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(count(&slow_asked), 1);
+        assert_eq!(count(&fast_asked), 1);
+        // Losing the race is no failure, only not answering at all is
+        assert_eq!(failures(&pool, 0), 0);
+        assert_eq!(failures(&pool, 1), 1);
+        assert_eq!(failures(&pool, 2), 0);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_minute() {
+        let state = UpstreamState {
+            upstream: Upstream::Udp("127.0.0.1:53".parse().unwrap()),
+            health: Mutex::new(Health::default()),
+            randomize_case: false,
+        };
+        for secs in [1, 2, 4, 8, 16, 32, 60, 60, 60] {
+            let before = Instant::now();
+            state.record_failure();
+            let retry_after = state.health.lock().unwrap().retry_after.unwrap();
+            let backoff = retry_after - before;
+            assert!(
+                backoff >= Duration::from_secs(secs)
+                    && backoff < Duration::from_secs(secs) + Duration::from_secs(1),
+                "{:?} instead of {}s",
+                backoff,
+                secs
+            );
+            assert!(!state.is_healthy(Instant::now()));
+            assert!(state.is_healthy(retry_after));
+        }
+
+        // One answer and it's forgiven
+        state.record_success(Duration::from_millis(1));
+        assert_eq!(state.health.lock().unwrap().failures, 0);
+        assert!(state.is_healthy(Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn servers_backing_off_come_last() {
+        let (addr, asked) = server(0, Duration::ZERO).await;
+        let pool = new_pool(&[closed(), addr.clone()], UpstreamStrategy::Failover, false);
+        pool.query("example.com", QueryType::A).await.unwrap();
+        assert_eq!(failures(&pool, 0), 1);
+
+        let (healthy, backing_off) = pool.candidates();
+        assert_eq!(healthy.len(), 1);
+        assert_eq!(healthy[0].upstream.to_string(), format!("udp://{}", addr));
+        assert_eq!(backing_off.len(), 1);
+
+        // Not asked again while the healthy one answers
+        pool.query("example.com", QueryType::A).await.unwrap();
+        assert_eq!(failures(&pool, 0), 1);
+        assert_eq!(count(&asked), 2);
+
+        // But still tried when nothing else is left
+        let pool = new_pool(&[closed()], UpstreamStrategy::Failover, false);
+        assert!(pool.query("example.com", QueryType::A).await.is_err());
+        assert!(pool.query("example.com", QueryType::A).await.is_err());
+        assert_eq!(failures(&pool, 0), 2);
+    }
+
+    #[tokio::test]
+    async fn case_is_randomized_and_restored() {
+        let (addr, asked) = server(0, Duration::ZERO).await;
+        let pool = new_pool(&[addr], UpstreamStrategy::Failover, true);
+        for _ in 0..3 {
+            let response = pool.query("www.Example.com", QueryType::A).await.unwrap();
+            assert_eq!(response.questions[0].name, "www.Example.com");
+            assert_eq!(response.answers[0].domain(), "www.Example.com");
+        }
+        let asked = asked.lock().unwrap();
+        assert!(asked
+            .iter()
+            .all(|name| name.eq_ignore_ascii_case("www.example.com")));
+        assert!(asked.iter().any(|name| name != "www.Example.com"));
+    }
+
+    #[tokio::test]
+    async fn case_is_left_alone_without_randomizing() {
+        let (addr, asked) = server(0, Duration::ZERO).await;
+        let pool = new_pool(&[addr], UpstreamStrategy::Failover, false);
+        pool.query("www.Example.com", QueryType::A).await.unwrap();
+        assert_eq!(*asked.lock().unwrap(), vec!["www.Example.com"]);
+    }
+
+    #[tokio::test]
+    async fn case_mismatches_are_asked_again_without_backing_off() {
+        let (addr, asked) = server(1, Duration::ZERO).await;
+        let pool = new_pool(&[addr], UpstreamStrategy::Failover, true);
+        let response = pool.query("www.example.com", QueryType::A).await.unwrap();
+        assert_eq!(response.answers[0].domain(), "www.example.com");
+        assert_eq!(asked.lock().unwrap().len(), 2);
+        assert_eq!(failures(&pool, 0), 0);
+
+        // A server that never echoes the case is given up on for this query only
+        let (addr, asked) = server(usize::MAX, Duration::ZERO).await;
+        let pool = new_pool(&[addr], UpstreamStrategy::Failover, true);
+        assert!(pool.query("www.example.com", QueryType::A).await.is_err());
+        assert_eq!(asked.lock().unwrap().len(), MAX_CASE_MISMATCHES);
+        assert_eq!(failures(&pool, 0), 0);
+        assert!(pool.data.upstreams[0].is_healthy(Instant::now()));
+    }
+}
@@ -0,0 +1,770 @@
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use rand::seq::SliceRandom;
+use tracing::debug;
+
+use crate::{
+    dnssec::names,
+    protocol::{
+        dns_packet::DnsPacket, dns_question::DnsQuestion, dns_record::DnsRecord,
+        query_type::QueryType, result_code::ResultCode, Result,
+    },
+};
+
+use super::{exchange, upstream::DNS_PORT, QueryFlags};
+
+/// The addresses of the root servers (https://www.iana.org/domains/root/servers)
+pub const ROOT_HINTS: [&str; 26] = [
+    "198.41.0.4",
+    "2001:503:ba3e::2:30",
+    "170.247.170.2",
+    "2801:1b8:10::b",
+    "192.33.4.12",
+    "2001:500:2::c",
+    "199.7.91.13",
+    "2001:500:2d::d",
+    "192.203.230.10",
+    "2001:500:a8::e",
+    "192.5.5.241",
+    "2001:500:2f::f",
+    "192.112.36.4",
+    "2001:500:12::d0d",
+    "198.97.190.53",
+    "2001:500:1::53",
+    "192.36.148.17",
+    "2001:7fe::53",
+    "192.58.128.30",
+    "2001:503:c27::2:30",
+    "193.0.14.129",
+    "2001:7fd::1",
+    "199.7.83.42",
+    "2001:500:9f::42",
+    "202.12.27.33",
+    "2001:dc3::35",
+];
+
+/// The most queries sent for one question, nameserver lookups and CNAMEs included, so a
+/// badly broken or malicious delegation can't keep us busy
+const MAX_QUERIES: usize = 64;
+
+/// The longest a question may take to resolve
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most referrals followed for one name
+const MAX_REFERRALS: usize = 16;
+
+/// How deep lookups for the addresses of nameservers may nest
+const MAX_NS_DEPTH: usize = 3;
+
+/// The most nameserver names looked up for a delegation without glue
+const MAX_NS_LOOKUPS: usize = 3;
+
+/// The most servers of a zone tried for one query
+const MAX_SERVER_TRIES: usize = 4;
+
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// The most labels added one at a time, the rest of a long name is sent in one go
+/// (RFC 9156 section 2.3)
+const MAX_MINIMISE_COUNT: usize = 10;
+
+const MAX_DELEGATION_TTL: Duration = Duration::from_secs(86400);
+
+const MAX_DELEGATIONS: usize = 10000;
+
+struct Delegation {
+    servers: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+/// What one question has cost so far
+struct Work {
+    queries: usize,
+    flags: QueryFlags,
+}
+
+/// Resolves names itself, starting at the root servers and following referrals down to
+/// the servers that are authoritative for them
+///
+/// Only one label more than the zone that is being asked is sent to each server (QNAME
+/// minimisation, RFC 9156), so the root and TLD servers never see the full names.
+/// Delegations are cached with the addresses of their nameservers.
+pub struct Resolver {
+    hints: Vec<SocketAddr>,
+    delegations: DashMap<String, Delegation>,
+}
+
+impl Resolver {
+    /// `hints` are the addresses of the root servers, with an optional port
+    pub fn new(hints: Vec<SocketAddr>) -> Self {
+        Self {
+            hints,
+            delegations: DashMap::new(),
+        }
+    }
+
+    pub fn hints(&self) -> &[SocketAddr] {
+        &self.hints
+    }
+
+    /// Resolve a question, a name that can't be resolved gets SERVFAIL
+    ///
+    /// Failing to resolve one name says nothing about the next, so it isn't an error
+    /// that would make the resolver back off. With `randomize_case`, every server asked
+    /// along the way has to echo the random case of its question (DNS 0x20).
+    pub async fn query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        randomize_case: bool,
+    ) -> Result<DnsPacket> {
+        let mut work = Work {
+            queries: 0,
+            flags: QueryFlags::ITERATIVE.with_random_case(randomize_case),
+        };
+        let resolved =
+            tokio::time::timeout(RESOLVE_TIMEOUT, self.resolve(qname, qtype, &mut work, 0)).await;
+        match resolved {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(err)) => {
+                debug!("Couldn't resolve {} {}: {}", qname, qtype, err);
+                Ok(server_failure(qname, qtype))
+            }
+            Err(_) => {
+                debug!("Timed out resolving {} {}", qname, qtype);
+                Ok(server_failure(qname, qtype))
+            }
+        }
+    }
+
+    /// Resolve a name, and whatever its CNAMEs point at
+    async fn resolve(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        work: &mut Work,
+        depth: usize,
+    ) -> Result<DnsPacket> {
+        let mut response = self.resolve_name(qname, qtype, work, depth).await?;
+        let mut name = qname.to_string();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let target = follow_cnames(&name, &response.answers);
+            let answered = response
+                .answers
+                .iter()
+                .any(|rec| rec.query_type() == qtype && names::eq(rec.domain(), &target));
+            if qtype == QueryType::CNAME
+                || answered
+                || names::eq(&target, &name)
+                || response.header.rescode != ResultCode::NOERROR
+            {
+                return Ok(response);
+            }
+
+            // The rest of the chain lives in another zone, and is only believed from the
+            // servers of that zone
+            let next = Box::pin(self.resolve_name(&target, qtype, work, depth)).await?;
+            response.answers.extend(next.answers);
+            response.authorities = next.authorities;
+            response.header.rescode = next.header.rescode;
+            name = target;
+        }
+        Err(format!("CNAME chain of {} is too long", qname).into())
+    }
+
+    /// Follow referrals from the closest known delegation down to an answer for `qname`
+    async fn resolve_name(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        work: &mut Work,
+        depth: usize,
+    ) -> Result<DnsPacket> {
+        // DS records are answered by the parent side of a zone cut
+        let start = match qtype {
+            QueryType::DS => names::parent(qname).unwrap_or_default(),
+            _ => qname.to_string(),
+        };
+        let (mut zone, mut servers) = self.closest_delegation(&start);
+        let labels = names::labels(qname).len();
+        let mut known = names::labels(&zone).len();
+        let mut minimised = 0;
+        let mut referrals = 0;
+
+        loop {
+            let minimise = known + 1 < labels && minimised < MAX_MINIMISE_COUNT;
+            let (name, ask_type) = match minimise {
+                true => (names::suffix(qname, known + 1), QueryType::A),
+                false => (qname.to_string(), qtype),
+            };
+            debug!("Asking {:?} for {} {} in {}", servers, name, ask_type, zone);
+            let response = match self.ask(&servers, &name, ask_type, work).await {
+                Ok(response) => response,
+                // Some servers choke on names that only exist as a parent of others, so
+                // ask for the full name instead
+                Err(_) if minimise => {
+                    minimised = MAX_MINIMISE_COUNT;
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+
+            if let Some((cut, hosts)) = referral(&response, &zone, &name) {
+                referrals += 1;
+                if referrals > MAX_REFERRALS {
+                    return Err(format!("Too many referrals for {}", qname).into());
+                }
+                servers = self
+                    .nameservers(&zone, &cut, &hosts, &response, work, depth)
+                    .await?;
+                known = names::labels(&cut).len();
+                zone = cut;
+                continue;
+            }
+
+            if minimise {
+                match response.header.rescode {
+                    // Not every server knows that nothing below a missing name exists
+                    // either (RFC 8020), so let the full name have the last word
+                    ResultCode::NXDOMAIN => minimised = MAX_MINIMISE_COUNT,
+                    _ => {
+                        known += 1;
+                        minimised += 1;
+                    }
+                }
+                continue;
+            }
+            return Ok(in_zone(response, &zone));
+        }
+    }
+
+    /// Send a query to the servers of a zone until one of them answers
+    ///
+    /// Servers that can't be reached at all, such as IPv6 ones from a host without IPv6,
+    /// fail straight away and don't count as a try, or a zone with mixed glue would often
+    /// run out of tries before getting to an address that works.
+    async fn ask(
+        &self,
+        servers: &[SocketAddr],
+        qname: &str,
+        qtype: QueryType,
+        work: &mut Work,
+    ) -> Result<DnsPacket> {
+        let mut servers = servers.to_vec();
+        servers.shuffle(&mut rand::thread_rng());
+        let mut tries = 0;
+        for server in servers {
+            if tries >= MAX_SERVER_TRIES {
+                break;
+            }
+            if work.queries >= MAX_QUERIES {
+                return Err(format!("Gave up after {} queries", MAX_QUERIES).into());
+            }
+            let result = exchange(qname, qtype, server, work.flags).await;
+            if let Err(err) = &result {
+                if is_unreachable(&**err) {
+                    debug!("{} can't be reached: {}", server, err);
+                    continue;
+                }
+            }
+            tries += 1;
+            work.queries += 1;
+            match result {
+                Ok(response)
+                    if matches!(
+                        response.header.rescode,
+                        ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP
+                    ) =>
+                {
+                    debug!(
+                        "{} answered {:?} for {}",
+                        server, response.header.rescode, qname
+                    )
+                }
+                Ok(response) => return Ok(response),
+                Err(err) => debug!("{} failed to answer {}: {}", server, qname, err),
+            }
+        }
+        Err(format!("No nameserver answered {}", qname).into())
+    }
+
+    /// The addresses of the nameservers a referral points at, from the glue or looked up
+    async fn nameservers(
+        &self,
+        zone: &str,
+        cut: &str,
+        hosts: &[String],
+        response: &DnsPacket,
+        work: &mut Work,
+        depth: usize,
+    ) -> Result<Vec<SocketAddr>> {
+        let mut servers = glue(zone, hosts, &response.resources);
+
+        if servers.is_empty() {
+            if depth >= MAX_NS_DEPTH {
+                return Err(format!("Nameservers of {} are nested too deep", cut).into());
+            }
+            // A nameserver named inside the zone it serves can't be found without glue
+            for host in hosts
+                .iter()
+                .filter(|host| !names::is_subdomain(host, cut))
+                .take(MAX_NS_LOOKUPS)
+            {
+                for qtype in [QueryType::A, QueryType::AAAA] {
+                    match Box::pin(self.resolve(host, qtype, work, depth + 1)).await {
+                        Ok(found) => servers.extend(addresses(&found.answers, host)),
+                        Err(err) => debug!("Couldn't resolve nameserver {}: {}", host, err),
+                    }
+                }
+                if !servers.is_empty() {
+                    break;
+                }
+            }
+        }
+        if servers.is_empty() {
+            return Err(format!("No address for any nameserver of {}", cut).into());
+        }
+
+        let ttl = response
+            .authorities
+            .iter()
+            .filter(|rec| rec.query_type() == QueryType::NS)
+            .map(DnsRecord::ttl)
+            .min()
+            .unwrap_or_default();
+        self.cache_delegation(cut, servers.clone(), ttl);
+        Ok(servers)
+    }
+
+    /// The deepest delegation we know of for `qname`, the root if there is nothing else
+    fn closest_delegation(&self, qname: &str) -> (String, Vec<SocketAddr>) {
+        let now = Instant::now();
+        let mut zone = names::lowercase(qname);
+        loop {
+            if let Some(delegation) = self
+                .delegations
+                .get(&zone)
+                .filter(|delegation| delegation.expires > now)
+            {
+                return (zone, delegation.servers.clone());
+            }
+            match names::parent(&zone) {
+                Some(parent) => zone = parent,
+                None => return (String::new(), self.hints.clone()),
+            }
+        }
+    }
+
+    fn cache_delegation(&self, zone: &str, servers: Vec<SocketAddr>, ttl: u32) {
+        let now = Instant::now();
+        if self.delegations.len() >= MAX_DELEGATIONS {
+            self.delegations
+                .retain(|_, delegation| delegation.expires > now);
+            if self.delegations.len() >= MAX_DELEGATIONS {
+                self.delegations.clear();
+            }
+        }
+        let ttl = Duration::from_secs(ttl as u64).min(MAX_DELEGATION_TTL);
+        self.delegations.insert(
+            names::lowercase(zone),
+            Delegation {
+                servers,
+                expires: now + ttl,
+            },
+        );
+    }
+}
+
+/// Whether a query failed before it could get anywhere, so it cost no time
+fn is_unreachable(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.downcast_ref::<std::io::Error>().is_some_and(|err| {
+        matches!(
+            err.kind(),
+            ErrorKind::NetworkUnreachable
+                | ErrorKind::HostUnreachable
+                | ErrorKind::AddrNotAvailable
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::Unsupported
+        )
+    })
+}
+
+/// The zone cut and nameserver names of a referral from the servers of `zone`, which
+/// has to lead closer to `qname`
+fn referral(response: &DnsPacket, zone: &str, qname: &str) -> Option<(String, Vec<String>)> {
+    if response.header.rescode != ResultCode::NOERROR || !response.answers.is_empty() {
+        return None;
+    }
+    let mut ns = response.authorities.iter().filter_map(|rec| match rec {
+        DnsRecord::NS { domain, host, .. }
+            if names::is_subdomain(qname, domain)
+                && names::is_subdomain(domain, zone)
+                && !names::eq(domain, zone) =>
+        {
+            Some((domain, host))
+        }
+        _ => None,
+    });
+    let (cut, first) = ns.next()?;
+    let mut hosts = vec![first.clone()];
+    hosts.extend(
+        ns.filter(|(domain, _)| names::eq(domain, cut))
+            .map(|(_, host)| host.clone()),
+    );
+    Some((cut.clone(), hosts))
+}
+
+/// The addresses of `hosts` in the additional records of a referral from the servers of
+/// `zone`, glue is only believed for nameservers inside the zone that sent it
+fn glue(zone: &str, hosts: &[String], resources: &[DnsRecord]) -> Vec<SocketAddr> {
+    hosts
+        .iter()
+        .filter(|host| names::is_subdomain(host, zone))
+        .flat_map(|host| addresses(resources, host))
+        .collect()
+}
+
+/// `response` without the answers for names outside `zone`, whose servers have no say
+/// over them (RFC 5452 section 6)
+fn in_zone(mut response: DnsPacket, zone: &str) -> DnsPacket {
+    response
+        .answers
+        .retain(|rec| names::is_subdomain(rec.domain(), zone));
+    response
+}
+
+/// The addresses `records` hold for `host`
+fn addresses(records: &[DnsRecord], host: &str) -> Vec<SocketAddr> {
+    records
+        .iter()
+        .filter_map(|rec| match rec {
+            DnsRecord::A { domain, addr, .. } if names::eq(domain, host) => {
+                Some(SocketAddr::from((*addr, DNS_PORT)))
+            }
+            DnsRecord::AAAA { domain, addr, .. } if names::eq(domain, host) => {
+                Some(SocketAddr::from((*addr, DNS_PORT)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The name the CNAMEs in `answers` lead to from `qname`
+fn follow_cnames(qname: &str, answers: &[DnsRecord]) -> String {
+    let mut name = qname.to_string();
+    for _ in 0..MAX_CNAME_CHAIN {
+        let next = answers.iter().find_map(|rec| match rec {
+            DnsRecord::CNAME { domain, host, .. } if names::eq(domain, &name) => Some(host),
+            _ => None,
+        });
+        match next {
+            Some(next) => name = next.clone(),
+            None => break,
+        }
+    }
+    name
+}
+
+fn server_failure(qname: &str, qtype: QueryType) -> DnsPacket {
+    let mut packet = DnsPacket::new();
+    packet.header.response = true;
+    packet.header.rescode = ResultCode::SERVFAIL;
+    packet
+        .questions
+        .push(DnsQuestion::new(qname.to_string(), qtype));
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::protocol::byte_packet_buffer::BytePacketBuffer;
+
+    fn ns(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::NS {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 3600,
+        }
+    }
+
+    fn a(domain: &str, addr: &str) -> DnsRecord {
+        DnsRecord::A {
+            domain: domain.to_string(),
+            addr: addr.parse().unwrap(),
+            ttl: 3600,
+        }
+    }
+
+    fn cname(domain: &str, host: &str) -> DnsRecord {
+        DnsRecord::CNAME {
+            domain: domain.to_string(),
+            host: host.to_string(),
+            ttl: 3600,
+        }
+    }
+
+    fn delegating(authorities: Vec<DnsRecord>) -> DnsPacket {
+        let mut response = DnsPacket::new();
+        response.header.response = true;
+        response.authorities = authorities;
+        response
+    }
+
+    #[test]
+    fn referrals_lead_into_the_zone_asked_about() {
+        let response = delegating(vec![
+            ns("com", "a.gtld-servers.net"),
+            ns("com", "b.gtld-servers.net"),
+        ]);
+        assert_eq!(
+            referral(&response, "", "example.com"),
+            Some((
+                "com".to_string(),
+                vec![
+                    "a.gtld-servers.net".to_string(),
+                    "b.gtld-servers.net".to_string()
+                ]
+            ))
+        );
+
+        // Several labels down at once is fine too
+        let response = delegating(vec![ns("www.example.com", "ns.example.com")]);
+        assert_eq!(
+            referral(&response, "com", "www.example.com").map(|(cut, _)| cut),
+            Some("www.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn referrals_that_dont_lead_down_are_no_referrals() {
+        // The zone itself, above it, beside it, or away from the name
+        for (domain, zone) in [
+            ("example.com", "example.com"),
+            ("com", "example.com"),
+            ("", "example.com"),
+            ("example.net", "com"),
+            ("other.example.com", "example.com"),
+        ] {
+            let response = delegating(vec![ns(domain, "ns.example.net")]);
+            assert_eq!(
+                referral(&response, zone, "www.example.com"),
+                None,
+                "{} from {}",
+                domain,
+                zone
+            );
+        }
+
+        // Answers and errors aren't referrals whatever else they hold
+        let mut response = delegating(vec![ns("example.com", "ns.example.com")]);
+        response.answers.push(a("www.example.com", "192.0.2.1"));
+        assert_eq!(referral(&response, "com", "www.example.com"), None);
+        let mut response = delegating(vec![ns("example.com", "ns.example.com")]);
+        response.header.rescode = ResultCode::NXDOMAIN;
+        assert_eq!(referral(&response, "com", "www.example.com"), None);
+    }
+
+    #[test]
+    fn glue_is_only_believed_inside_the_zone() {
+        let hosts = ["ns1.example.com".to_string(), "ns.example.net".to_string()];
+        let resources = [
+            a("ns1.example.com", "192.0.2.1"),
+            a("ns.example.net", "192.0.2.2"),
+            a("unrelated.com", "192.0.2.3"),
+        ];
+        assert_eq!(
+            glue("com", &hosts, &resources),
+            vec![SocketAddr::from(([192, 0, 2, 1], DNS_PORT))]
+        );
+        assert!(glue("org", &hosts, &resources).is_empty());
+    }
+
+    #[test]
+    fn answers_outside_the_zone_are_dropped() {
+        let mut response = DnsPacket::new();
+        response.answers = vec![
+            cname("www.example.com", "cdn.example.net"),
+            a("cdn.example.net", "192.0.2.66"),
+        ];
+        let response = in_zone(response, "example.com");
+        assert_eq!(
+            response.answers,
+            vec![cname("www.example.com", "cdn.example.net")]
+        );
+        // So the target is left to be looked up on its own
+        assert_eq!(
+            follow_cnames("www.example.com", &response.answers),
+            "cdn.example.net"
+        );
+    }
+
+    #[test]
+    fn cname_chains_are_only_followed_so_far() {
+        let chain: Vec<DnsRecord> = (0..20)
+            .map(|i| cname(&format!("c{}.example", i), &format!("c{}.example", i + 1)))
+            .collect();
+        assert_eq!(
+            follow_cnames("c0.example", &chain),
+            format!("c{}.example", MAX_CNAME_CHAIN)
+        );
+
+        let chain = [
+            cname("a.example", "b.example"),
+            cname("b.example", "a.example"),
+        ];
+        assert_eq!(follow_cnames("a.example", &chain), "a.example");
+    }
+
+    #[test]
+    fn closest_delegation_falls_back_to_the_root() {
+        let hints = vec![SocketAddr::from(([192, 0, 2, 53], DNS_PORT))];
+        let servers = vec![SocketAddr::from(([192, 0, 2, 1], DNS_PORT))];
+        let resolver = Resolver::new(hints.clone());
+        resolver.cache_delegation("Example.COM", servers.clone(), 3600);
+        resolver.cache_delegation("expired.com", servers.clone(), 0);
+
+        assert_eq!(
+            resolver.closest_delegation("www.example.com"),
+            ("example.com".to_string(), servers)
+        );
+        assert_eq!(
+            resolver.closest_delegation("www.expired.com"),
+            (String::new(), hints.clone())
+        );
+        assert_eq!(
+            resolver.closest_delegation("example.org"),
+            (String::new(), hints)
+        );
+    }
+
+    /// A root server on a local socket that holds `name` itself, but claims none of the
+    /// names above it exist, and the names it was asked for with whether recursion was
+    /// desired. Without `echo_case` questions come back in lowercase.
+    async fn no_empty_non_terminals(
+        name: &'static str,
+        echo_case: bool,
+    ) -> (SocketAddr, Arc<Mutex<Vec<(String, bool)>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let log = asked.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (size, client) = socket.recv_from(&mut buf).await.unwrap();
+                let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&buf[..size]))
+                    .unwrap();
+                let mut question = query.questions[0].clone();
+                log.lock()
+                    .unwrap()
+                    .push((question.name.clone(), query.header.recursion_desired));
+
+                let mut response = DnsPacket::new();
+                response.header.id = query.header.id;
+                response.header.response = true;
+                if !echo_case {
+                    question.name = question.name.to_ascii_lowercase();
+                }
+                response.questions.push(question.clone());
+                match names::eq(&question.name, name) {
+                    true => response.answers.push(a(name, "192.0.2.1")),
+                    false => response.header.rescode = ResultCode::NXDOMAIN,
+                }
+                let mut out = BytePacketBuffer::new();
+                response.write(&mut out).unwrap();
+                socket.send_to(&out.buf[..out.pos], client).await.unwrap();
+            }
+        });
+        (addr, asked)
+    }
+
+    #[tokio::test]
+    async fn minimisation_asks_for_the_full_name_after_nxdomain() {
+        let (addr, asked) = no_empty_non_terminals("www.example.com", true).await;
+        let resolver = Resolver::new(vec![addr]);
+        let response = resolver
+            .query("www.example.com", QueryType::A, false)
+            .await
+            .unwrap();
+
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers, vec![a("www.example.com", "192.0.2.1")]);
+        // Iterative queries don't ask for recursion
+        assert_eq!(
+            *asked.lock().unwrap(),
+            vec![
+                ("com".to_string(), false),
+                ("www.example.com".to_string(), false)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_servers_dont_use_up_the_tries() {
+        let (addr, _) = no_empty_non_terminals("www.example.com", true).await;
+        // IPv6 ports nothing listens on, which refuse queries straight away
+        let mut hints = Vec::new();
+        for _ in 0..MAX_SERVER_TRIES * 2 {
+            let socket = std::net::UdpSocket::bind("[::1]:0").unwrap();
+            hints.push(socket.local_addr().unwrap());
+        }
+        hints.push(addr);
+
+        let resolver = Resolver::new(hints);
+        for _ in 0..5 {
+            let response = resolver
+                .query("www.example.com", QueryType::A, false)
+                .await
+                .unwrap();
+            assert_eq!(response.answers, vec![a("www.example.com", "192.0.2.1")]);
+        }
+    }
+
+    #[tokio::test]
+    async fn every_server_asked_has_to_echo_the_case() {
+        let (addr, asked) = no_empty_non_terminals("www.example.com", true).await;
+        let resolver = Resolver::new(vec![addr]);
+        for _ in 0..3 {
+            let response = resolver
+                .query("www.Example.com", QueryType::A, true)
+                .await
+                .unwrap();
+            // The answer is spelled the way it was asked for
+            assert_eq!(response.questions[0].name, "www.Example.com");
+            assert_eq!(response.answers, vec![a("www.Example.com", "192.0.2.1")]);
+        }
+        let asked = asked.lock().unwrap().clone();
+        assert_eq!(asked.len(), 6);
+        assert!(asked
+            .iter()
+            .all(|(name, _)| name.eq_ignore_ascii_case("com")
+                || name.eq_ignore_ascii_case("www.example.com")));
+        assert!(asked
+            .iter()
+            .any(|(name, _)| *name != "com" && *name != "www.Example.com"));
+
+        // A server that doesn't echo the case can't be told apart from a forger
+        let (addr, _) = no_empty_non_terminals("www.example.com", false).await;
+        let resolver = Resolver::new(vec![addr]);
+        let response = resolver
+            .query("www.example.com", QueryType::A, true)
+            .await
+            .unwrap();
+        assert_eq!(response.header.rescode, ResultCode::SERVFAIL);
+        let response = resolver
+            .query("www.example.com", QueryType::A, false)
+            .await
+            .unwrap();
+        assert_eq!(response.answers, vec![a("www.example.com", "192.0.2.1")]);
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Mutex;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug;
+
+use crate::{
+    networking::framing::{read_message, write_message},
+    protocol::{
+        byte_packet_buffer::BytePacketBuffer, dns_packet::DnsPacket, query_type::QueryType, Result,
+    },
+};
+
+use super::{is_response_to, query_packet, QueryFlags, LOOKUP_TIMEOUT};
+
+/// How many idle connections are kept open to a single upstream
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// Send a single query over an established stream, framed per RFC 7766
+pub async fn exchange_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    qname: &str,
+    qtype: QueryType,
+    flags: QueryFlags,
+) -> Result<DnsPacket> {
+    let mut packet = query_packet(qname, qtype, flags);
+
+    let mut req_buffer = BytePacketBuffer::new();
+    packet.write(&mut req_buffer)?;
+
+    let response = tokio::time::timeout(LOOKUP_TIMEOUT, async {
+        write_message(stream, &req_buffer.buf[0..req_buffer.pos]).await?;
+        loop {
+            let Some(data) = read_message(stream).await? else {
+                return Err("Connection closed before an answer arrived".into());
+            };
+            let mut res_buffer = BytePacketBuffer::from_bytes(&data);
+            let response = DnsPacket::from_buffer(&mut res_buffer)?;
+            if is_response_to(&packet, &response) {
+                return Ok(response);
+            }
+            debug!("Dropping mismatched stream response for {}", qname);
+        }
+    })
+    .await;
+
+    match response {
+        Ok(response) => response,
+        Err(_) => Err(format!("Timed out waiting for an answer to {}", qname).into()),
+    }
+}
+
+/// Open connections to an upstream that are not in use right now
+///
+/// A connection is taken out for the length of one exchange and put back afterwards, so
+/// concurrent queries each get their own connection while sequential ones share.
+pub struct IdleConnections<S> {
+    idle: Mutex<Vec<S>>,
+}
+
+impl<S> IdleConnections<S> {
+    pub fn new() -> Self {
+        Self {
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn take(&self) -> Option<S> {
+        self.idle.lock().unwrap().pop()
+    }
+
+    pub fn put(&self, stream: S) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+    }
+}
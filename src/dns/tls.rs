@@ -0,0 +1,216 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use rustls::{
+    pki_types::{ServerName, TrustAnchor},
+    ClientConfig, RootCertStore,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tracing::{debug, trace};
+
+use crate::protocol::{dns_packet::DnsPacket, query_type::QueryType, Result};
+
+use super::{
+    stream::{exchange_stream, IdleConnections},
+    QueryFlags, LOOKUP_TIMEOUT,
+};
+
+/// The port DNS over TLS servers listen on (RFC 7858)
+pub const DOT_PORT: u16 = 853;
+
+/// An upstream reached over DNS over TLS (RFC 7858)
+///
+/// Connections are kept open and reused between queries, since the handshake costs far
+/// more than the query itself.
+pub struct TlsUpstream {
+    addr: SocketAddr,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    connections: IdleConnections<TlsStream<TcpStream>>,
+}
+
+impl TlsUpstream {
+    pub fn new(addr: SocketAddr, server_name: &str) -> std::result::Result<Self, String> {
+        Self::with_roots(addr, server_name, webpki_roots::TLS_SERVER_ROOTS.to_vec())
+    }
+
+    /// An upstream whose certificate is checked against `roots` only
+    fn with_roots(
+        addr: SocketAddr,
+        server_name: &str,
+        roots: Vec<TrustAnchor<'static>>,
+    ) -> std::result::Result<Self, String> {
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|_| format!("Invalid TLS server name {}", server_name))?;
+        Ok(Self {
+            addr,
+            server_name,
+            connector: TlsConnector::from(client_config(roots)),
+            connections: IdleConnections::new(),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn server_name(&self) -> String {
+        self.server_name.to_str().to_string()
+    }
+
+    pub async fn query(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket> {
+        // A reused connection may have been closed by the server in the meantime, so a
+        // failure on one is retried once on a fresh connection
+        if let Some(mut stream) = self.connections.take() {
+            match exchange_stream(&mut stream, qname, qtype, QueryFlags::RECURSIVE).await {
+                Ok(response) => {
+                    self.connections.put(stream);
+                    return Ok(response);
+                }
+                Err(err) => debug!("Reused connection to {} failed: {}", self.addr, err),
+            }
+        }
+
+        let mut stream = self.connect().await?;
+        let response = exchange_stream(&mut stream, qname, qtype, QueryFlags::RECURSIVE).await?;
+        self.connections.put(stream);
+        Ok(response)
+    }
+
+    async fn connect(&self) -> Result<TlsStream<TcpStream>> {
+        trace!("Connecting to {} ({:?})", self.addr, self.server_name);
+        let stream = tokio::time::timeout(LOOKUP_TIMEOUT, async {
+            let tcp = TcpStream::connect(self.addr).await?;
+            tcp.set_nodelay(true)?;
+            self.connector.connect(self.server_name.clone(), tcp).await
+        })
+        .await
+        .map_err(|_| format!("Timed out connecting to {}", self.addr))??;
+        Ok(stream)
+    }
+}
+
+/// TLS client settings trusting `roots`, which are the Mozilla root store for every
+/// configured upstream
+fn client_config(roots: Vec<TrustAnchor<'static>>) -> Arc<ClientConfig> {
+    let roots = RootCertStore { roots };
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rustls::ServerConfig;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::{
+        networking::framing::{read_message, write_message},
+        protocol::{byte_packet_buffer::BytePacketBuffer, dns_record::DnsRecord},
+    };
+
+    const CERT: &[u8] = include_bytes!("../networking/testdata/one.pem");
+    const KEY: &[u8] = include_bytes!("../networking/testdata/one.key");
+
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(query)).unwrap();
+        let mut response = DnsPacket::new();
+        response.header.id = query.header.id;
+        response.header.response = true;
+        response.answers.push(DnsRecord::A {
+            domain: query.questions[0].name.clone(),
+            addr: [192, 0, 2, 1].into(),
+            ttl: 300,
+        });
+        response.questions = query.questions;
+        let mut out = BytePacketBuffer::new();
+        response.write(&mut out).unwrap();
+        out.buf[..out.pos].to_vec()
+    }
+
+    /// A DoT server for dns.test on a local port, which hangs up after every answer with
+    /// `hang_up`, and how many connections it accepted
+    async fn server(hang_up: bool) -> (SocketAddr, Arc<AtomicUsize>) {
+        let chain = rustls_pemfile::certs(&mut &CERT[..])
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        let key = rustls_pemfile::private_key(&mut &KEY[..]).unwrap().unwrap();
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(chain, key)
+                .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let count = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (tcp, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::Relaxed);
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(tcp).await else {
+                        return;
+                    };
+                    while let Ok(Some(query)) = read_message(&mut stream).await {
+                        write_message(&mut stream, &answer(&query)).await.unwrap();
+                        if hang_up {
+                            stream.shutdown().await.unwrap();
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    fn upstream(addr: SocketAddr, server_name: &str) -> TlsUpstream {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut &CERT[..]) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        TlsUpstream::with_roots(addr, server_name, roots.roots).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused() {
+        let (addr, accepted) = server(false).await;
+        let upstream = upstream(addr, "dns.test");
+        for _ in 0..3 {
+            let response = upstream.query("www.example", QueryType::A).await.unwrap();
+            assert_eq!(response.answers[0].domain(), "www.example");
+        }
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_retried_on_a_new_one() {
+        let (addr, accepted) = server(true).await;
+        let upstream = upstream(addr, "dns.test");
+        for _ in 0..3 {
+            let response = upstream.query("www.example", QueryType::A).await.unwrap();
+            assert_eq!(response.answers[0].domain(), "www.example");
+        }
+        assert_eq!(accepted.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn the_certificate_has_to_match_the_name() {
+        let (addr, _) = server(false).await;
+        let upstream = upstream(addr, "other.test");
+        assert!(upstream.query("www.example", QueryType::A).await.is_err());
+    }
+}
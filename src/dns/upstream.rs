@@ -0,0 +1,128 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use reqwest::Client;
+
+use crate::protocol::{dns_packet::DnsPacket, query_type::QueryType, Result};
+
+use super::{
+    exchange, exchange_tcp,
+    https::HttpsUpstream,
+    resolver::{Resolver, ROOT_HINTS},
+    tls::{TlsUpstream, DOT_PORT},
+    QueryFlags,
+};
+
+/// The port plain DNS servers listen on
+pub const DNS_PORT: u16 = 53;
+
+/// A server that queries can be forwarded to
+pub enum Upstream {
+    /// Plain DNS over UDP, written as `1.1.1.1`, `1.1.1.1:53` or `udp://[2606:4700::1111]:53`
+    Udp(SocketAddr),
+    /// Plain DNS over TCP, written as `tcp://192.168.1.1` or `tcp://192.168.1.1:53`
+    Tcp(SocketAddr),
+    /// DNS over TLS, written as `tls://1.1.1.1@cloudflare-dns.com`, the name after the `@`
+    /// is checked against the server certificate and defaults to the address itself
+    Tls(Arc<TlsUpstream>),
+    /// DNS over HTTPS, written as the URL of the resolver, such as
+    /// `https://cloudflare-dns.com/dns-query`
+    Https(Arc<HttpsUpstream>),
+    /// Resolve names ourselves from the root servers, written as `recursive`, or as
+    /// `recursive://192.0.2.1,192.0.2.2` to start from other servers than the root
+    Recursive(Arc<Resolver>),
+}
+
+impl Upstream {
+    /// `randomize_case` sends names over UDP in random case (DNS 0x20), for recursive
+    /// resolution to every server asked along the way
+    pub async fn query(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        randomize_case: bool,
+    ) -> Result<DnsPacket> {
+        match self {
+            Upstream::Udp(addr) => {
+                let flags = QueryFlags::RECURSIVE.with_random_case(randomize_case);
+                exchange(qname, qtype, *addr, flags).await
+            }
+            Upstream::Tcp(addr) => exchange_tcp(qname, qtype, *addr, QueryFlags::RECURSIVE).await,
+            Upstream::Tls(upstream) => upstream.query(qname, qtype).await,
+            Upstream::Https(upstream) => upstream.query(qname, qtype).await,
+            Upstream::Recursive(resolver) => resolver.query(qname, qtype, randomize_case).await,
+        }
+    }
+}
+
+impl Upstream {
+    /// Parse an upstream as written in the configuration, HTTPS upstreams send their
+    /// queries through `client`
+    pub fn parse(s: &str, client: &Client) -> std::result::Result<Self, String> {
+        let (scheme, rest) = match s {
+            "recursive" => ("recursive", ""),
+            _ => s.split_once("://").unwrap_or(("udp", s)),
+        };
+        match scheme {
+            "udp" => parse_addr(rest, DNS_PORT).map(Upstream::Udp),
+            "tcp" => parse_addr(rest, DNS_PORT).map(Upstream::Tcp),
+            "tls" => {
+                let (addr, name) = match rest.split_once('@') {
+                    Some((addr, name)) => (parse_addr(addr, DOT_PORT)?, name.to_string()),
+                    None => {
+                        let addr = parse_addr(rest, DOT_PORT)?;
+                        (addr, addr.ip().to_string())
+                    }
+                };
+                Ok(Upstream::Tls(Arc::new(TlsUpstream::new(addr, &name)?)))
+            }
+            "https" => Ok(Upstream::Https(Arc::new(HttpsUpstream::new(
+                s,
+                client.clone(),
+            )?))),
+            "recursive" => {
+                let hints = match rest {
+                    "" => ROOT_HINTS.join(","),
+                    hints => hints.to_string(),
+                };
+                let hints = hints
+                    .split(',')
+                    .map(|hint| parse_addr(hint.trim(), DNS_PORT))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                Ok(Upstream::Recursive(Arc::new(Resolver::new(hints))))
+            }
+            _ => Err(format!("Unsupported upstream scheme {} in {}", scheme, s)),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Udp(addr) => write!(f, "udp://{}", addr),
+            Upstream::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Upstream::Tls(upstream) => {
+                write!(f, "tls://{}@{}", upstream.addr(), upstream.server_name())
+            }
+            Upstream::Https(upstream) => write!(f, "{}", upstream.url()),
+            Upstream::Recursive(resolver) => {
+                let hints: Vec<String> = resolver.hints().iter().map(|h| h.to_string()).collect();
+                write!(f, "recursive://{}", hints.join(","))
+            }
+        }
+    }
+}
+
+/// Parse an address with an optional port, IPv6 addresses with a port need brackets
+fn parse_addr(s: &str, default_port: u16) -> std::result::Result<SocketAddr, String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let ip = s.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, default_port))
+        .map_err(|_| format!("Invalid upstream address {}", s))
+}
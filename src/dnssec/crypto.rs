@@ -0,0 +1,207 @@
+use ring::{digest, signature};
+
+use crate::protocol::{
+    byte_packet_buffer::{BytePacketBuffer, STREAM_MAX_SIZE},
+    dns_record::DnsRecord,
+    dnssec::{key_tag, RrsigData},
+    Result,
+};
+
+use super::names;
+
+/// The DNSKEY flag marking a zone key, only those may sign (RFC 4034 section 2.1.1)
+pub const ZONE_KEY: u16 = 0x0100;
+
+/// The DNSKEY flag marking a key the parent's DS records point at (RFC 4034 section 2.1.1)
+pub const SECURE_ENTRY_POINT: u16 = 0x0001;
+
+/// The DNSKEY flag marking a revoked key (RFC 5011)
+const REVOKED: u16 = 0x0080;
+
+/// The only protocol a DNSKEY may have
+pub const DNSKEY_PROTOCOL: u8 = 3;
+
+pub const ECDSAP256SHA256: u8 = 13;
+pub const ED25519: u8 = 15;
+
+/// The DS digest type everyone has to support (RFC 8624 section 3.3)
+pub const DIGEST_SHA256: u8 = 2;
+
+/// The signing algorithms we can check (RFC 8624 section 3.1)
+pub fn supported_algorithm(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+/// The DS digest types we can check (RFC 8624 section 3.3)
+pub fn supported_digest(digest_type: u8) -> bool {
+    matches!(digest_type, 1 | 2 | 4)
+}
+
+/// The RDATA of a record in canonical wire format
+pub fn canonical_rdata(record: &DnsRecord) -> Result<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::canonical(STREAM_MAX_SIZE);
+    record.write(&mut buffer)?;
+    // Past the owner, type, class, TTL and length
+    let start = names::wire(record.domain()).len() + 10;
+    Ok(buffer.buf[start..buffer.pos()].to_vec())
+}
+
+/// The data an RRSIG signs, itself without the signature followed by the RRset in
+/// canonical form and order (RFC 4034 section 3.1.8.1)
+pub fn signed_data(rrsig: &RrsigData, rrset: &[DnsRecord]) -> Result<Vec<u8>> {
+    let mut buffer = BytePacketBuffer::canonical(STREAM_MAX_SIZE);
+    rrsig.write_unsigned(&mut buffer)?;
+    let mut data = buffer.buf[..buffer.pos()].to_vec();
+
+    let Some(first) = rrset.first() else {
+        return Ok(data);
+    };
+    // A record expanded from a wildcard was signed as the wildcard
+    let mut owner = first.domain().to_string();
+    if (rrsig.labels as usize) < names::label_count(&owner) {
+        owner = names::wildcard(&names::suffix(&owner, rrsig.labels as usize));
+    }
+    let mut rdatas = rrset
+        .iter()
+        .map(canonical_rdata)
+        .collect::<Result<Vec<_>>>()?;
+    rdatas.sort();
+    rdatas.dedup();
+
+    let owner = names::wire(&owner);
+    for rdata in rdatas {
+        data.extend_from_slice(&owner);
+        data.extend_from_slice(&rrsig.type_covered.to_be_bytes());
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.extend_from_slice(&rrsig.original_ttl.to_be_bytes());
+        data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        data.extend_from_slice(&rdata);
+    }
+    Ok(data)
+}
+
+/// Check that `key` made `rrsig` over `rrset`
+pub fn verify_rrset(rrsig: &RrsigData, rrset: &[DnsRecord], key: &DnsRecord) -> bool {
+    let DnsRecord::DNSKEY {
+        domain,
+        flags,
+        protocol,
+        algorithm,
+        public_key,
+        ..
+    } = key
+    else {
+        return false;
+    };
+    if flags & ZONE_KEY == 0
+        || flags & REVOKED != 0
+        || *protocol != DNSKEY_PROTOCOL
+        || *algorithm != rrsig.algorithm
+        || !names::eq(domain, &rrsig.signer)
+        || canonical_rdata(key).map(|rdata| key_tag(&rdata)).ok() != Some(rrsig.key_tag)
+    {
+        return false;
+    }
+    let Ok(data) = signed_data(rrsig, rrset) else {
+        return false;
+    };
+    verify_signature(*algorithm, public_key, &data, &rrsig.signature)
+}
+
+/// Check a signature with a public key in DNSKEY format
+pub fn verify_signature(algorithm: u8, public_key: &[u8], message: &[u8], sig: &[u8]) -> bool {
+    match algorithm {
+        5 | 7 | 8 | 10 => {
+            // The exponent length takes one byte, or three when the first is zero
+            // (RFC 3110 section 2)
+            let (exponent_len, rest) = match public_key.split_first() {
+                Some((0, rest)) if rest.len() >= 2 => {
+                    (u16::from_be_bytes([rest[0], rest[1]]) as usize, &rest[2..])
+                }
+                Some((&len, rest)) => (len as usize, rest),
+                None => return false,
+            };
+            if rest.len() <= exponent_len {
+                return false;
+            }
+            let (e, n) = rest.split_at(exponent_len);
+            let params = match algorithm {
+                8 => &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                10 => &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+                _ => &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+            };
+            signature::RsaPublicKeyComponents { n, e }
+                .verify(params, message, sig)
+                .is_ok()
+        }
+        13 | 14 => {
+            // DNSKEY leaves off the uncompressed point marker (RFC 6605 section 4)
+            let mut key = vec![0x04];
+            key.extend_from_slice(public_key);
+            let params = match algorithm {
+                13 => &signature::ECDSA_P256_SHA256_FIXED,
+                _ => &signature::ECDSA_P384_SHA384_FIXED,
+            };
+            signature::UnparsedPublicKey::new(params, key)
+                .verify(message, sig)
+                .is_ok()
+        }
+        15 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// The digest a DS record holds for a DNSKEY (RFC 4034 section 5.1.4)
+pub fn ds_digest(digest_type: u8, key: &DnsRecord) -> Option<Vec<u8>> {
+    let algorithm = match digest_type {
+        1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        2 => &digest::SHA256,
+        4 => &digest::SHA384,
+        _ => return None,
+    };
+    let mut ctx = digest::Context::new(algorithm);
+    ctx.update(&names::wire(key.domain()));
+    ctx.update(&canonical_rdata(key).ok()?);
+    Some(ctx.finish().as_ref().to_vec())
+}
+
+/// Whether the DS record `ds` refers to `key`
+pub fn ds_matches(ds: &DnsRecord, key: &DnsRecord) -> bool {
+    let (
+        DnsRecord::DS {
+            domain: ds_domain,
+            key_tag: tag,
+            algorithm: ds_algorithm,
+            digest_type,
+            digest,
+            ..
+        },
+        DnsRecord::DNSKEY {
+            domain, algorithm, ..
+        },
+    ) = (ds, key)
+    else {
+        return false;
+    };
+    names::eq(ds_domain, domain)
+        && ds_algorithm == algorithm
+        && canonical_rdata(key).map(|rdata| key_tag(&rdata)).ok() == Some(*tag)
+        && ds_digest(*digest_type, key).as_ref() == Some(digest)
+}
+
+/// The hashed owner name NSEC3 uses in place of `name` (RFC 5155 section 5)
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let hash = |data: &[u8]| {
+        let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        ctx.update(data);
+        ctx.update(salt);
+        ctx.finish().as_ref().to_vec()
+    };
+    let mut hashed = hash(&names::wire(name));
+    for _ in 0..iterations {
+        hashed = hash(&hashed);
+    }
+    hashed
+}
@@ -0,0 +1,324 @@
+use std::cmp::Ordering;
+
+use crate::protocol::{
+    dns_record::DnsRecord,
+    dnssec::{base32hex_decode, Nsec3Data},
+    query_type::QueryType,
+};
+
+use super::{crypto::nsec3_hash, names};
+
+/// Validators may give up on NSEC3 chains hashed more often than this and treat the zone
+/// as insecure, it only makes them a cheap way to burn CPU (RFC 9276 section 3.2)
+pub(crate) const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// The only NSEC3 hash algorithm, SHA-1
+pub(crate) const NSEC3_SHA1: u8 = 1;
+
+const NS: u16 = 2;
+const SOA: u16 = 6;
+const CNAME: u16 = 5;
+const DNAME: u16 = 39;
+const DS: u16 = 43;
+
+/// What a set of NSEC or NSEC3 records shows about a name
+#[derive(Debug, PartialEq, Eq)]
+pub enum Proof {
+    Secure,
+    /// The name is in a span that may hold unsigned delegations (NSEC3 opt-out), or the
+    /// proof uses parameters we don't check
+    Insecure,
+}
+
+/// Prove that `name` has no records of `qtype`, or doesn't exist at all for NXDOMAIN,
+/// from the already validated NSEC or NSEC3 `records` of the authority section
+pub fn prove_absence(
+    name: &str,
+    qtype: QueryType,
+    nxdomain: bool,
+    records: &[DnsRecord],
+) -> Result<Proof, String> {
+    match Nsec3Chain::new(records) {
+        Some(Ok(chain)) if nxdomain => chain.prove_nxdomain(name),
+        Some(Ok(chain)) => chain.prove_nodata(name, qtype.to_num()),
+        Some(Err(proof)) => Ok(proof),
+        None => {
+            let nsecs = nsecs(records);
+            match nxdomain {
+                true => prove_nsec_nxdomain(name, &nsecs).map(|_| Proof::Secure),
+                false => prove_nsec_nodata(name, qtype.to_num(), &nsecs).map(|_| Proof::Secure),
+            }
+        }
+    }
+}
+
+/// Prove that there is nothing at `name` itself, for an answer that was expanded from a
+/// wildcard with `labels` labels (RFC 4035 section 5.3.4)
+pub fn prove_wildcard(name: &str, labels: usize, records: &[DnsRecord]) -> Result<Proof, String> {
+    match Nsec3Chain::new(records) {
+        Some(Ok(chain)) => {
+            let next_closer = names::suffix(name, labels + 1);
+            match chain.covering(&next_closer) {
+                Some(nsec3) if nsec3.data.opt_out() => Ok(Proof::Insecure),
+                Some(_) => Ok(Proof::Secure),
+                None => Err(format!("No NSEC3 shows {} doesn't exist", next_closer)),
+            }
+        }
+        Some(Err(proof)) => Ok(proof),
+        None => match nsecs(records).iter().any(|nsec| nsec.covers(name)) {
+            true => Ok(Proof::Secure),
+            false => Err(format!("No NSEC shows {} doesn't exist", name)),
+        },
+    }
+}
+
+/// Whether the records show a delegation at `name`, which is all that makes a missing DS
+/// mean an unsigned child zone rather than a lie
+pub fn is_delegation(name: &str, records: &[DnsRecord]) -> bool {
+    match Nsec3Chain::new(records) {
+        Some(Ok(chain)) => chain
+            .matching(name)
+            .is_some_and(|nsec3| nsec3.data.types.contains(&NS)),
+        // Opt-out spans only ever hide delegations
+        Some(Err(_)) => true,
+        None => nsecs(records)
+            .iter()
+            .any(|nsec| names::eq(nsec.owner, name) && nsec.has(NS)),
+    }
+}
+
+struct Nsec<'a> {
+    owner: &'a str,
+    next: &'a str,
+    types: &'a [u16],
+}
+
+impl Nsec<'_> {
+    fn has(&self, qtype: u16) -> bool {
+        self.types.contains(&qtype)
+    }
+
+    /// Names below a delegation or DNAME belong to another zone, the NSEC says nothing
+    /// about them
+    fn cut(&self) -> bool {
+        (self.has(NS) && !self.has(SOA)) || self.has(DNAME)
+    }
+
+    /// Whether `name` sorts strictly between the owner and the next name, the last NSEC
+    /// of the zone wraps around to the apex
+    fn covers(&self, name: &str) -> bool {
+        if names::is_subdomain(name, self.owner) && self.cut() {
+            return false;
+        }
+        let after = names::canonical_cmp(self.owner, name) == Ordering::Less;
+        let before = names::canonical_cmp(name, self.next) == Ordering::Less;
+        let wraps = names::canonical_cmp(self.next, self.owner) != Ordering::Greater;
+        after && (before || wraps)
+    }
+}
+
+fn nsecs(records: &[DnsRecord]) -> Vec<Nsec<'_>> {
+    records
+        .iter()
+        .filter_map(|record| match record {
+            DnsRecord::NSEC {
+                domain,
+                next,
+                types,
+                ..
+            } => Some(Nsec {
+                owner: domain,
+                next,
+                types,
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Check the types an NSEC or NSEC3 shows at the name that was asked for
+fn check_types(name: &str, qtype: u16, types: &[u16]) -> Result<(), String> {
+    if types.contains(&qtype) || types.contains(&CNAME) {
+        return Err(format!(
+            "Denial for {} lists the type that was asked for",
+            name
+        ));
+    }
+    let apex = types.contains(&SOA);
+    let delegation = types.contains(&NS) && !apex;
+    // DS lives on the parent side of a delegation, everything else on the child side
+    match qtype == DS {
+        true if apex => Err(format!(
+            "Denial of DS for {} comes from the child zone",
+            name
+        )),
+        false if delegation => Err(format!("Denial for {} comes from the parent zone", name)),
+        _ => Ok(()),
+    }
+}
+
+/// The closest encloser of `name` given the NSEC that covers it, the longest ancestor
+/// that exists (RFC 4592 section 3.3.1)
+fn nsec_closest_encloser(name: &str, nsec: &Nsec) -> String {
+    let a = names::common_ancestor(name, nsec.owner);
+    let b = names::common_ancestor(name, nsec.next);
+    match names::labels(&a).len() >= names::labels(&b).len() {
+        true => a,
+        false => b,
+    }
+}
+
+fn prove_nsec_nxdomain(name: &str, nsecs: &[Nsec]) -> Result<(), String> {
+    let Some(covering) = nsecs.iter().find(|nsec| nsec.covers(name)) else {
+        return Err(format!("No NSEC shows {} doesn't exist", name));
+    };
+    let wildcard = names::wildcard(&nsec_closest_encloser(name, covering));
+    match nsecs.iter().any(|nsec| nsec.covers(&wildcard)) {
+        true => Ok(()),
+        false => Err(format!("No NSEC shows {} doesn't exist", wildcard)),
+    }
+}
+
+fn prove_nsec_nodata(name: &str, qtype: u16, nsecs: &[Nsec]) -> Result<(), String> {
+    if let Some(nsec) = nsecs.iter().find(|nsec| names::eq(nsec.owner, name)) {
+        return check_types(name, qtype, nsec.types);
+    }
+    let Some(covering) = nsecs.iter().find(|nsec| nsec.covers(name)) else {
+        return Err(format!("No NSEC for {}", name));
+    };
+    // An empty non-terminal has names below it but no NSEC of its own
+    if names::is_subdomain(covering.next, name) {
+        return Ok(());
+    }
+    // Otherwise the answer has to come from a wildcard without the type
+    let wildcard = names::wildcard(&nsec_closest_encloser(name, covering));
+    match nsecs.iter().find(|nsec| names::eq(nsec.owner, &wildcard)) {
+        Some(nsec) => check_types(name, qtype, nsec.types),
+        None => Err(format!(
+            "No NSEC shows {} has no {} records",
+            name,
+            QueryType::from_num(qtype)
+        )),
+    }
+}
+
+struct Nsec3<'a> {
+    hash: Vec<u8>,
+    zone: String,
+    data: &'a Nsec3Data,
+}
+
+/// The NSEC3 records of one zone, sharing the same hash parameters
+struct Nsec3Chain<'a> {
+    records: Vec<Nsec3<'a>>,
+    salt: &'a [u8],
+    iterations: u16,
+}
+
+impl<'a> Nsec3Chain<'a> {
+    /// None without any NSEC3 records, or the verdict straight away when the chain uses
+    /// parameters we won't check
+    fn new(records: &'a [DnsRecord]) -> Option<Result<Nsec3Chain<'a>, Proof>> {
+        let nsec3s = records.iter().filter_map(|record| match record {
+            DnsRecord::NSEC3 { domain, data, .. } => Some((domain, data)),
+            _ => None,
+        });
+        let (_, first) = nsec3s.clone().next()?;
+        if first.hash_algorithm != NSEC3_SHA1 || first.iterations > MAX_NSEC3_ITERATIONS {
+            return Some(Err(Proof::Insecure));
+        }
+
+        let records = nsec3s
+            .filter(|(_, data)| {
+                data.hash_algorithm == first.hash_algorithm
+                    && data.iterations == first.iterations
+                    && data.salt == first.salt
+            })
+            .filter_map(|(domain, data)| {
+                let labels = names::labels(domain);
+                let hash = base32hex_decode(std::str::from_utf8(labels.first()?).ok()?)?;
+                Some(Nsec3 {
+                    hash,
+                    zone: names::suffix(domain, labels.len() - 1),
+                    data,
+                })
+            })
+            .collect();
+        Some(Ok(Nsec3Chain {
+            records,
+            salt: &first.salt,
+            iterations: first.iterations,
+        }))
+    }
+
+    fn hash(&self, name: &str) -> Vec<u8> {
+        nsec3_hash(name, self.salt, self.iterations)
+    }
+
+    fn matching(&self, name: &str) -> Option<&Nsec3<'a>> {
+        let hash = self.hash(name);
+        self.records
+            .iter()
+            .find(|nsec3| nsec3.hash == hash && names::is_subdomain(name, &nsec3.zone))
+    }
+
+    fn covering(&self, name: &str) -> Option<&Nsec3<'a>> {
+        let hash = self.hash(name);
+        self.records.iter().find(|nsec3| {
+            let (owner, next) = (&nsec3.hash, &nsec3.data.next_hashed);
+            // The last record of the chain wraps around, covering what sorts after it and
+            // what sorts before the first one
+            let covered = match next > owner {
+                true => *owner < hash && hash < *next,
+                false => hash > *owner || hash < *next,
+            };
+            names::is_subdomain(name, &nsec3.zone) && covered
+        })
+    }
+
+    /// The closest encloser proof, the longest ancestor of `name` that exists along with
+    /// the NSEC3 covering the name one label below it (RFC 5155 section 8.3)
+    fn closest_encloser(&self, name: &str) -> Option<(String, &Nsec3<'a>)> {
+        let count = names::labels(name).len();
+        (0..count).rev().find_map(|len| {
+            let encloser = names::suffix(name, len);
+            self.matching(&encloser)?;
+            let next_closer = names::suffix(name, len + 1);
+            Some((encloser, self.covering(&next_closer)?))
+        })
+    }
+
+    fn prove_nxdomain(&self, name: &str) -> Result<Proof, String> {
+        let Some((encloser, covering)) = self.closest_encloser(name) else {
+            return Err(format!("No closest encloser proof for {}", name));
+        };
+        if self.covering(&names::wildcard(&encloser)).is_none() {
+            return Err(format!("No NSEC3 shows *.{} doesn't exist", encloser));
+        }
+        match covering.data.opt_out() {
+            true => Ok(Proof::Insecure),
+            false => Ok(Proof::Secure),
+        }
+    }
+
+    fn prove_nodata(&self, name: &str, qtype: u16) -> Result<Proof, String> {
+        if let Some(nsec3) = self.matching(name) {
+            return check_types(name, qtype, &nsec3.data.types).map(|_| Proof::Secure);
+        }
+        let Some((encloser, covering)) = self.closest_encloser(name) else {
+            return Err(format!("No NSEC3 for {}", name));
+        };
+        // A missing DS in an opt-out span is an unsigned delegation (RFC 5155 section 8.6)
+        if qtype == DS && covering.data.opt_out() {
+            return Ok(Proof::Insecure);
+        }
+        match self.matching(&names::wildcard(&encloser)) {
+            Some(nsec3) => check_types(name, qtype, &nsec3.data.types).map(|_| Proof::Secure),
+            None => Err(format!(
+                "No NSEC3 shows {} has no {} records",
+                name,
+                QueryType::from_num(qtype)
+            )),
+        }
+    }
+}
@@ -0,0 +1,572 @@
+pub mod crypto;
+pub mod denial;
+pub mod names;
+pub mod signer;
+#[cfg(test)]
+mod tests;
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use dashmap::DashMap;
+use tracing::debug;
+
+use crate::{
+    cache::Cache,
+    dns::forward::Forwarder,
+    protocol::{
+        dns_packet::DnsPacket, dns_question::DnsQuestion, dns_record::DnsRecord, dnssec::RrsigData,
+        query_type::QueryType, result_code::ResultCode, Result,
+    },
+};
+
+use self::denial::Proof;
+
+/// The DS records of the root zone KSKs, KSK-2017 and KSK-2024
+/// (https://data.iana.org/root-anchors/root-anchors.xml)
+pub const ROOT_TRUST_ANCHORS: [&str; 2] = [
+    "20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    "38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+];
+
+/// How deep a chain of trust may go, a name can't have more labels than this anyway
+const MAX_CHAIN_DEPTH: usize = 128;
+
+/// The longest we hold on to the keys of a zone, so rollovers are noticed
+const MAX_KEY_TTL: Duration = Duration::from_secs(3600);
+
+/// How long a zone whose keys didn't check out stays bogus before we look again
+const BOGUS_TTL: Duration = Duration::from_secs(60);
+
+/// How many CNAMEs we follow through an answer
+const MAX_CNAME_CHAIN: usize = 16;
+
+/// The DNAME record type, which we don't otherwise know
+const DNAME: u16 = 39;
+
+/// The outcome of validating an answer (RFC 4035 section 4.3)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Security {
+    /// Every record is signed by a chain of trust from the trust anchor
+    Secure,
+    /// Some records come from a zone that is provably not signed
+    Insecure,
+    /// Signatures are missing or don't check out where they should
+    Bogus(String),
+}
+
+impl Security {
+    /// The weaker of both, an answer is only as good as its worst part
+    fn and(self, other: Security) -> Security {
+        match (self, other) {
+            (Security::Bogus(reason), _) | (_, Security::Bogus(reason)) => Security::Bogus(reason),
+            (Security::Insecure, _) | (_, Security::Insecure) => Security::Insecure,
+            _ => Security::Secure,
+        }
+    }
+}
+
+/// Where the validator gets the records it needs to build a chain of trust
+#[async_trait]
+pub trait Lookup: Send + Sync {
+    async fn lookup(&self, qname: &str, qtype: QueryType)
+        -> std::result::Result<DnsPacket, String>;
+}
+
+/// Looks records up through the cache, and asks the upstream servers on a miss
+pub struct UpstreamLookup {
+    pub cache: Cache,
+    pub upstreams: Forwarder,
+}
+
+#[async_trait]
+impl Lookup for UpstreamLookup {
+    async fn lookup(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+    ) -> std::result::Result<DnsPacket, String> {
+        let question = DnsQuestion::new(qname.to_string(), qtype);
+        if let Some(cached) = self.cache.get(&question) {
+            return Ok(cached);
+        }
+        let response = self
+            .upstreams
+            .query(qname, qtype)
+            .await
+            .map_err(|err| err.to_string())?;
+        self.cache.insert(&question, &response);
+        Ok(response)
+    }
+}
+
+/// What we know about the keys of a zone
+#[derive(Clone)]
+enum ZoneKeys {
+    /// The DNSKEY set, authenticated from the parent's DS records
+    Secure(Vec<DnsRecord>),
+    Insecure,
+    Bogus(String),
+}
+
+/// What the parent says about a zone's keys
+enum Delegation {
+    Signed(Vec<DnsRecord>),
+    Unsigned,
+    Bogus(String),
+}
+
+/// Checks the DNSSEC signatures of answers against a chain of trust starting at the
+/// root trust anchors (RFC 4035 section 5)
+#[derive(Clone)]
+pub struct Validator {
+    data: Arc<ValidatorData>,
+}
+
+struct ValidatorData {
+    lookup: Box<dyn Lookup>,
+    /// The DS records of the root zone, that every chain of trust starts from
+    anchors: Vec<DnsRecord>,
+    /// The keys of zones we've already looked at, and until when they hold
+    keys: DashMap<String, (ZoneKeys, Instant)>,
+}
+
+impl Validator {
+    /// `trust_anchors` are the root zone DS records in presentation format
+    pub fn new(trust_anchors: &[String], lookup: impl Lookup + 'static) -> Result<Self> {
+        let anchors = trust_anchors
+            .iter()
+            .map(|anchor| DnsRecord::from_text("", QueryType::DS, anchor, 0))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|err| format!("Invalid trust anchor: {}", err))?;
+        if anchors.is_empty() {
+            return Err("At least one trust anchor is needed to validate".into());
+        }
+
+        Ok(Validator {
+            data: Arc::new(ValidatorData {
+                lookup: Box::new(lookup),
+                anchors,
+                keys: DashMap::new(),
+            }),
+        })
+    }
+
+    /// Validate the answer to `question`
+    pub async fn validate(&self, question: &DnsQuestion, response: &DnsPacket) -> Security {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as u32)
+            .unwrap_or_default();
+        self.validate_at(question, response, now).await
+    }
+
+    /// Validate as if it was `now`, in seconds since the epoch, which keeps fixture zones
+    /// that were signed once valid
+    pub async fn validate_at(
+        &self,
+        question: &DnsQuestion,
+        response: &DnsPacket,
+        now: u32,
+    ) -> Security {
+        let nxdomain = match response.header.rescode {
+            ResultCode::NOERROR => false,
+            ResultCode::NXDOMAIN => true,
+            // Errors carry nothing to check
+            _ => return Security::Insecure,
+        };
+
+        // Every RRset in the answer has to check out on its own
+        let mut security = Security::Secure;
+        for (rrset, sigs) in rrsets(&response.answers) {
+            let owner = rrset[0].domain();
+            if sigs.is_empty() && is_synthesized(&rrset[0], &response.answers) {
+                continue;
+            }
+            security = security.and(self.verify_rrset(&rrset, &sigs, owner, now, 0).await);
+
+            // An answer expanded from a wildcard also needs proof that there was
+            // nothing closer to match
+            let count = names::label_count(owner);
+            if let Some(sig) = sigs.iter().find(|sig| (sig.labels as usize) < count) {
+                let labels = sig.labels as usize;
+                security =
+                    security.and(match self.authority_proofs(response, owner, now, 0).await {
+                        Ok(records) => {
+                            proof_security(denial::prove_wildcard(owner, labels, &records))
+                        }
+                        Err(security) => security,
+                    });
+            }
+        }
+
+        // If the answer doesn't end in the records that were asked for, the name or the
+        // type has to be shown not to exist
+        let target = follow_cnames(&question.name, question.qtype, &response.answers);
+        let answered = response
+            .answers
+            .iter()
+            .any(|rec| names::eq(rec.domain(), &target) && rec.query_type() == question.qtype);
+        if nxdomain || !answered {
+            security = security.and(
+                match self.authority_proofs(response, &target, now, 0).await {
+                    Ok(records) => proof_security(denial::prove_absence(
+                        &target,
+                        question.qtype,
+                        nxdomain,
+                        &records,
+                    )),
+                    Err(security) => security,
+                },
+            );
+        }
+
+        if let Security::Bogus(reason) = &security {
+            debug!(
+                "Bogus answer for {} {}: {}",
+                question.name, question.qtype, reason
+            );
+        }
+        security
+    }
+
+    /// Check an RRset against its signatures, `unsigned_at` is the name whose zone has to
+    /// be insecure if there aren't any
+    async fn verify_rrset(
+        &self,
+        rrset: &[DnsRecord],
+        sigs: &[RrsigData],
+        unsigned_at: &str,
+        now: u32,
+        depth: usize,
+    ) -> Security {
+        let owner = rrset[0].domain();
+        if sigs.is_empty() {
+            return self.unsigned(unsigned_at, now, depth).await;
+        }
+
+        let mut reason = format!(
+            "No usable signature for {} {}",
+            owner,
+            rrset[0].query_type()
+        );
+        for sig in sigs {
+            if !names::is_subdomain(owner, &sig.signer)
+                || sig.labels as usize > names::label_count(owner)
+            {
+                continue;
+            }
+            if !in_validity(sig, now) {
+                reason = format!(
+                    "Signature for {} {} has expired",
+                    owner,
+                    rrset[0].query_type()
+                );
+                continue;
+            }
+            match self.zone_keys(&sig.signer, now, depth + 1).await {
+                ZoneKeys::Insecure => return Security::Insecure,
+                ZoneKeys::Bogus(err) => reason = err,
+                ZoneKeys::Secure(keys) => {
+                    if keys.iter().any(|key| crypto::verify_rrset(sig, rrset, key)) {
+                        return Security::Secure;
+                    }
+                    reason = format!("Bad signature for {} {}", owner, rrset[0].query_type());
+                }
+            }
+        }
+        Security::Bogus(reason)
+    }
+
+    /// Records without signatures are fine only in a zone that is provably unsigned
+    async fn unsigned(&self, name: &str, now: u32, depth: usize) -> Security {
+        let response = match self.data.lookup.lookup(name, QueryType::SOA).await {
+            Ok(response) => response,
+            Err(err) => {
+                return Security::Bogus(format!("Couldn't find the zone of {}: {}", name, err))
+            }
+        };
+        let zone = response
+            .answers
+            .iter()
+            .chain(response.authorities.iter())
+            .find(|rec| {
+                rec.query_type() == QueryType::SOA && names::is_subdomain(name, rec.domain())
+            })
+            .map(|soa| soa.domain().to_string());
+        let Some(zone) = zone else {
+            return Security::Bogus(format!("Couldn't find the zone of {}", name));
+        };
+
+        match self.zone_keys(&zone, now, depth + 1).await {
+            ZoneKeys::Insecure => Security::Insecure,
+            ZoneKeys::Secure(_) => Security::Bogus(format!("Missing signatures for {}", name)),
+            ZoneKeys::Bogus(reason) => Security::Bogus(reason),
+        }
+    }
+
+    /// Validate the NSEC and NSEC3 records of the authority section, along with the SOA
+    /// that goes with them, and hand back the ones that are secure
+    async fn authority_proofs(
+        &self,
+        response: &DnsPacket,
+        unsigned_at: &str,
+        now: u32,
+        depth: usize,
+    ) -> std::result::Result<Vec<DnsRecord>, Security> {
+        let mut security = Security::Secure;
+        let mut proofs = Vec::new();
+        let mut seen = false;
+        for (rrset, sigs) in rrsets(&response.authorities) {
+            let qtype = rrset[0].query_type();
+            if !matches!(qtype, QueryType::SOA | QueryType::NSEC | QueryType::NSEC3) {
+                continue;
+            }
+            seen = true;
+            security = security.and(
+                self.verify_rrset(&rrset, &sigs, unsigned_at, now, depth)
+                    .await,
+            );
+            if qtype != QueryType::SOA {
+                proofs.extend(rrset);
+            }
+        }
+        if !seen {
+            security = self.unsigned(unsigned_at, now, depth).await;
+        }
+
+        match security {
+            Security::Secure if proofs.is_empty() => Err(Security::Bogus(
+                "Signed denial without NSEC or NSEC3 records".to_string(),
+            )),
+            Security::Secure => Ok(proofs),
+            other => Err(other),
+        }
+    }
+
+    /// The keys of `zone`, checked against the DS records of its parent
+    async fn zone_keys(&self, zone: &str, now: u32, depth: usize) -> ZoneKeys {
+        let key = names::lowercase(zone);
+        let cached = self
+            .data
+            .keys
+            .get(&key)
+            .filter(|entry| entry.1 > Instant::now())
+            .map(|entry| entry.0.clone());
+        if let Some(keys) = cached {
+            return keys;
+        }
+        if depth > MAX_CHAIN_DEPTH {
+            return ZoneKeys::Bogus(format!("Chain of trust for {} is too long", zone));
+        }
+
+        let (keys, ttl) = Box::pin(self.fetch_zone_keys(zone, now, depth)).await;
+        self.data
+            .keys
+            .insert(key, (keys.clone(), Instant::now() + ttl));
+        keys
+    }
+
+    async fn fetch_zone_keys(&self, zone: &str, now: u32, depth: usize) -> (ZoneKeys, Duration) {
+        let ds = if zone.is_empty() {
+            self.data.anchors.clone()
+        } else {
+            match self.delegation(zone, now, depth).await {
+                Delegation::Signed(ds) => ds,
+                Delegation::Unsigned => return (ZoneKeys::Insecure, MAX_KEY_TTL),
+                Delegation::Bogus(reason) => return (ZoneKeys::Bogus(reason), BOGUS_TTL),
+            }
+        };
+        // A zone signed only with algorithms we don't know is as good as unsigned
+        // (RFC 4035 section 5.2)
+        let ds: Vec<&DnsRecord> = ds
+            .iter()
+            .filter(|ds| match ds {
+                DnsRecord::DS {
+                    algorithm,
+                    digest_type,
+                    ..
+                } => {
+                    crypto::supported_algorithm(*algorithm)
+                        && crypto::supported_digest(*digest_type)
+                }
+                _ => false,
+            })
+            .collect();
+        if ds.is_empty() {
+            return (ZoneKeys::Insecure, MAX_KEY_TTL);
+        }
+
+        let response = match self.data.lookup.lookup(zone, QueryType::DNSKEY).await {
+            Ok(response) => response,
+            Err(err) => {
+                let reason = format!("Couldn't look up the DNSKEY of {}: {}", zone, err);
+                return (ZoneKeys::Bogus(reason), BOGUS_TTL);
+            }
+        };
+        let Some((keys, sigs)) = rrsets(&response.answers).into_iter().find(|(rrset, _)| {
+            rrset[0].query_type() == QueryType::DNSKEY && names::eq(rrset[0].domain(), zone)
+        }) else {
+            return (
+                ZoneKeys::Bogus(format!("No DNSKEY for {}", zone)),
+                BOGUS_TTL,
+            );
+        };
+
+        // One of the keys the parent vouches for has to sign the whole set
+        for key in keys
+            .iter()
+            .filter(|key| ds.iter().any(|ds| crypto::ds_matches(ds, key)))
+        {
+            for sig in sigs
+                .iter()
+                .filter(|sig| names::eq(&sig.signer, zone) && in_validity(sig, now))
+            {
+                if crypto::verify_rrset(sig, &keys, key) {
+                    let ttl = keys
+                        .iter()
+                        .map(DnsRecord::ttl)
+                        .chain([sig.original_ttl, sig.expiration.wrapping_sub(now)])
+                        .min()
+                        .unwrap_or_default();
+                    let ttl = Duration::from_secs(ttl as u64).min(MAX_KEY_TTL);
+                    return (ZoneKeys::Secure(keys), ttl);
+                }
+            }
+        }
+        (
+            ZoneKeys::Bogus(format!("No DNSKEY of {} matches its DS records", zone)),
+            BOGUS_TTL,
+        )
+    }
+
+    /// Ask the parent of `zone` for its DS records, or proof that there are none
+    async fn delegation(&self, zone: &str, now: u32, depth: usize) -> Delegation {
+        let response = match self.data.lookup.lookup(zone, QueryType::DS).await {
+            Ok(response) => response,
+            Err(err) => {
+                return Delegation::Bogus(format!("Couldn't look up the DS of {}: {}", zone, err))
+            }
+        };
+        // Without signatures, it's the parent's zone that decides
+        let parent = names::parent(zone).unwrap_or_default();
+
+        let ds = rrsets(&response.answers).into_iter().find(|(rrset, _)| {
+            rrset[0].query_type() == QueryType::DS && names::eq(rrset[0].domain(), zone)
+        });
+        if let Some((ds, sigs)) = ds {
+            // Only the parent can sign DS records
+            let sigs: Vec<RrsigData> = sigs
+                .into_iter()
+                .filter(|sig| !names::eq(&sig.signer, zone))
+                .collect();
+            return match self.verify_rrset(&ds, &sigs, &parent, now, depth).await {
+                Security::Secure => Delegation::Signed(ds),
+                Security::Insecure => Delegation::Unsigned,
+                Security::Bogus(reason) => Delegation::Bogus(reason),
+            };
+        }
+
+        let nxdomain = response.header.rescode == ResultCode::NXDOMAIN;
+        match self.authority_proofs(&response, &parent, now, depth).await {
+            Ok(records) => match denial::prove_absence(zone, QueryType::DS, nxdomain, &records) {
+                Ok(Proof::Insecure) => Delegation::Unsigned,
+                // A signer that isn't a delegation can't be anyone's zone
+                Ok(Proof::Secure) if !nxdomain && denial::is_delegation(zone, &records) => {
+                    Delegation::Unsigned
+                }
+                Ok(Proof::Secure) => Delegation::Bogus(format!("{} isn't a delegation", zone)),
+                Err(reason) => Delegation::Bogus(reason),
+            },
+            Err(Security::Bogus(reason)) => Delegation::Bogus(reason),
+            Err(_) => Delegation::Unsigned,
+        }
+    }
+}
+
+fn proof_security(proof: std::result::Result<Proof, String>) -> Security {
+    match proof {
+        Ok(Proof::Secure) => Security::Secure,
+        Ok(Proof::Insecure) => Security::Insecure,
+        Err(reason) => Security::Bogus(reason),
+    }
+}
+
+/// Whether `now` lies between the inception and expiration of the signature, in serial
+/// number arithmetic (RFC 4034 section 3.1.5)
+fn in_validity(sig: &RrsigData, now: u32) -> bool {
+    let not_after = |a: u32, b: u32| (b.wrapping_sub(a) as i32) >= 0;
+    not_after(sig.inception, now) && not_after(now, sig.expiration)
+}
+
+/// Group records into RRsets by owner and type, each with the signatures that cover it
+fn rrsets(records: &[DnsRecord]) -> Vec<(Vec<DnsRecord>, Vec<RrsigData>)> {
+    let mut sets: Vec<(Vec<DnsRecord>, Vec<RrsigData>)> = Vec::new();
+    for record in records {
+        if let DnsRecord::RRSIG { .. } = record {
+            continue;
+        }
+        let set = sets.iter_mut().find(|(rrset, _)| {
+            rrset[0].query_type() == record.query_type()
+                && names::eq(rrset[0].domain(), record.domain())
+        });
+        match set {
+            Some((rrset, _)) => rrset.push(record.clone()),
+            None => sets.push((vec![record.clone()], Vec::new())),
+        }
+    }
+    for record in records {
+        let DnsRecord::RRSIG { domain, data, .. } = record else {
+            continue;
+        };
+        let set = sets.iter_mut().find(|(rrset, _)| {
+            rrset[0].query_type().to_num() == data.type_covered
+                && names::eq(rrset[0].domain(), domain)
+        });
+        if let Some((_, sigs)) = set {
+            sigs.push(data.clone());
+        }
+    }
+    sets
+}
+
+/// A CNAME synthesized from a DNAME comes without signatures, the signed DNAME vouches
+/// for it (RFC 4035 section 5.3.2), but only if it points where the DNAME says
+fn is_synthesized(record: &DnsRecord, answers: &[DnsRecord]) -> bool {
+    let DnsRecord::CNAME { domain, host, .. } = record else {
+        return false;
+    };
+    answers.iter().any(|rec| match rec {
+        DnsRecord::UNKNOWN {
+            domain: owner,
+            qtype: DNAME,
+            data,
+            ..
+        } if !names::eq(domain, owner) => names::from_wire(data)
+            .and_then(|target| names::substitute(domain, owner, &target))
+            .is_some_and(|substituted| names::eq(host, &substituted)),
+        _ => false,
+    })
+}
+
+/// The name an answer ends up at after following its CNAMEs from `qname`
+fn follow_cnames(qname: &str, qtype: QueryType, answers: &[DnsRecord]) -> String {
+    let mut name = qname.to_string();
+    if qtype == QueryType::CNAME {
+        return name;
+    }
+    for _ in 0..MAX_CNAME_CHAIN {
+        let next = answers.iter().find_map(|rec| match rec {
+            DnsRecord::CNAME { domain, host, .. } if names::eq(domain, &name) => Some(host.clone()),
+            _ => None,
+        });
+        match next {
+            Some(next) => name = next,
+            None => break,
+        }
+    }
+    name
+}
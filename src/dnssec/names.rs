@@ -0,0 +1,127 @@
+use std::cmp::Ordering;
+
+use crate::protocol::byte_packet_buffer::{format_label, parse_name};
+
+/// The labels of `name` in lowercase, which is how DNSSEC compares names
+pub fn labels(name: &str) -> Vec<Vec<u8>> {
+    let mut labels = parse_name(name).unwrap_or_default();
+    labels
+        .iter_mut()
+        .for_each(|label| label.make_ascii_lowercase());
+    labels
+}
+
+/// The name in uncompressed wire format and lowercase
+pub fn wire(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in labels(name) {
+        wire.push(label.len() as u8);
+        wire.extend(label);
+    }
+    wire.push(0);
+    wire
+}
+
+/// The number of labels, not counting the root or a leading wildcard, as RRSIG records
+/// count them (RFC 4034 section 3.1.3)
+pub fn label_count(name: &str) -> usize {
+    let labels = labels(name);
+    match labels.first() {
+        Some(first) if first == b"*" => labels.len() - 1,
+        _ => labels.len(),
+    }
+}
+
+/// The name in lowercase, so any spelling of it can be looked up the same
+pub fn lowercase(name: &str) -> String {
+    join(&labels(name))
+}
+
+pub fn eq(a: &str, b: &str) -> bool {
+    labels(a) == labels(b)
+}
+
+/// Whether `name` is `zone` or anywhere below it
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    let name = labels(name);
+    let zone = labels(zone);
+    name.len() >= zone.len() && name[name.len() - zone.len()..] == zone[..]
+}
+
+/// The last `count` labels of `name`
+pub fn suffix(name: &str, count: usize) -> String {
+    let labels = labels(name);
+    join(&labels[labels.len().saturating_sub(count)..])
+}
+
+/// The name one label up, the root has none
+pub fn parent(name: &str) -> Option<String> {
+    let labels = labels(name);
+    labels.split_first().map(|(_, rest)| join(rest))
+}
+
+/// The wildcard that would match names right below `name`
+pub fn wildcard(name: &str) -> String {
+    match name.is_empty() {
+        true => "*".to_string(),
+        false => format!("*.{}", name),
+    }
+}
+
+/// Compare names in canonical DNSSEC order, label by label from the right
+/// (RFC 4034 section 6.1)
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    labels(a).iter().rev().cmp(labels(b).iter().rev())
+}
+
+/// The longest name both `a` and `b` are under
+pub fn common_ancestor(a: &str, b: &str) -> String {
+    let a = labels(a);
+    let b = labels(b);
+    let common = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    join(&a[a.len() - common..])
+}
+
+/// A name in uncompressed wire format, the way DNAME targets are sent (RFC 6672
+/// section 2.5)
+pub fn from_wire(data: &[u8]) -> Option<String> {
+    let mut labels = Vec::new();
+    let mut pos = 0;
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1;
+        match len {
+            0 => break,
+            // Compression pointers have no place here
+            64.. => return None,
+            _ => labels.push(data.get(pos..pos + len)?.to_vec()),
+        }
+        pos += len;
+    }
+    (pos == data.len()).then(|| join(&labels))
+}
+
+/// `name` with `from` at its end replaced by `to`, the way a DNAME rewrites the names
+/// below it (RFC 6672 section 2.2)
+pub fn substitute(name: &str, from: &str, to: &str) -> Option<String> {
+    if !is_subdomain(name, from) {
+        return None;
+    }
+    let mut labels = labels(name);
+    labels.truncate(labels.len() - self::labels(from).len());
+    labels.extend(self::labels(to));
+    Some(join(&labels))
+}
+
+fn join(labels: &[Vec<u8>]) -> String {
+    labels
+        .iter()
+        .map(|label| format_label(label))
+        .collect::<Vec<_>>()
+        .join(".")
+}
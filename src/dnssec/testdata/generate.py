@@ -0,0 +1,157 @@
+# Generates the pre-signed DNSSEC fixture zones for the validator tests, independently of the
+# Rust signing code. Ed25519 signatures are deterministic, so the output is reproducible:
+#
+#     python3 generate.py .
+import hashlib, struct, base64, datetime, os, sys
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+from cryptography.hazmat.primitives import serialization
+
+OUT = sys.argv[1]
+A, NS, SOA, TXT, AAAA, DNAME, DS, RRSIG, NSEC, DNSKEY, NSEC3 = 1, 2, 6, 16, 28, 39, 43, 46, 47, 48, 50
+TNAME = {1: 'A', 2: 'NS', 6: 'SOA', 16: 'TXT', 28: 'AAAA', 39: 'TYPE39', 43: 'DS', 46: 'RRSIG', 47: 'NSEC', 48: 'DNSKEY', 50: 'NSEC3'}
+
+def ts(s): return int(datetime.datetime.strptime(s, '%Y%m%d%H%M%S').replace(tzinfo=datetime.timezone.utc).timestamp())
+INCEPTION, EXPIRATION = ts('20260101000000'), ts('20360101000000')
+OLD_INCEPTION, OLD_EXPIRATION = ts('20240101000000'), ts('20250101000000')
+def tstr(t): return datetime.datetime.fromtimestamp(t, datetime.timezone.utc).strftime('%Y%m%d%H%M%S')
+
+def labels(n): return [l for l in n.lower().split('.') if l]
+def wire(n): return b''.join(bytes([len(l)]) + l.encode() for l in labels(n)) + b'\0'
+def under(l, o): return f'{l}.{o}' if o else l
+def fqdn(n): return n + '.' if n else '.'
+def canon_key(n): return [l.encode() for l in reversed(labels(n))]
+def is_sub(n, z): L, Z = labels(n), labels(z); return len(L) >= len(Z) and L[len(L)-len(Z):] == Z
+def keytag(rd):
+    ac = 0
+    for i, b in enumerate(rd): ac += b if i & 1 else b << 8
+    ac += (ac >> 16) & 0xffff
+    return ac & 0xffff
+def bitmap(types):
+    out = b''; wins = {}
+    for t in sorted(set(types)): wins.setdefault(t >> 8, []).append(t & 0xff)
+    for w, tl in sorted(wins.items()):
+        bm = bytearray(max(tl) // 8 + 1)
+        for t in tl: bm[t // 8] |= 0x80 >> (t % 8)
+        out += bytes([w, len(bm)]) + bytes(bm)
+    return out
+B32 = '0123456789ABCDEFGHIJKLMNOPQRSTUV'
+def b32hex(b):
+    bits = ''.join(f'{x:08b}' for x in b); bits += '0' * (-len(bits) % 5)
+    return ''.join(B32[int(bits[i:i+5], 2)] for i in range(0, len(bits), 5))
+def nsec3hash(n, it):
+    h = hashlib.sha1(wire(n)).digest()
+    for _ in range(it): h = hashlib.sha1(h).digest()
+    return h
+
+class Zone:
+    def __init__(self, origin, signed=True, nsec3=None):
+        self.origin, self.signed, self.nsec3 = origin, signed, nsec3
+        self.rrs = []  # (owner, type, ttl, rdata wire, rdata text)
+        self.delegations = set()
+        if signed:
+            seed = hashlib.sha256(b'mindns fixture ' + origin.encode()).digest()
+            self.key = Ed25519PrivateKey.from_private_bytes(seed)
+            pub = self.key.public_key().public_bytes(serialization.Encoding.Raw, serialization.PublicFormat.Raw)
+            rd = struct.pack('>HBB', 257, 3, 15) + pub
+            self.dnskey = rd
+            self.tag = keytag(rd)
+            self.add(origin, DNSKEY, 3600, rd, f'257 3 15 {base64.b64encode(pub).decode()}')
+        self.add(origin, SOA, 3600, wire(under('ns', origin)) + wire(under('admin', origin)) + struct.pack('>IIIII', 1, 3600, 600, 86400, 60),
+                 f'{fqdn(under("ns", origin))} {fqdn(under("admin", origin))} 1 3600 600 86400 60')
+        self.add(origin, NS, 3600, wire(under('ns', origin)), fqdn(under('ns', origin)))
+    def add(self, owner, t, ttl, rd, text): self.rrs.append((owner, t, ttl, rd, text))
+    def a(self, owner, addr):
+        self.add(owner, A, 300, bytes(int(x) for x in addr.split('.')), addr)
+    def dname(self, owner, target):
+        rd = wire(target)
+        self.add(owner, DNAME, 300, rd, f'\\# {len(rd)} {rd.hex().upper()}')
+    def delegate(self, child):
+        self.delegations.add(child.origin)
+        self.add(child.origin, NS, 3600, wire(under('ns', child.origin)), fqdn(under('ns', child.origin)))
+        if child.signed:
+            digest = hashlib.sha256(wire(child.origin) + child.dnskey).digest()
+            self.add(child.origin, DS, 3600, struct.pack('>HBB', child.tag, 15, 2) + digest,
+                     f'{child.tag} 15 2 {digest.hex().upper()}')
+    def ds_text(self):
+        digest = hashlib.sha256(wire(self.origin) + self.dnskey).digest()
+        return f'{self.tag} 15 2 {digest.hex().upper()}'
+    def rrsig(self, owner, t, ttl, rdatas, inception=INCEPTION, expiration=EXPIRATION):
+        labs = labels(owner)
+        count = len(labs) - (1 if labs and labs[0] == '*' else 0)
+        head = struct.pack('>HBBIIIH', t, 15, count, ttl, expiration, inception, self.tag) + wire(self.origin)
+        data = head + b''.join(wire(owner) + struct.pack('>HHIH', t, 1, ttl, len(rd)) + rd for rd in sorted(set(rdatas)))
+        sig = self.key.sign(data)
+        text = f'{TNAME[t]} 15 {count} {ttl} {tstr(expiration)} {tstr(inception)} {self.tag} {fqdn(self.origin)} {base64.b64encode(sig).decode()}'
+        return (owner, RRSIG, ttl, head + sig, text)
+    def authoritative(self, owner, t):
+        # Delegation NS records and glue aren't signed by the parent
+        return not (owner in self.delegations and t == NS)
+    def denial(self):
+        names = sorted({o for o, *_ in self.rrs}, key=canon_key)
+        types = lambda n: [t for o, t, *_ in self.rrs if o == n]
+        out = []
+        if self.nsec3 is None:
+            for i, n in enumerate(names):
+                nxt = names[(i + 1) % len(names)]
+                ts_ = types(n) + [RRSIG, NSEC]
+                out.append((n, NSEC, 60, wire(nxt) + bitmap(ts_), f'{fqdn(nxt)} ' + ' '.join(TNAME[t] for t in sorted(set(ts_)))))
+        else:
+            it = self.nsec3
+            # Empty non-terminals get NSEC3 records too
+            full = set(names)
+            for n in names:
+                l = labels(n)
+                for k in range(len(labels(self.origin)), len(l)):
+                    full.add('.'.join(l[len(l) - k:]))
+            hashes = sorted((nsec3hash(n, it), n) for n in full)
+            for i, (h, n) in enumerate(hashes):
+                nxt = hashes[(i + 1) % len(hashes)][0]
+                ts_ = types(n)
+                if ts_ and not (n in self.delegations and DS not in ts_): ts_ = ts_ + [RRSIG]
+                owner = b32hex(h) + '.' + self.origin
+                rd = struct.pack('>BBHB', 1, 0, it, 0) + bytes([20]) + nxt + bitmap(ts_)
+                text = f'1 0 {it} - {b32hex(nxt)}' + ''.join(' ' + TNAME[t] for t in sorted(set(ts_)))
+                out.append((owner, NSEC3, 60, rd, text))
+        return out
+    def write(self, path, comment):
+        records = list(self.rrs)
+        if self.signed: records += self.denial()
+        lines = [f'; {comment}']
+        groups = {}
+        for r in records: groups.setdefault((r[0], r[1]), []).append(r)
+        for (owner, t) in sorted(groups, key=lambda k: (canon_key(k[0]), k[1])):
+            rs = groups[(owner, t)]
+            for r in rs: lines.append(f'{fqdn(r[0])} {r[2]} IN {TNAME[r[1]]} {r[4]}')
+            if self.signed and self.authoritative(owner, t):
+                window = (OLD_INCEPTION, OLD_EXPIRATION) if owner.startswith('old.') and t == A else (INCEPTION, EXPIRATION)
+                s = self.rrsig(owner, t, rs[0][2], [r[3] for r in rs], *window)
+                lines.append(f'{fqdn(s[0])} {s[2]} IN RRSIG {s[4]}')
+        open(path, 'w').write('\n'.join(lines) + '\n')
+
+root = Zone('')
+example = Zone('example')
+leaf = Zone('leaf.example')
+insecure = Zone('insecure.example', signed=False)
+nsec3 = Zone('nsec3', nsec3=0)
+slow = Zone('slow', nsec3=500)
+
+example.a('www.example', '192.0.2.1')
+example.a('old.example', '192.0.2.2')
+example.a('*.wild.example', '192.0.2.3')
+example.dname('dname.example', 'leaf.example')
+leaf.a('www.leaf.example', '192.0.2.10')
+insecure.a('www.insecure.example', '192.0.2.20')
+nsec3.a('www.nsec3', '192.0.2.30')
+slow.a('www.slow', '192.0.2.40')
+
+for child in (example, nsec3, slow): root.delegate(child)
+for child in (leaf, insecure): example.delegate(child)
+
+SIGNED = 'Signed with a fixed Ed25519 key, valid from 2026-01-01 until 2036-01-01'
+root.write(f'{OUT}/root.zone', SIGNED)
+example.write(f'{OUT}/example.zone', SIGNED + ', except old.example whose signature ran out in 2025')
+leaf.write(f'{OUT}/leaf.example.zone', SIGNED)
+insecure.write(f'{OUT}/insecure.example.zone', 'Not signed, and delegated from example without a DS record')
+nsec3.write(f'{OUT}/nsec3.zone', SIGNED + ', denials use NSEC3 without salt or extra iterations')
+slow.write(f'{OUT}/slow.zone', SIGNED + ', denials use NSEC3 with 500 iterations')
+print(root.ds_text())
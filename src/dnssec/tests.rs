@@ -0,0 +1,362 @@
+use async_trait::async_trait;
+
+use super::{denial::MAX_NSEC3_ITERATIONS, names, Lookup, Security, Validator};
+use crate::protocol::{
+    dns_packet::DnsPacket, dns_question::DnsQuestion, dns_record::DnsRecord, query_type::QueryType,
+    result_code::ResultCode,
+};
+
+/// The DS record of the fixture root zone
+const ROOT_ANCHOR: &str =
+    "46709 15 2 70B57C8A9A663F6AA47AE5A1F1D047234B518BE682C89D581216538064F422EA";
+
+/// 2027-01-01, while the fixture signatures are valid
+const NOW: u32 = 1_798_761_600;
+
+/// The fixture zones, signed once with fixed keys by an outside signer
+const ZONES: [&str; 6] = [
+    include_str!("testdata/root.zone"),
+    include_str!("testdata/example.zone"),
+    include_str!("testdata/leaf.example.zone"),
+    include_str!("testdata/insecure.example.zone"),
+    include_str!("testdata/nsec3.zone"),
+    include_str!("testdata/slow.zone"),
+];
+
+struct Zone {
+    origin: String,
+    records: Vec<DnsRecord>,
+}
+
+impl Zone {
+    fn parse(text: &str) -> Zone {
+        let records: Vec<DnsRecord> = text
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with(';'))
+            .map(|line| {
+                let fields: Vec<&str> = line.splitn(5, ' ').collect();
+                let qtype = fields[3].parse::<QueryType>().unwrap();
+                let ttl = fields[1].parse().unwrap();
+                DnsRecord::from_text(&names::lowercase(fields[0]), qtype, fields[4], ttl).unwrap()
+            })
+            .collect();
+        let origin = records
+            .iter()
+            .find(|rec| rec.query_type() == QueryType::SOA)
+            .map(|rec| rec.domain().to_string())
+            .unwrap();
+        Zone { origin, records }
+    }
+
+    /// The RRset of `qtype` at `name` with its signatures
+    fn rrset(&self, name: &str, qtype: QueryType) -> Vec<DnsRecord> {
+        self.records
+            .iter()
+            .filter(|rec| names::eq(rec.domain(), name))
+            .filter(|rec| match rec {
+                DnsRecord::RRSIG { data, .. } => data.type_covered == qtype.to_num(),
+                rec => rec.query_type() == qtype,
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        self.records
+            .iter()
+            .any(|rec| names::is_subdomain(rec.domain(), name))
+    }
+
+    /// The SOA and every NSEC or NSEC3 record, so any denial can be proven from them
+    fn denial(&self) -> Vec<DnsRecord> {
+        let mut records = self.rrset(&self.origin, QueryType::SOA);
+        for qtype in [QueryType::NSEC, QueryType::NSEC3] {
+            for rec in self.records.iter().filter(|rec| rec.query_type() == qtype) {
+                records.extend(self.rrset(rec.domain(), qtype));
+            }
+        }
+        records
+    }
+}
+
+/// Answers like the authoritative servers of the fixture zones would
+struct Fixtures {
+    zones: Vec<Zone>,
+}
+
+impl Fixtures {
+    fn load() -> Fixtures {
+        Fixtures {
+            zones: ZONES.iter().map(|text| Zone::parse(text)).collect(),
+        }
+    }
+
+    /// The answer for `qname`, from the closest zone holding it, the parent for a DS record
+    fn response(&self, qname: &str, qtype: QueryType) -> DnsPacket {
+        let zone = self
+            .zones
+            .iter()
+            .filter(|zone| names::is_subdomain(qname, &zone.origin))
+            .filter(|zone| qtype != QueryType::DS || !names::eq(qname, &zone.origin))
+            .max_by_key(|zone| names::label_count(&zone.origin))
+            .unwrap();
+
+        let mut packet = DnsPacket::new();
+        packet.header.response = true;
+        packet
+            .questions
+            .push(DnsQuestion::new(qname.to_string(), qtype));
+        packet.answers = zone.rrset(qname, qtype);
+        if !packet.answers.is_empty() {
+            return packet;
+        }
+
+        let wildcard = names::parent(qname).map(|parent| names::wildcard(&parent));
+        match wildcard {
+            Some(wildcard) if !zone.exists(qname) && zone.exists(&wildcard) => {
+                packet.answers = zone.rrset(&wildcard, qtype);
+                for rec in &mut packet.answers {
+                    rec.set_domain(qname);
+                }
+            }
+            _ if !zone.exists(qname) => packet.header.rescode = ResultCode::NXDOMAIN,
+            _ => {}
+        }
+        packet.authorities = zone.denial();
+        packet
+    }
+}
+
+#[async_trait]
+impl Lookup for Fixtures {
+    async fn lookup(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+    ) -> std::result::Result<DnsPacket, String> {
+        Ok(self.response(qname, qtype))
+    }
+}
+
+/// How the answer to `qname` from the fixtures validates after `tamper` is done with it
+async fn validate(
+    qname: &str,
+    qtype: QueryType,
+    tamper: impl FnOnce(&mut DnsPacket),
+    now: u32,
+) -> Security {
+    let fixtures = Fixtures::load();
+    let mut response = fixtures.response(qname, qtype);
+    tamper(&mut response);
+    let validator = Validator::new(&[ROOT_ANCHOR.to_string()], fixtures).unwrap();
+    let question = DnsQuestion::new(qname.to_string(), qtype);
+    validator.validate_at(&question, &response, now).await
+}
+
+fn is_bogus(security: &Security) -> bool {
+    matches!(security, Security::Bogus(_))
+}
+
+#[tokio::test]
+async fn answers_signed_down_from_the_anchor_are_secure() {
+    let response = Fixtures::load().response("www.leaf.example", QueryType::A);
+    assert_eq!(response.answers.len(), 2);
+
+    assert_eq!(
+        validate("www.leaf.example", QueryType::A, |_| {}, NOW).await,
+        Security::Secure
+    );
+    assert_eq!(
+        validate("www.example", QueryType::A, |_| {}, NOW).await,
+        Security::Secure
+    );
+}
+
+#[tokio::test]
+async fn answers_below_a_delegation_without_ds_are_insecure() {
+    assert_eq!(
+        validate("www.insecure.example", QueryType::A, |_| {}, NOW).await,
+        Security::Insecure
+    );
+}
+
+#[tokio::test]
+async fn broken_or_missing_signatures_are_bogus() {
+    let flip = |response: &mut DnsPacket| {
+        for rec in &mut response.answers {
+            if let DnsRecord::RRSIG { data, .. } = rec {
+                data.signature[0] ^= 1;
+            }
+        }
+    };
+    assert!(is_bogus(
+        &validate("www.example", QueryType::A, flip, NOW).await
+    ));
+
+    let strip = |response: &mut DnsPacket| {
+        response
+            .answers
+            .retain(|rec| rec.query_type() != QueryType::RRSIG)
+    };
+    assert!(is_bogus(
+        &validate("www.leaf.example", QueryType::A, strip, NOW).await
+    ));
+
+    let swap = |response: &mut DnsPacket| {
+        if let DnsRecord::A { addr, .. } = &mut response.answers[0] {
+            *addr = "192.0.2.99".parse().unwrap();
+        }
+    };
+    assert!(is_bogus(
+        &validate("www.example", QueryType::A, swap, NOW).await
+    ));
+}
+
+#[tokio::test]
+async fn signatures_only_hold_between_inception_and_expiration() {
+    match validate("old.example", QueryType::A, |_| {}, NOW).await {
+        Security::Bogus(reason) => assert!(reason.contains("expired"), "{}", reason),
+        security => panic!("{:?}", security),
+    }
+
+    // 2025-06-01, before the fixture signatures were made
+    assert!(is_bogus(
+        &validate("www.example", QueryType::A, |_| {}, 1_748_736_000).await
+    ));
+    // 2036-06-01, after they ran out
+    assert!(is_bogus(
+        &validate("www.example", QueryType::A, |_| {}, 2_096_409_600).await
+    ));
+}
+
+#[tokio::test]
+async fn nsec_proves_names_and_types_away() {
+    let response = Fixtures::load().response("nx.example", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(
+        validate("nx.example", QueryType::A, |_| {}, NOW).await,
+        Security::Secure
+    );
+    assert_eq!(
+        validate("www.example", QueryType::AAAA, |_| {}, NOW).await,
+        Security::Secure
+    );
+
+    // Without the NSEC records there is nothing to show for it
+    let strip = |response: &mut DnsPacket| {
+        response.authorities.retain(|rec| match rec {
+            DnsRecord::NSEC { .. } => false,
+            DnsRecord::RRSIG { data, .. } => data.type_covered != QueryType::NSEC.to_num(),
+            _ => true,
+        })
+    };
+    assert!(is_bogus(
+        &validate("nx.example", QueryType::A, strip, NOW).await
+    ));
+
+    // A name that does exist can't be denied with the chain of another
+    let response = Fixtures::load().response("nx.example", QueryType::A);
+    let validator = Validator::new(&[ROOT_ANCHOR.to_string()], Fixtures::load()).unwrap();
+    let question = DnsQuestion::new("www.example".to_string(), QueryType::A);
+    assert!(is_bogus(
+        &validator.validate_at(&question, &response, NOW).await
+    ));
+}
+
+#[tokio::test]
+async fn nsec3_proves_names_and_types_away() {
+    let response = Fixtures::load().response("nx.nsec3", QueryType::A);
+    assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+    assert_eq!(
+        validate("nx.nsec3", QueryType::A, |_| {}, NOW).await,
+        Security::Secure
+    );
+    // n1.nsec3 hashes to 13LH1AGV..., before the first owner of the chain, so only the
+    // last record covers it by wrapping around
+    assert_eq!(
+        validate("n1.nsec3", QueryType::A, |_| {}, NOW).await,
+        Security::Secure
+    );
+    assert_eq!(
+        validate("www.nsec3", QueryType::AAAA, |_| {}, NOW).await,
+        Security::Secure
+    );
+
+    // NODATA for a type the bitmap lists is a lie
+    let hide = |response: &mut DnsPacket| response.answers.clear();
+    assert!(is_bogus(
+        &validate("www.nsec3", QueryType::A, hide, NOW).await
+    ));
+}
+
+#[tokio::test]
+async fn wildcard_expansions_need_proof_of_no_closer_match() {
+    let response = Fixtures::load().response("host.wild.example", QueryType::A);
+    assert!(names::eq(response.answers[0].domain(), "host.wild.example"));
+    assert_eq!(
+        validate("host.wild.example", QueryType::A, |_| {}, NOW).await,
+        Security::Secure
+    );
+
+    let strip = |response: &mut DnsPacket| response.authorities.clear();
+    assert!(is_bogus(
+        &validate("host.wild.example", QueryType::A, strip, NOW).await
+    ));
+}
+
+#[tokio::test]
+async fn nsec3_with_too_many_iterations_is_insecure() {
+    let response = Fixtures::load().response("nx.slow", QueryType::A);
+    let iterations = response.authorities.iter().find_map(|rec| match rec {
+        DnsRecord::NSEC3 { data, .. } => Some(data.iterations),
+        _ => None,
+    });
+    assert!(iterations.unwrap() > MAX_NSEC3_ITERATIONS);
+
+    assert_eq!(
+        validate("nx.slow", QueryType::A, |_| {}, NOW).await,
+        Security::Insecure
+    );
+    // Answers from the zone itself are still checked as usual
+    assert_eq!(
+        validate("www.slow", QueryType::A, |_| {}, NOW).await,
+        Security::Secure
+    );
+}
+
+/// The answer for `qname` below dname.example as a server would synthesize it, with the
+/// unsigned CNAME pointing at `target`
+fn dname_answer(qname: &str, target: &str) -> DnsPacket {
+    let fixtures = Fixtures::load();
+    let mut response = fixtures.response("dname.example", QueryType::UNKNOWN(39));
+    response.questions[0] = DnsQuestion::new(qname.to_string(), QueryType::A);
+    response.answers.push(DnsRecord::CNAME {
+        domain: qname.to_string(),
+        host: target.to_string(),
+        ttl: 300,
+    });
+    response
+        .answers
+        .extend(fixtures.response(target, QueryType::A).answers);
+    response
+}
+
+#[tokio::test]
+async fn cnames_from_a_dname_have_to_follow_it() {
+    let validator = Validator::new(&[ROOT_ANCHOR.to_string()], Fixtures::load()).unwrap();
+    let question = DnsQuestion::new("www.dname.example".to_string(), QueryType::A);
+
+    let response = dname_answer("www.dname.example", "www.leaf.example");
+    assert_eq!(response.answers.len(), 5);
+    assert_eq!(
+        validator.validate_at(&question, &response, NOW).await,
+        Security::Secure
+    );
+
+    // A signed DNAME doesn't vouch for a CNAME that points anywhere else
+    let response = dname_answer("www.dname.example", "www.example");
+    assert_eq!(response.answers.len(), 5);
+    assert!(is_bogus(
+        &validator.validate_at(&question, &response, NOW).await
+    ));
+}
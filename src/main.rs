@@ -1,32 +1,44 @@
-use std::net::Ipv4Addr;
-use std::pin::pin;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::{pin, Pin};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use block::Blocker;
+use cache::Cache;
+use dns::forward::Forwarder;
+use dnssec::{signer::Signer, UpstreamLookup, Validator};
+use k8s_openapi::api::core::v1::{Secret, Service};
 use k8s_openapi::api::networking::v1::Ingress;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
 use kube::api::ListParams;
 use kube::runtime::{watcher, WatchStreamExt};
 use kube::{Api, Client};
 use protocol::Result;
-use rewrites::{RewriteRule, Rewrites};
+use rewrites::{RewriteRule, RewriteValue, Rewrites};
 use tokio::join;
-use tracing::info;
+use tracing::{error, info};
 
 use crate::config::Config;
+use crate::networking::certs::CertResolver;
+use crate::networking::doh_serv::DohServer;
 use crate::networking::handler::handle_request;
+use crate::networking::tcp_serv::TcpServer;
+use crate::networking::tls_serv::TlsServer;
 use crate::networking::udp_serv::UdpServer;
+use crate::networking::Protocol;
 use crate::protocol::byte_packet_buffer::BytePacketBuffer;
 
 mod block;
+mod cache;
 mod config;
 mod dns;
+mod dnssec;
 mod networking;
 mod protocol;
 mod rewrites;
 
-pub type Cache = dashmap::DashMap<String, (std::time::SystemTime, protocol::dns_packet::DnsPacket)>;
-
 #[tokio::main]
 async fn main() -> Result<()> {
     // Load configuration file.
@@ -36,44 +48,344 @@ async fn main() -> Result<()> {
 
     // Start DNS server.
     let raw_addr = format!("{}:{}", config.server.bind, config.server.port);
-    info!("Starting DNS server at udp://{}", raw_addr);
+    info!("Starting DNS server at udp://{0} and tcp://{0}", raw_addr);
 
-    let cache = Arc::new(Cache::new());
-    let blocker = Blocker::new(config.block.lists.clone());
-    blocker.process_lists().await;
+    let cache = Cache::new(config.cache.clone());
+    // One client for every request over HTTP, so they all share its connection pool
+    let http = reqwest::Client::builder()
+        .use_rustls_tls()
+        .build()
+        .map_err(|err| format!("Failed to create HTTP client: {}", err))?;
+    let blocker = Blocker::new(
+        config.block.lists.clone(),
+        config.block.cache_dir.clone(),
+        http.clone(),
+    );
+    // Serving starts with whatever the files and the cache have, the lists are downloaded
+    // alongside it so a slow or missing network never holds it up
+    blocker.load_cache().await;
+    let block_refresh = {
+        let blocker = blocker.clone();
+        let enabled = config.block.enabled;
+        let interval = config.block.refresh_interval;
+        async move {
+            blocker.process_lists().await;
+            if enabled && interval > 0 {
+                blocker.refresh(Duration::from_secs(interval)).await;
+            }
+        }
+    };
     let rewrites = Rewrites::new();
     for rule in config.rewrites.iter() {
         rewrites.add_rewrite(rule).await;
     }
 
+    let upstreams = Forwarder::new(&config.mirror, &http)?;
+    let validator = Validator::new(
+        &config.dnssec.trust_anchors,
+        UpstreamLookup {
+            cache: cache.clone(),
+            upstreams: upstreams.clone(),
+        },
+    )?;
+    let signer = Signer::new(
+        &config
+            .dnssec
+            .zones
+            .iter()
+            .map(|zone| (zone.name.clone(), zone.denial))
+            .collect::<Vec<_>>(),
+    );
+    let signing = futures::future::join_all(config.dnssec.zones.iter().map(|zone| {
+        let signer = signer.clone();
+        async move {
+            if let Some(secret) = &zone.secret {
+                k8s_dnssec_secret(signer, &zone.name, secret).await;
+            } else if let Some(key) = &zone.key {
+                signer.watch_file(zone.name.clone(), key.clone()).await;
+            }
+        }
+    }));
+
     let k8s = k8s(rewrites.clone());
 
-    let server = UdpServer::new(raw_addr, move |peer, mut reader, config: Config| {
+    let server = {
         let cache = cache.clone();
         let blocker = blocker.clone();
         let rewrites = rewrites.clone();
-        async move {
-            let mut buffer = BytePacketBuffer::new();
-            while let Some(Ok(data)) = reader.recv().await {
-                buffer.pos = 0;
-                buffer.buf[..data.len()].copy_from_slice(&data);
+        let upstreams = upstreams.clone();
+        let validator = validator.clone();
+        let signer = signer.clone();
+        UdpServer::new(&raw_addr, move |peer, mut reader, config: Config| {
+            let cache = cache.clone();
+            let blocker = blocker.clone();
+            let rewrites = rewrites.clone();
+            let upstreams = upstreams.clone();
+            let validator = validator.clone();
+            let signer = signer.clone();
+            async move {
+                while let Some(Ok(data)) = reader.recv().await {
+                    let mut buffer = BytePacketBuffer::from_bytes(&data);
+
+                    let response = handle_request(
+                        &config,
+                        Protocol::Udp,
+                        &mut buffer,
+                        &cache,
+                        &blocker,
+                        &rewrites,
+                        &upstreams,
+                        &validator,
+                        &signer,
+                    )
+                    .await?;
+                    peer.send(&response).await?;
+                }
 
-                handle_request(&config, &peer, &mut buffer, &cache, &blocker, &rewrites).await?;
+                Ok(())
             }
+        })?
+        .set_peer_timeout_sec(20)
+    };
+
+    let tcp_server = TcpServer::new(
+        &raw_addr,
+        stream_handler(
+            Protocol::Tcp,
+            cache.clone(),
+            blocker.clone(),
+            rewrites.clone(),
+            upstreams.clone(),
+            validator.clone(),
+            signer.clone(),
+        ),
+    )
+    .await?
+    .set_idle_timeout_sec(config.server.tcp_idle_timeout);
 
-            Ok(())
+    let tls_server = if config.server.tls.enabled {
+        let tls_addr = format!("{}:{}", config.server.bind, config.server.tls.port);
+        info!("Starting DNS over TLS server at tls://{}", tls_addr);
+        let certs = Arc::new(CertResolver::new());
+        let server = TlsServer::new(
+            &tls_addr,
+            certs.clone(),
+            stream_handler(
+                Protocol::Tls,
+                cache.clone(),
+                blocker.clone(),
+                rewrites.clone(),
+                upstreams.clone(),
+                validator.clone(),
+                signer.clone(),
+            ),
+        )
+        .await?
+        .set_idle_timeout_sec(config.server.tcp_idle_timeout);
+        Some((server, certs))
+    } else {
+        None
+    };
+    let tls = async {
+        let Some((server, certs)) = &tls_server else {
+            return;
+        };
+        let tls = &config.server.tls;
+        let certs = async {
+            if let Some(secret) = &tls.secret {
+                k8s_tls_secret(certs.clone(), secret).await;
+            } else if let (Some(cert), Some(key)) = (&tls.cert, &tls.key) {
+                certs.clone().watch_files(cert.clone(), key.clone()).await;
+            }
+        };
+        let _ = join!(server.start(config.clone()), certs);
+    };
+
+    let doh_server = if config.server.https.enabled {
+        let https = &config.server.https;
+        let doh_addr = format!("{}:{}", config.server.bind, https.port);
+        info!(
+            "Starting DNS over HTTPS server at http://{}{}",
+            doh_addr, https.path
+        );
+        Some(
+            DohServer::new(
+                &doh_addr,
+                &https.path,
+                stream_handler(
+                    Protocol::Https,
+                    cache,
+                    blocker,
+                    rewrites,
+                    upstreams,
+                    validator,
+                    signer,
+                ),
+            )
+            .await?,
+        )
+    } else {
+        None
+    };
+    let doh = async {
+        if let Some(server) = &doh_server {
+            let _ = server.start(config.clone()).await;
         }
-    })?
-    .set_peer_timeout_sec(20);
+    };
 
-    let _ = join!(server.start(config), k8s);
+    let _ = join!(
+        server.start(config.clone()),
+        tcp_server.start(config.clone()),
+        tls,
+        doh,
+        signing,
+        block_refresh,
+        k8s
+    );
 
     Ok(())
 }
 
+type StreamHandler = Box<
+    dyn Fn(SocketAddr, Vec<u8>, Config) -> Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send>>
+        + Send
+        + Sync,
+>;
+
+/// Answer requests arriving over a stream transport
+fn stream_handler(
+    protocol: Protocol,
+    cache: Cache,
+    blocker: Blocker,
+    rewrites: Rewrites,
+    upstreams: Forwarder,
+    validator: Validator,
+    signer: Signer,
+) -> StreamHandler {
+    Box::new(move |_addr, data, config: Config| {
+        let cache = cache.clone();
+        let blocker = blocker.clone();
+        let rewrites = rewrites.clone();
+        let upstreams = upstreams.clone();
+        let validator = validator.clone();
+        let signer = signer.clone();
+        Box::pin(async move {
+            let mut buffer = BytePacketBuffer::from_bytes(&data);
+            handle_request(
+                &config,
+                protocol,
+                &mut buffer,
+                &cache,
+                &blocker,
+                &rewrites,
+                &upstreams,
+                &validator,
+                &signer,
+            )
+            .await
+        })
+    })
+}
+
+/// Keep the DNS over TLS certificate in sync with a Kubernetes TLS secret,
+/// given as `namespace/name` or just `name` in the current namespace
+async fn k8s_tls_secret(certs: Arc<CertResolver>, secret: &str) {
+    use futures::TryStreamExt;
+    let client = Client::try_default().await.unwrap();
+    let (secrets, name): (Api<Secret>, _) = match secret.split_once('/') {
+        Some((namespace, name)) => (Api::namespaced(client, namespace), name),
+        None => (Api::default_namespaced(client), secret),
+    };
+
+    let obs = watcher(
+        secrets,
+        kube::runtime::watcher::Config::default().fields(&format!("metadata.name={}", name)),
+    )
+    .default_backoff()
+    .applied_objects();
+    let mut obs = pin!(obs);
+
+    while let Some(secret) = obs.try_next().await.unwrap() {
+        let data = secret.data.unwrap_or_default();
+        let (Some(cert), Some(key)) = (data.get("tls.crt"), data.get("tls.key")) else {
+            error!("Secret {} is not a TLS secret", name);
+            continue;
+        };
+        match certs.set_pem(&cert.0, &key.0) {
+            Ok(()) => info!("Loaded TLS certificate from secret {}", name),
+            Err(err) => error!(
+                "Failed to load TLS certificate from secret {}: {}",
+                name, err
+            ),
+        }
+    }
+}
+
+/// The entry of a DNSSEC key secret that holds the PEM encoded private key
+const DNSSEC_KEY_ENTRY: &str = "dnssec.key";
+
+/// Keep the key of a signed zone in sync with a Kubernetes secret, given as
+/// `namespace/name` or just `name` in the current namespace
+async fn k8s_dnssec_secret(signer: Signer, zone: &str, secret: &str) {
+    use futures::TryStreamExt;
+    let client = Client::try_default().await.unwrap();
+    let (secrets, name): (Api<Secret>, _) = match secret.split_once('/') {
+        Some((namespace, name)) => (Api::namespaced(client, namespace), name),
+        None => (Api::default_namespaced(client), secret),
+    };
+
+    let obs = watcher(
+        secrets,
+        kube::runtime::watcher::Config::default().fields(&format!("metadata.name={}", name)),
+    )
+    .default_backoff()
+    .applied_objects();
+    let mut obs = pin!(obs);
+
+    while let Some(secret) = obs.try_next().await.unwrap() {
+        let data = secret.data.unwrap_or_default();
+        let Some(key) = data.get(DNSSEC_KEY_ENTRY) else {
+            error!("Secret {} has no {} entry", name, DNSSEC_KEY_ENTRY);
+            continue;
+        };
+        match signer.set_pem(zone, &key.0) {
+            Ok(()) => info!("Loaded DNSSEC key for {} from secret {}", zone, name),
+            Err(err) => error!(
+                "Failed to load DNSSEC key for {} from secret {}: {}",
+                zone, name, err
+            ),
+        }
+    }
+}
+
+/// Annotation holding extra records for an Ingress or Service, as a YAML list of rewrites,
+/// for example `[{host: _http._tcp.web.lan, type: SRV, value: "10 5 80 web.lan."}]`
+const RECORDS_ANNOTATION: &str = "mindns-k8s/records";
+
 async fn k8s(rewrites: Rewrites) {
+    fn annotated_rewrites(meta: &ObjectMeta) -> Vec<RewriteRule> {
+        let Some(records) = meta
+            .annotations
+            .as_ref()
+            .and_then(|annotations| annotations.get(RECORDS_ANNOTATION))
+        else {
+            return vec![];
+        };
+        match serde_yaml::from_str(records) {
+            Ok(rules) => rules,
+            Err(err) => {
+                error!(
+                    "Invalid {} annotation on {}: {}",
+                    RECORDS_ANNOTATION,
+                    meta.name.as_deref().unwrap_or_default(),
+                    err
+                );
+                vec![]
+            }
+        }
+    }
     async fn ingress_rewrites(ingress: Ingress) -> Vec<RewriteRule> {
-        let mut ret = vec![];
+        let mut ret = annotated_rewrites(&ingress.metadata);
         let Some(spec) = ingress.spec else {
             return ret;
         };
@@ -94,41 +406,71 @@ async fn k8s(rewrites: Rewrites) {
         else {
             return ret;
         };
+        let Ok(ip) = IpAddr::from_str(&ingress_ip) else {
+            error!(
+                "Ingress {} has {} as its address, which isn't an IP",
+                ingress.metadata.name.as_deref().unwrap_or_default(),
+                ingress_ip
+            );
+            return ret;
+        };
+        let hint = match ip {
+            IpAddr::V4(ip) => format!("ipv4hint={}", ip),
+            IpAddr::V6(ip) => format!("ipv6hint={}", ip),
+        };
         for rule in rules {
             if let Some(host) = rule.host {
+                ret.push(RewriteRule {
+                    host: host.clone(),
+                    value: RewriteValue::Ip { ip },
+                });
+                // Lets browsers go straight to HTTP/2 or HTTP/3 without an extra lookup
                 ret.push(RewriteRule {
                     host,
-                    ip: std::net::IpAddr::V4(Ipv4Addr::from_str(&ingress_ip).unwrap()),
+                    value: RewriteValue::Record {
+                        qtype: "HTTPS".to_string(),
+                        value: format!("1 . alpn=h2,h3 {}", hint),
+                    },
                 });
             }
         }
         ret
     }
+    async fn all_rewrites(client: &Client) -> Vec<RewriteRule> {
+        let ingress: Api<Ingress> = Api::all(client.clone());
+        let existing = ingress.list(&ListParams::default()).await.unwrap();
+        let mut rules = Vec::new();
+        for i in existing {
+            rules.append(&mut ingress_rewrites(i).await);
+        }
+        let services: Api<Service> = Api::all(client.clone());
+        for s in services.list(&ListParams::default()).await.unwrap() {
+            rules.append(&mut annotated_rewrites(&s.metadata));
+        }
+        rules
+    }
     use futures::TryStreamExt;
     info!("Connecting to k8s API");
     let client = Client::try_default().await.unwrap();
-    let ingress: Api<Ingress> = Api::all(client.clone());
 
-    let existing = ingress.list(&ListParams::default()).await.unwrap();
-    let mut rules = Vec::new();
-    for i in existing {
-        rules.append(&mut ingress_rewrites(i).await);
-    }
-    rewrites.add_k8s_rewrites(rules).await;
+    rewrites.add_k8s_rewrites(all_rewrites(&client).await).await;
 
-    let obs = watcher(ingress, kube::runtime::watcher::Config::default())
-        .default_backoff()
-        .applied_objects();
+    let ingress: Api<Ingress> = Api::all(client.clone());
+    let services: Api<Service> = Api::all(client.clone());
+    let obs = futures::stream::select(
+        watcher(ingress, kube::runtime::watcher::Config::default())
+            .default_backoff()
+            .applied_objects()
+            .map_ok(|_| ()),
+        watcher(services, kube::runtime::watcher::Config::default())
+            .default_backoff()
+            .applied_objects()
+            .map_ok(|_| ()),
+    );
     let mut obs = pin!(obs);
 
     while obs.try_next().await.unwrap().is_some() {
         // I am too lazy to do this correctly, so just redo the whole thing.
-        let ingress: Api<Ingress> = Api::all(client.clone());
-        let existing = ingress.list(&ListParams::default()).await.unwrap();
-        let mut rules = Vec::new();
-        for i in existing {
-            rules.append(&mut ingress_rewrites(i).await);
-        }
-        rewrites.add_k8s_rewrites(rules).await;
+        rewrites.add_k8s_rewrites(all_rewrites(&client).await).await;
     }
 }
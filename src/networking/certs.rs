@@ -0,0 +1,170 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::ring::sign::any_supported_type,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tracing::{error, info};
+
+use crate::protocol::Result;
+
+/// How often certificate files are checked for changes
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Hands out whichever certificate was loaded last
+///
+/// Swapping the certificate only affects handshakes that start afterwards, connections that
+/// are already established keep going with the one they negotiated.
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the certificate with a PEM encoded chain and private key
+    pub fn set_pem(&self, cert: &[u8], key: &[u8]) -> Result<()> {
+        let chain =
+            rustls_pemfile::certs(&mut &cert[..]).collect::<std::result::Result<Vec<_>, _>>()?;
+        if chain.is_empty() {
+            return Err("No certificates found".into());
+        }
+        let key = rustls_pemfile::private_key(&mut &key[..])?.ok_or("No private key found")?;
+        let key = any_supported_type(&key)?;
+        *self.current.write().unwrap() = Some(Arc::new(CertifiedKey::new(chain, key)));
+        Ok(())
+    }
+
+    /// Load the certificate from files, and keep reloading it whenever they change
+    pub async fn watch_files(self: Arc<Self>, cert: PathBuf, key: PathBuf) {
+        self.watch_files_every(cert, key, FILE_CHECK_INTERVAL).await
+    }
+
+    async fn watch_files_every(self: Arc<Self>, cert: PathBuf, key: PathBuf, interval: Duration) {
+        let mut loaded: Option<(SystemTime, SystemTime)> = None;
+        loop {
+            let modified = (
+                tokio::fs::metadata(&cert).await.and_then(|m| m.modified()),
+                tokio::fs::metadata(&key).await.and_then(|m| m.modified()),
+            );
+            if let (Ok(cert_modified), Ok(key_modified)) = modified {
+                if loaded != Some((cert_modified, key_modified)) {
+                    match self.load_files(&cert, &key).await {
+                        Ok(()) => {
+                            info!("Loaded TLS certificate from {}", cert.display());
+                            loaded = Some((cert_modified, key_modified));
+                        }
+                        Err(err) => error!("Failed to load TLS certificate: {}", err),
+                    }
+                }
+            } else if loaded.is_none() {
+                error!("TLS certificate {} not found", cert.display());
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    async fn load_files(&self, cert: &PathBuf, key: &PathBuf) -> Result<()> {
+        let cert = tokio::fs::read(cert).await?;
+        let key = tokio::fs::read(key).await?;
+        self.set_pem(&cert, &key)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    const ONE: (&[u8], &[u8]) = (
+        include_bytes!("testdata/one.pem"),
+        include_bytes!("testdata/one.key"),
+    );
+    const TWO: (&[u8], &[u8]) = (
+        include_bytes!("testdata/two.pem"),
+        include_bytes!("testdata/two.key"),
+    );
+
+    fn der(pem: &[u8]) -> Vec<u8> {
+        rustls_pemfile::certs(&mut &pem[..])
+            .next()
+            .unwrap()
+            .unwrap()
+            .to_vec()
+    }
+
+    /// The certificate handed out right now
+    fn current(resolver: &CertResolver) -> Option<Vec<u8>> {
+        let current = resolver.current.read().unwrap();
+        current.as_ref().map(|key| key.cert[0].to_vec())
+    }
+
+    /// Wait until the resolver hands out `pem`
+    async fn wait_for(resolver: &CertResolver, pem: &[u8]) {
+        let start = Instant::now();
+        while current(resolver) != Some(der(pem)) {
+            assert!(start.elapsed() < Duration::from_secs(5), "never loaded");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn certificates_are_swapped_as_a_whole() {
+        let resolver = CertResolver::new();
+        assert_eq!(current(&resolver), None);
+        resolver.set_pem(ONE.0, ONE.1).unwrap();
+        assert_eq!(current(&resolver), Some(der(ONE.0)));
+        resolver.set_pem(TWO.0, TWO.1).unwrap();
+        assert_eq!(current(&resolver), Some(der(TWO.0)));
+
+        // Anything that doesn't load leaves the last one in place
+        assert!(resolver.set_pem(b"", TWO.1).is_err());
+        assert!(resolver.set_pem(ONE.0, b"").is_err());
+        assert!(resolver.set_pem(ONE.0, ONE.0).is_err());
+        assert_eq!(current(&resolver), Some(der(TWO.0)));
+    }
+
+    #[tokio::test]
+    async fn files_are_loaded_again_when_they_change() {
+        let dir = std::env::temp_dir().join(format!("mindns-certs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("tls.crt"), dir.join("tls.key"));
+        std::fs::write(&cert, ONE.0).unwrap();
+        std::fs::write(&key, ONE.1).unwrap();
+
+        let resolver = Arc::new(CertResolver::new());
+        let watch = tokio::spawn(resolver.clone().watch_files_every(
+            cert.clone(),
+            key.clone(),
+            Duration::from_millis(20),
+        ));
+        wait_for(&resolver, ONE.0).await;
+
+        std::fs::write(&cert, TWO.0).unwrap();
+        std::fs::write(&key, TWO.1).unwrap();
+        wait_for(&resolver, TWO.0).await;
+
+        // A broken copy is skipped, and the one before stays
+        std::fs::write(&cert, b"broken").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(current(&resolver), Some(der(TWO.0)));
+
+        watch.abort();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, ACCEPT, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tracing::{debug, trace};
+
+use crate::protocol::byte_packet_buffer::{BytePacketBuffer, STREAM_MAX_SIZE};
+use crate::protocol::dns_packet::DnsPacket;
+use crate::protocol::dns_question::DnsQuestion;
+use crate::protocol::dns_record::DnsRecord;
+use crate::protocol::query_type::QueryType;
+
+/// The media type of a wire format DNS message (RFC 8484)
+pub const DNS_MESSAGE: &str = "application/dns-message";
+
+/// The media type of the JSON flavour popularised by Google and Cloudflare
+pub const DNS_JSON: &str = "application/dns-json";
+
+type HttpResponse = Response<Full<Bytes>>;
+
+/// DNS over HTTPS Server listen (RFC 8484)
+///
+/// Speaks plain HTTP/1.1 and HTTP/2, TLS is expected to be terminated in front of it by an
+/// ingress controller. Wire format queries are accepted as a base64url `dns` parameter on
+/// GET or as the body of a POST, and `name`/`type` parameters get a JSON answer.
+pub struct DohServer<I, T> {
+    listener: TcpListener,
+    path: String,
+    input: Arc<I>,
+    _ph: PhantomData<T>,
+}
+
+impl<I, R, T> DohServer<I, T>
+where
+    I: Fn(SocketAddr, Vec<u8>, T) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send + 'static,
+    T: Sync + Send + Clone + 'static,
+{
+    /// new doh server answering queries on `path`
+    pub async fn new<A: ToSocketAddrs>(addr: A, path: &str, input: I) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(DohServer {
+            listener,
+            path: path.to_string(),
+            input: Arc::new(input),
+            _ph: Default::default(),
+        })
+    }
+
+    /// start server
+    pub async fn start(&self, inner: T) -> io::Result<()> {
+        let path: Arc<str> = self.path.as_str().into();
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    trace!("doh accept error:{err}");
+                    continue;
+                }
+            };
+            trace!("accepted doh connection:{addr}");
+            let input = self.input.clone();
+            let inner = inner.clone();
+            let path = path.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| {
+                    let input = input.clone();
+                    let inner = inner.clone();
+                    let path = path.clone();
+                    async move {
+                        let response = handle_http(req, addr, &path, input, inner).await;
+                        Ok::<_, Infallible>(response)
+                    }
+                });
+                if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("doh connection:{addr} error:{err}");
+                }
+            });
+        }
+    }
+}
+
+async fn handle_http<I, R, T>(
+    req: Request<Incoming>,
+    addr: SocketAddr,
+    path: &str,
+    input: Arc<I>,
+    inner: T,
+) -> HttpResponse
+where
+    I: Fn(SocketAddr, Vec<u8>, T) -> R,
+    R: Future<Output = Result<Vec<u8>, Box<dyn Error>>>,
+{
+    if req.uri().path() != path {
+        return status(StatusCode::NOT_FOUND);
+    }
+    let params: HashMap<String, String> = req
+        .uri()
+        .query()
+        .map(|query| {
+            url::form_urlencoded::parse(query.as_bytes())
+                .into_owned()
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let json = header_is(&req, ACCEPT, DNS_JSON) || params.contains_key("name");
+    let query = match *req.method() {
+        Method::GET if json => match json_query(&params) {
+            Some(query) => query,
+            None => return status(StatusCode::BAD_REQUEST),
+        },
+        Method::GET => {
+            let Some(dns) = params.get("dns") else {
+                return status(StatusCode::BAD_REQUEST);
+            };
+            match URL_SAFE_NO_PAD.decode(dns.trim_end_matches('=')) {
+                Ok(query) => query,
+                Err(_) => return status(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::POST => {
+            if !header_is(&req, CONTENT_TYPE, DNS_MESSAGE) {
+                return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            match Limited::new(req.into_body(), STREAM_MAX_SIZE)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+            }
+        }
+        _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let response = match (input)(addr, query, inner).await {
+        Ok(response) => response,
+        Err(err) => {
+            debug!("doh input error:{err}");
+            return status(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    let Ok(packet) = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&response)) else {
+        return status(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    // Let HTTP caches keep the answer for as long as its shortest TTL (RFC 8484 section 5.1)
+    let max_age = packet
+        .answers
+        .iter()
+        .chain(packet.authorities.iter())
+        .map(|rec| rec.ttl())
+        .min()
+        .unwrap_or(0);
+
+    let (content_type, body) = if json {
+        (DNS_JSON, json_answer(&packet).to_string().into_bytes())
+    } else {
+        (DNS_MESSAGE, response)
+    };
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", max_age)) {
+        headers.insert(CACHE_CONTROL, value);
+    }
+    response
+}
+
+fn status(code: StatusCode) -> HttpResponse {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = code;
+    response
+}
+
+fn header_is(req: &Request<Incoming>, name: hyper::header::HeaderName, value: &str) -> bool {
+    req.headers()
+        .get(name)
+        .and_then(|header| header.to_str().ok())
+        .is_some_and(|header| header.split(',').any(|v| v.trim().starts_with(value)))
+}
+
+/// Build a wire format query from the `name`, `type` and `cd` JSON API parameters
+fn json_query(params: &HashMap<String, String>) -> Option<Vec<u8>> {
+    let name = params.get("name")?.trim_end_matches('.');
+    let qtype = match params.get("type") {
+        Some(qtype) => qtype.parse::<QueryType>().ok()?,
+        None => QueryType::A,
+    };
+
+    let mut packet = DnsPacket::new();
+    packet.header.recursion_desired = true;
+    packet.header.checking_disabled = params.get("cd").is_some_and(|cd| cd == "1" || cd == "true");
+    packet
+        .questions
+        .push(DnsQuestion::new(name.to_string(), qtype));
+
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).ok()?;
+    Some(buffer.buf[0..buffer.pos].to_vec())
+}
+
+fn json_answer(packet: &DnsPacket) -> Value {
+    fn records(records: &[DnsRecord]) -> Vec<Value> {
+        records
+            .iter()
+            .map(|rec| {
+                json!({
+                    "name": format!("{}.", rec.domain()),
+                    "type": rec.query_type().to_num(),
+                    "TTL": rec.ttl(),
+                    "data": rec.rdata_text(),
+                })
+            })
+            .collect()
+    }
+
+    let questions: Vec<Value> = packet
+        .questions
+        .iter()
+        .map(|q| json!({ "name": format!("{}.", q.name), "type": q.qtype.to_num() }))
+        .collect();
+
+    let mut answer = json!({
+        "Status": packet.header.rescode as u8,
+        "TC": packet.header.truncated_message,
+        "RD": packet.header.recursion_desired,
+        "RA": packet.header.recursion_available,
+        "AD": packet.header.authed_data,
+        "CD": packet.header.checking_disabled,
+        "Question": questions,
+    });
+    if !packet.answers.is_empty() {
+        answer["Answer"] = records(&packet.answers).into();
+    }
+    if !packet.authorities.is_empty() {
+        answer["Authority"] = records(&packet.authorities).into();
+    }
+    answer
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Client;
+
+    use super::*;
+
+    /// Answer every question with two addresses, the second one with the shorter TTL
+    async fn answer(query: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&query))?;
+        let mut packet = DnsPacket::new();
+        packet.header.id = query.header.id;
+        packet.header.response = true;
+        packet.header.recursion_desired = query.header.recursion_desired;
+        packet.header.checking_disabled = query.header.checking_disabled;
+        let name = query.questions[0].name.clone();
+        packet.questions = query.questions;
+        for (addr, ttl) in [([192, 0, 2, 1], 300), ([192, 0, 2, 2], 60)] {
+            packet.answers.push(DnsRecord::A {
+                domain: name.clone(),
+                addr: addr.into(),
+                ttl,
+            });
+        }
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer)?;
+        Ok(buffer.buf[..buffer.pos].to_vec())
+    }
+
+    /// Start a server on a local port, returning the URL of its DoH endpoint
+    async fn serve() -> String {
+        let server = DohServer::new("127.0.0.1:0", "/dns-query", |_, query, _: ()| answer(query))
+            .await
+            .unwrap();
+        let url = format!("http://{}/dns-query", server.listener.local_addr().unwrap());
+        tokio::spawn(async move { server.start(()).await });
+        url
+    }
+
+    fn client() -> Client {
+        Client::builder().no_proxy().build().unwrap()
+    }
+
+    fn query(name: &str) -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 0;
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(DnsQuestion::new(name.to_string(), QueryType::A));
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        buffer.buf[..buffer.pos].to_vec()
+    }
+
+    fn parse(body: &[u8]) -> DnsPacket {
+        DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(body)).unwrap()
+    }
+
+    fn header(response: &reqwest::Response, name: hyper::header::HeaderName) -> &str {
+        response.headers()[name].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn get_takes_the_query_in_base64url() {
+        let url = serve().await;
+        let dns = URL_SAFE_NO_PAD.encode(query("www.example"));
+        let response = client()
+            .get(format!("{}?dns={}", url, dns))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, CONTENT_TYPE), DNS_MESSAGE);
+        // As long as the shortest TTL
+        assert_eq!(header(&response, CACHE_CONTROL), "max-age=60");
+        let packet = parse(&response.bytes().await.unwrap());
+        assert_eq!(packet.questions[0].name, "www.example");
+        assert_eq!(packet.answers.len(), 2);
+
+        // Padding is tolerated, anything else that isn't base64url isn't
+        let padded = base64::engine::general_purpose::URL_SAFE.encode(query("www.example"));
+        let response = client()
+            .get(format!("{}?dns={}", url, padded))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        for bad in ["?dns=not*base64", "?dns=AAAA", ""] {
+            let response = client()
+                .get(format!("{}{}", url, bad))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn post_needs_the_dns_message_type() {
+        let url = serve().await;
+        let response = client()
+            .post(&url)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(query("www.example"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, CACHE_CONTROL), "max-age=60");
+        let packet = parse(&response.bytes().await.unwrap());
+        assert_eq!(packet.questions[0].name, "www.example");
+
+        let response = client()
+            .post(&url)
+            .body(query("www.example"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn other_methods_and_paths_are_refused() {
+        let url = serve().await;
+        let response = client()
+            .put(&url)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(query("www.example"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let dns = URL_SAFE_NO_PAD.encode(query("www.example"));
+        let response = client()
+            .get(format!("{}x?dns={}", url, dns))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn json_api() {
+        let url = serve().await;
+        let response = client()
+            .get(format!("{}?name=www.example.&type=A&cd=1", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, CONTENT_TYPE), DNS_JSON);
+        assert_eq!(header(&response, CACHE_CONTROL), "max-age=60");
+        let answer: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert_eq!(answer["Status"], 0);
+        assert_eq!(answer["RD"], true);
+        assert_eq!(answer["CD"], true);
+        assert_eq!(
+            answer["Question"],
+            json!([{ "name": "www.example.", "type": 1 }])
+        );
+        assert_eq!(
+            answer["Answer"][1],
+            json!({ "name": "www.example.", "type": 1, "TTL": 60, "data": "192.0.2.2" })
+        );
+
+        // A JSON query needs a name, and a type that exists
+        let response = client()
+            .get(format!("{}?name=www.example&type=BOGUS", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = client()
+            .get(format!("{}?type=A", url))
+            .header(ACCEPT, DNS_JSON)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,36 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Read a single DNS message prefixed with its two byte length (RFC 7766)
+/// returns `None` if the stream was closed cleanly between messages
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let len = u16::from_be_bytes(len) as usize;
+    if len == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "zero length dns message",
+        ));
+    }
+    let mut data = vec![0; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(data))
+}
+
+/// Write a single DNS message prefixed with its two byte length (RFC 7766)
+/// the prefix and message are sent in one write so they share a segment
+pub async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "dns message too large"))?;
+    let mut frame = Vec::with_capacity(data.len() + 2);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(data);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
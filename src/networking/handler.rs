@@ -1,21 +1,31 @@
-use std::sync::Arc;
-
 use tracing::{debug, info};
 
 use crate::{
     block::Blocker,
+    cache::Cache,
     config::Config,
-    dns::recursive_lookup,
+    dns::forward::Forwarder,
+    dnssec::{signer::Signer, Security, Validator},
     protocol::{
-        byte_packet_buffer::BytePacketBuffer, dns_packet::DnsPacket, dns_question::DnsQuestion,
-        result_code::ResultCode, Result,
+        byte_packet_buffer::BytePacketBuffer,
+        dns_header::DnsHeader,
+        dns_packet::DnsPacket,
+        dns_question::DnsQuestion,
+        dns_record::DnsRecord,
+        edns::{Edns, BADVERS},
+        query_type::QueryType,
+        result_code::ResultCode,
+        Result,
     },
     rewrites::Rewrites,
-    Cache,
 };
 
-use super::peer::UdpPeer;
+use super::Protocol;
+
+/// The only opcode we implement, a standard query
+const OPCODE_QUERY: u8 = 0;
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_query(
     config: &Config,
     question: &DnsQuestion,
@@ -23,26 +33,51 @@ pub async fn handle_query(
     cache: &Cache,
     blocker: &Blocker,
     rewrites: &Rewrites,
+    upstreams: &Forwarder,
+    validator: &Validator,
+    signer: &Signer,
 ) {
+    if let Some(zone) = signer.zone_of(&question.name, question.qtype) {
+        debug!("Answering {} from signed zone {}", question.name, zone);
+        let dnssec_ok = out.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+        signer
+            .answer(&zone, question, rewrites, dnssec_ok, out)
+            .await;
+        return;
+    }
+
     if !config.rewrites.is_empty() {
-        if let Some(rewrite) = rewrites.get_rewrite(&question.name).await {
+        if let Some(mut rewrite) = rewrites.get_rewrite(&question.name, question.qtype).await {
             info!("Rewriting query for {}", question.name);
             out.header.rescode = ResultCode::NOERROR;
-            out.answers.push(rewrite);
+            if rewrite.is_empty() {
+                out.authorities
+                    .push(rewrites.negative_soa(&question.name).await);
+            }
+            // Owned by the name exactly as it was asked for
+            for rec in rewrite.iter_mut() {
+                rec.set_domain(&question.name);
+            }
+            out.answers.extend(rewrite);
             return;
         }
     }
 
-    if config.block.enabled && blocker.is_blocked(&question.name).await {
+    if config.block.enabled
+        && blocker
+            .is_blocked(&question.name.to_ascii_lowercase())
+            .await
+    {
         info!("Blocked query for {}", question.name);
         out.header.rescode = ResultCode::NXDOMAIN;
         return;
     }
 
-    let mirror_ns = config.mirror.servers.first().unwrap().as_str();
-
     if config.mirror.enabled {
-        if question.name.ends_with(".home.arpa") {
+        // Unless a rule sends them to the local router that knows them
+        if question.name.to_ascii_lowercase().ends_with(".home.arpa")
+            && !upstreams.has_rule(&question.name)
+        {
             out.header.rescode = ResultCode::NXDOMAIN;
             debug!("NXDOMAIN for {}", question.name);
             return;
@@ -50,59 +85,458 @@ pub async fn handle_query(
 
         debug!("Lookup for {}", question.name);
 
-        let result = recursive_lookup(mirror_ns, &question.name, question.qtype, cache);
+        let result = match cache.get(question) {
+            Some(cached) => {
+                debug!("Cache hit for {}", question.name);
+                Ok(cached)
+            }
+            None => upstreams
+                .query(&question.name, question.qtype)
+                .await
+                .inspect(|result| cache.insert(question, result)),
+        };
 
-        if let Ok(result) = result {
-            out.header.rescode = result.header.rescode;
+        // The error isn't Send, so it can't be held over the validation below
+        let Some(result) = result.ok() else {
+            out.header.rescode = ResultCode::SERVFAIL;
+            return;
+        };
+        out.header.rescode = result.header.rescode;
+        out.header.authed_data = result.header.authed_data;
 
-            if result.header.rescode == ResultCode::NOERROR {
-                for rec in result.answers {
-                    out.answers.push(rec);
+        if config.dnssec.validate {
+            match validator.validate(question, &result).await {
+                Security::Secure => out.header.authed_data = true,
+                // With checking disabled the client gets to look at bogus answers
+                Security::Insecure | Security::Bogus(_) if out.header.checking_disabled => {
+                    out.header.authed_data = false
                 }
-
-                for rec in result.authorities {
-                    out.authorities.push(rec);
+                Security::Insecure => out.header.authed_data = false,
+                Security::Bogus(_) => {
+                    info!("DNSSEC validation failed for {}", question.name);
+                    out.header.rescode = ResultCode::SERVFAIL;
+                    out.header.authed_data = false;
+                    return;
                 }
+            }
+        }
 
-                for rec in result.resources {
-                    out.resources.push(rec);
-                }
+        if matches!(
+            result.header.rescode,
+            ResultCode::NOERROR | ResultCode::NXDOMAIN
+        ) {
+            for rec in result.answers {
+                out.answers.push(rec);
+            }
+
+            for rec in result.authorities {
+                out.authorities.push(rec);
+            }
+
+            for rec in result.resources {
+                out.resources.push(rec);
             }
-        } else {
-            out.header.rescode = ResultCode::SERVFAIL;
         }
     }
 }
 
+/// Answer a request that couldn't be parsed with FORMERR, as long as there is at least a
+/// header to reply to
+fn format_error(protocol: Protocol, buffer: &mut BytePacketBuffer) -> Result<Vec<u8>> {
+    let mut request = DnsHeader::new();
+    buffer.seek(0)?;
+    request.read(buffer)?;
+    if request.response {
+        return Err("Malformed message is not a query".into());
+    }
+
+    let mut packet = DnsPacket::new();
+    packet.header.id = request.id;
+    packet.header.opcode = request.opcode;
+    packet.header.recursion_desired = request.recursion_desired;
+    packet.header.checking_disabled = request.checking_disabled;
+    packet.header.recursion_available = true;
+    packet.header.response = true;
+    packet.header.rescode = ResultCode::FORMERR;
+
+    let mut res_buffer = BytePacketBuffer::with_size(protocol.max_response_size(None));
+    packet.write(&mut res_buffer)?;
+    Ok(res_buffer.get_range(0, res_buffer.pos())?.to_vec())
+}
+
+/// Drop the DNSSEC records a client didn't ask for, from an answer to one that doesn't
+/// understand them (RFC 3225 section 3)
+fn strip_dnssec(packet: &mut DnsPacket) {
+    let qtype = packet.questions.first().map(|question| question.qtype);
+    let keep = |rec: &DnsRecord| {
+        let rtype = rec.query_type();
+        !matches!(rtype, QueryType::RRSIG | QueryType::NSEC | QueryType::NSEC3)
+            || Some(rtype) == qtype
+    };
+    packet.answers.retain(keep);
+    packet.authorities.retain(keep);
+    packet.resources.retain(keep);
+}
+
+/// Answer a single request, returning the response to send back over `protocol`
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_request(
     config: &Config,
-    peer: &Arc<UdpPeer>,
+    protocol: Protocol,
     buffer: &mut BytePacketBuffer,
     cache: &Cache,
     blocker: &Blocker,
     rewrites: &Rewrites,
-) -> Result<()> {
-    let mut request = DnsPacket::from_buffer(buffer)?;
+    upstreams: &Forwarder,
+    validator: &Validator,
+    signer: &Signer,
+) -> Result<Vec<u8>> {
+    let request = if config.server.strict_parsing {
+        DnsPacket::from_buffer_strict(buffer)
+    } else {
+        DnsPacket::from_buffer(buffer)
+    }
+    .map_err(|err| err.to_string());
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => {
+            debug!("Malformed request: {}", err);
+            return format_error(protocol, buffer);
+        }
+    };
 
     let mut packet = DnsPacket::new();
     packet.header.id = request.header.id;
-    packet.header.recursion_desired = true;
+    packet.header.opcode = request.header.opcode;
+    packet.header.recursion_desired = request.header.recursion_desired;
+    packet.header.checking_disabled = request.header.checking_disabled;
     packet.header.recursion_available = true;
     packet.header.response = true;
 
-    if let Some(question) = request.questions.pop() {
-        packet.questions.push(question.clone());
-        handle_query(config, &question, &mut packet, cache, blocker, rewrites).await;
-    } else {
+    // A client that speaks EDNS gets EDNS back, echoing its DO bit (RFC 6891, RFC 3225)
+    let mut bad_version = false;
+    if let Some(edns) = &request.edns {
+        let mut response_edns = Edns::new();
+        response_edns.dnssec_ok = edns.dnssec_ok;
+        if edns.version > 0 {
+            debug!("Unsupported EDNS version {} from client", edns.version);
+            packet.header.rescode = ResultCode::from_num(response_edns.set_rcode(BADVERS));
+            bad_version = true;
+        }
+        packet.edns = Some(response_edns);
+    }
+
+    if bad_version {
+        // Nothing else is answered, the client should retry with a version we support
+    } else if request.header.opcode != OPCODE_QUERY {
+        debug!("Unsupported opcode {}", request.header.opcode);
+        packet.questions = request.questions;
+        packet.header.rescode = ResultCode::NOTIMP;
+    } else if request.questions.len() != 1 {
+        // Nobody agrees on what several questions in one message would mean, so like
+        // everyone else we only take exactly one
+        debug!("Request with {} questions", request.questions.len());
         packet.header.rescode = ResultCode::FORMERR;
+    } else {
+        let question = &request.questions[0];
+        packet.questions.push(question.clone());
+        handle_query(
+            config,
+            question,
+            &mut packet,
+            cache,
+            blocker,
+            rewrites,
+            upstreams,
+            validator,
+            signer,
+        )
+        .await;
+    }
+
+    let dnssec_ok = request.edns.as_ref().is_some_and(|edns| edns.dnssec_ok);
+    if !dnssec_ok {
+        strip_dnssec(&mut packet);
     }
+    // AD only goes to clients that said they understand it, with the AD or DO bit
+    // (RFC 6840 section 5.8)
+    packet.header.authed_data &= request.header.authed_data || dnssec_ok;
 
-    let mut res_buffer = BytePacketBuffer::new();
+    let mut res_buffer =
+        BytePacketBuffer::with_size(protocol.max_response_size(request.edns.as_ref()));
     packet.write(&mut res_buffer)?;
 
     let len = res_buffer.pos();
     let data = res_buffer.get_range(0, len)?;
 
-    peer.send(data).await?;
-    Ok(())
+    Ok(data.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+    use crate::{config::load_config, dnssec::UpstreamLookup, protocol::dnssec::RrsigData};
+
+    /// An upstream on a local socket that answers every name with a signed address and
+    /// an NSEC record, claiming it validated them
+    async fn upstream() -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            loop {
+                let (size, client) = socket.recv_from(&mut buf).await.unwrap();
+                let query = DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&buf[..size]))
+                    .unwrap();
+                let name = query.questions[0].name.clone();
+
+                let mut response = DnsPacket::new();
+                response.header.id = query.header.id;
+                response.header.response = true;
+                response.header.authed_data = true;
+                response.questions = query.questions;
+                response.answers.push(DnsRecord::A {
+                    domain: name.clone(),
+                    addr: [192, 0, 2, 1].into(),
+                    ttl: 300,
+                });
+                response.answers.push(DnsRecord::RRSIG {
+                    domain: name.clone(),
+                    data: RrsigData {
+                        type_covered: QueryType::A.to_num(),
+                        algorithm: 15,
+                        labels: 2,
+                        original_ttl: 300,
+                        expiration: 2000000000,
+                        inception: 1700000000,
+                        key_tag: 1,
+                        signer: "example".to_string(),
+                        signature: vec![0; 64],
+                    },
+                    ttl: 300,
+                });
+                response.authorities.push(DnsRecord::NSEC {
+                    domain: name,
+                    next: "z.example".to_string(),
+                    types: vec![QueryType::A.to_num(), QueryType::RRSIG.to_num()],
+                    ttl: 300,
+                });
+                let mut out = BytePacketBuffer::new();
+                response.write(&mut out).unwrap();
+                socket.send_to(&out.buf[..out.pos], client).await.unwrap();
+            }
+        });
+        addr.to_string()
+    }
+
+    /// Everything a request is answered with, forwarding to a local upstream, with a
+    /// rule for each domain in `forward` that sends it to the same upstream
+    struct Handler {
+        config: Config,
+        cache: Cache,
+        blocker: Blocker,
+        rewrites: Rewrites,
+        upstreams: Forwarder,
+        validator: Validator,
+        signer: Signer,
+    }
+
+    impl Handler {
+        async fn new(forward: &[&str]) -> Handler {
+            let path = std::env::temp_dir().join(format!(
+                "mindns-handler-{}-{}.yaml",
+                std::process::id(),
+                rand::random::<u32>()
+            ));
+            let upstream = upstream().await;
+            let mut yaml = format!("mirror:\n  servers: [\"{}\"]\n  forward:\n", upstream);
+            for domain in forward {
+                yaml += &format!(
+                    "    - domain: {}\n      servers: [\"{}\"]\n",
+                    domain, upstream
+                );
+            }
+            yaml += "rewrites: []\n";
+            std::fs::write(&path, yaml).unwrap();
+            let config = load_config(path.clone());
+            std::fs::remove_file(path).unwrap();
+
+            let http = reqwest::Client::new();
+            let cache = Cache::new(config.cache.clone());
+            let upstreams = Forwarder::new(&config.mirror, &http).unwrap();
+            let validator = Validator::new(
+                &config.dnssec.trust_anchors,
+                UpstreamLookup {
+                    cache: cache.clone(),
+                    upstreams: upstreams.clone(),
+                },
+            )
+            .unwrap();
+            Handler {
+                blocker: Blocker::new(Vec::new(), None, http),
+                rewrites: Rewrites::new(),
+                signer: Signer::new(&[]),
+                config,
+                cache,
+                upstreams,
+                validator,
+            }
+        }
+
+        async fn ask(&self, mut request: DnsPacket) -> DnsPacket {
+            let mut buffer = BytePacketBuffer::new();
+            request.write(&mut buffer).unwrap();
+            let mut buffer = BytePacketBuffer::from_bytes(&buffer.buf[..buffer.pos]);
+            let response = handle_request(
+                &self.config,
+                Protocol::Udp,
+                &mut buffer,
+                &self.cache,
+                &self.blocker,
+                &self.rewrites,
+                &self.upstreams,
+                &self.validator,
+                &self.signer,
+            )
+            .await
+            .unwrap();
+            DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&response)).unwrap()
+        }
+    }
+
+    fn query(name: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 4242;
+        packet.header.recursion_desired = true;
+        packet
+            .questions
+            .push(DnsQuestion::new(name.to_string(), qtype));
+        packet
+    }
+
+    fn dnssec_ok(mut packet: DnsPacket) -> DnsPacket {
+        let mut edns = Edns::new();
+        edns.dnssec_ok = true;
+        packet.edns = Some(edns);
+        packet
+    }
+
+    #[tokio::test]
+    async fn home_arpa_is_nxdomain_without_a_rule() {
+        let handler = Handler::new(&[]).await;
+        let response = handler.ask(query("router.home.arpa", QueryType::A)).await;
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+        assert!(response.answers.is_empty());
+        let response = handler.ask(query("Router.Home.Arpa", QueryType::A)).await;
+        assert_eq!(response.header.rescode, ResultCode::NXDOMAIN);
+
+        // A rule sends them to the router that knows them
+        let handler = Handler::new(&["home.arpa"]).await;
+        let response = handler.ask(query("router.home.arpa", QueryType::A)).await;
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+        assert_eq!(response.answers[0].domain(), "router.home.arpa");
+    }
+
+    #[tokio::test]
+    async fn other_opcodes_are_not_implemented() {
+        let handler = Handler::new(&[]).await;
+        let mut request = query("www.example", QueryType::A);
+        request.header.opcode = 2;
+        let response = handler.ask(request).await;
+        assert_eq!(response.header.id, 4242);
+        assert_eq!(response.header.opcode, 2);
+        assert_eq!(response.header.rescode, ResultCode::NOTIMP);
+        assert_eq!(response.questions[0].name, "www.example");
+        assert!(response.answers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn exactly_one_question_is_answered() {
+        let handler = Handler::new(&[]).await;
+        let mut request = query("www.example", QueryType::A);
+        request.questions.clear();
+        let response = handler.ask(request).await;
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+
+        let mut request = query("www.example", QueryType::A);
+        request
+            .questions
+            .push(DnsQuestion::new("mail.example".to_string(), QueryType::A));
+        let response = handler.ask(request).await;
+        assert_eq!(response.header.rescode, ResultCode::FORMERR);
+        assert!(response.answers.is_empty());
+
+        let response = handler.ask(query("www.example", QueryType::A)).await;
+        assert_eq!(response.header.rescode, ResultCode::NOERROR);
+    }
+
+    #[tokio::test]
+    async fn rd_and_cd_are_copied() {
+        let handler = Handler::new(&[]).await;
+        for (rd, cd) in [(true, false), (false, true)] {
+            let mut request = query("www.example", QueryType::A);
+            request.header.recursion_desired = rd;
+            request.header.checking_disabled = cd;
+            let response = handler.ask(request).await;
+            assert_eq!(response.header.recursion_desired, rd);
+            assert_eq!(response.header.checking_disabled, cd);
+            assert!(response.header.recursion_available);
+        }
+    }
+
+    #[tokio::test]
+    async fn ad_only_goes_to_clients_that_understand_it() {
+        let handler = Handler::new(&[]).await;
+        let response = handler.ask(query("www.example", QueryType::A)).await;
+        assert!(!response.header.authed_data);
+
+        let mut request = query("www.example", QueryType::A);
+        request.header.authed_data = true;
+        let response = handler.ask(request).await;
+        assert!(response.header.authed_data);
+
+        let response = handler
+            .ask(dnssec_ok(query("www.example", QueryType::A)))
+            .await;
+        assert!(response.header.authed_data);
+    }
+
+    #[tokio::test]
+    async fn dnssec_records_only_go_to_clients_with_do() {
+        let handler = Handler::new(&[]).await;
+        let response = handler.ask(query("www.example", QueryType::A)).await;
+        assert_eq!(
+            response
+                .answers
+                .iter()
+                .map(DnsRecord::query_type)
+                .collect::<Vec<_>>(),
+            [QueryType::A]
+        );
+        assert!(response.authorities.is_empty());
+        assert!(response.edns.is_none());
+
+        let response = handler
+            .ask(dnssec_ok(query("www.example", QueryType::A)))
+            .await;
+        assert_eq!(
+            response
+                .answers
+                .iter()
+                .map(DnsRecord::query_type)
+                .collect::<Vec<_>>(),
+            [QueryType::A, QueryType::RRSIG]
+        );
+        assert_eq!(response.authorities[0].query_type(), QueryType::NSEC);
+        assert!(response.edns.is_some_and(|edns| edns.dnssec_ok));
+
+        // Unless they asked for exactly those records
+        let response = handler.ask(query("www.example", QueryType::RRSIG)).await;
+        assert!(response
+            .answers
+            .iter()
+            .any(|rec| rec.query_type() == QueryType::RRSIG));
+    }
 }
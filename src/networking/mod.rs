@@ -1,3 +1,35 @@
+use crate::protocol::{
+    byte_packet_buffer::{STREAM_MAX_SIZE, UDP_MAX_SIZE},
+    edns::{Edns, EDNS_UDP_SIZE},
+};
+
+pub mod certs;
+pub mod doh_serv;
+pub mod framing;
 pub mod handler;
 pub mod peer;
+pub mod tcp_serv;
+pub mod tls_serv;
 pub mod udp_serv;
+
+/// The transport a request arrived on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl Protocol {
+    /// The largest response that can be sent back over this transport, to a client that
+    /// sent `edns` with its query
+    pub fn max_response_size(self, edns: Option<&Edns>) -> usize {
+        match self {
+            Protocol::Udp => edns.map_or(UDP_MAX_SIZE, |edns| {
+                (edns.udp_size as usize).clamp(UDP_MAX_SIZE, EDNS_UDP_SIZE as usize)
+            }),
+            Protocol::Tcp | Protocol::Tls | Protocol::Https => STREAM_MAX_SIZE,
+        }
+    }
+}
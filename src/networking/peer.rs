@@ -14,6 +14,7 @@ pub type UdpReader = UnboundedReceiver<io::Result<Vec<u8>>>;
 /// UDP Peer
 /// each address+port is equal to one UDP peer
 pub struct UdpPeer {
+    #[allow(dead_code)]
     pub socket_id: usize,
     pub udp_sock: Arc<UdpSocket>,
     pub addr: SocketAddr,
@@ -57,7 +58,7 @@ impl UdpPeer {
     #[inline]
     pub(crate) fn push_data(&self, buf: Vec<u8>) -> io::Result<()> {
         if let Err(err) = self.sender.send(Ok(buf)) {
-            Err(io::Error::new(ErrorKind::Other, err))
+            Err(io::Error::other(err))
         } else {
             Ok(())
         }
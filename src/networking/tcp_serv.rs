@@ -0,0 +1,233 @@
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, error, trace};
+
+use super::framing::{read_message, write_message};
+
+/// How long a connection may sit without any outstanding queries by default
+pub const DEFAULT_IDLE_TIMEOUT_SEC: u64 = 10;
+
+/// How many queries from a single connection are processed concurrently
+pub const MAX_IN_FLIGHT: usize = 64;
+
+/// TCP Server listen
+/// each connection may pipeline queries, responses are sent as soon as they are ready
+pub struct TcpServer<I, T> {
+    listener: TcpListener,
+    input: Arc<I>,
+    _ph: PhantomData<T>,
+    idle_timeout: Duration,
+}
+
+impl<I, R, T> TcpServer<I, T>
+where
+    I: Fn(SocketAddr, Vec<u8>, T) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send + 'static,
+    T: Sync + Send + Clone + 'static,
+{
+    /// new tcp server
+    pub async fn new<A: ToSocketAddrs>(addr: A, input: I) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(TcpServer {
+            listener,
+            input: Arc::new(input),
+            _ph: Default::default(),
+            idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SEC),
+        })
+    }
+
+    /// set how long a connection without outstanding queries is kept open
+    #[inline]
+    pub fn set_idle_timeout_sec(mut self, sec: u64) -> TcpServer<I, T> {
+        assert!(sec > 0);
+        self.idle_timeout = Duration::from_secs(sec);
+        self
+    }
+
+    /// start server
+    pub async fn start(&self, inner: T) -> io::Result<()> {
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    trace!("tcp accept error:{err}");
+                    continue;
+                }
+            };
+            if let Err(err) = stream.set_nodelay(true) {
+                trace!("tcp set nodelay error:{err}");
+            }
+            trace!("accepted tcp connection:{addr}");
+            let input = self.input.clone();
+            let inner = inner.clone();
+            let idle_timeout = self.idle_timeout;
+            tokio::spawn(async move {
+                if let Err(err) = serve_connection(stream, addr, input, inner, idle_timeout).await {
+                    debug!("tcp connection:{addr} error:{err}");
+                }
+            });
+        }
+    }
+}
+
+/// Serve DNS messages framed per RFC 7766 on an established stream
+///
+/// Queries are read continuously and handled concurrently, so a slow answer does not hold
+/// up the ones queued behind it. The connection is closed once the client goes away, or
+/// after `idle_timeout` passes with no outstanding queries.
+pub async fn serve_connection<S, I, R, T>(
+    stream: S,
+    addr: SocketAddr,
+    input: Arc<I>,
+    inner: T,
+    idle_timeout: Duration,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    I: Fn(SocketAddr, Vec<u8>, T) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send + 'static,
+    T: Sync + Send + Clone + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(MAX_IN_FLIGHT);
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(response) = rx.recv().await {
+            write_message(&mut writer, &response).await?;
+        }
+        Ok::<_, io::Error>(())
+    });
+
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    loop {
+        let message = {
+            let mut read = pin!(read_message(&mut reader));
+            loop {
+                tokio::select! {
+                    message = &mut read => break message,
+                    _ = tokio::time::sleep(idle_timeout) => {
+                        // Only idle if nothing is waiting on an answer
+                        if in_flight.available_permits() == MAX_IN_FLIGHT {
+                            trace!("tcp connection:{addr} idle timeout");
+                            break Ok(None);
+                        }
+                    }
+                }
+            }
+        };
+        let Some(message) = message? else {
+            break;
+        };
+
+        let permit = in_flight
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let input = input.clone();
+        let inner = inner.clone();
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let response = match (input)(addr, message, inner).await {
+                Ok(response) => response,
+                Err(err) => {
+                    error!("tcp input error:{err}");
+                    return;
+                }
+            };
+            let _ = tx.send(response).await;
+            drop(permit);
+        });
+    }
+
+    // Let outstanding queries finish, the writer stops once every sender is gone
+    drop(tx);
+    writer_task.await.map_err(io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    const ADDR: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+    /// Serve a connection that echoes every message back after waiting as many
+    /// milliseconds as its first byte says
+    fn echo_server(idle_timeout: Duration) -> DuplexStream {
+        let (client, server) = duplex(4096);
+        let input = Arc::new(|_addr, message: Vec<u8>, _inner: ()| async move {
+            tokio::time::sleep(Duration::from_millis(message[0] as u64)).await;
+            Ok::<_, Box<dyn Error>>(message)
+        });
+        tokio::spawn(serve_connection(server, ADDR, input, (), idle_timeout));
+        client
+    }
+
+    #[tokio::test]
+    async fn framing_round_trips() {
+        let (mut a, mut b) = duplex(1024);
+        write_message(&mut a, b"first").await.unwrap();
+        write_message(&mut a, &[7; 300]).await.unwrap();
+        drop(a);
+        assert_eq!(read_message(&mut b).await.unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_message(&mut b).await.unwrap(), Some(vec![7; 300]));
+        // Closing between messages is a clean end of the stream
+        assert_eq!(read_message(&mut b).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn framing_rejects_empty_and_cut_off_messages() {
+        let (mut a, mut b) = duplex(1024);
+        a.write_all(&[0, 0]).await.unwrap();
+        assert!(read_message(&mut b).await.is_err());
+
+        let (mut a, mut b) = duplex(1024);
+        a.write_all(&[0, 10, 1, 2, 3]).await.unwrap();
+        drop(a);
+        assert!(read_message(&mut b).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pipelined_queries_are_answered_as_they_finish() {
+        let mut client = echo_server(Duration::from_secs(5));
+        // Both queries go out before either answer comes back
+        write_message(&mut client, &[200, 1]).await.unwrap();
+        write_message(&mut client, &[0, 2]).await.unwrap();
+        assert_eq!(read_message(&mut client).await.unwrap(), Some(vec![0, 2]));
+        assert_eq!(read_message(&mut client).await.unwrap(), Some(vec![200, 1]));
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed() {
+        let mut client = echo_server(Duration::from_millis(100));
+        let start = Instant::now();
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn outstanding_queries_keep_the_connection_open() {
+        let mut client = echo_server(Duration::from_millis(50));
+        write_message(&mut client, &[250, 3]).await.unwrap();
+        assert_eq!(read_message(&mut client).await.unwrap(), Some(vec![250, 3]));
+        // Once nothing is outstanding the timeout applies again
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,11 @@
+#!/bin/sh
+# Self-signed certificates for dns.test, for the TLS tests to trust and swap between
+set -e
+for name in one two; do
+    openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes -days 36500 \
+        -subj "/CN=dns.test" \
+        -addext "subjectAltName=DNS:dns.test" \
+        -addext "basicConstraints=critical,CA:FALSE" \
+        -addext "extendedKeyUsage=serverAuth" \
+        -keyout "$name.key" -out "$name.pem"
+done
@@ -0,0 +1,235 @@
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::ServerConfig;
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, trace};
+
+use super::certs::CertResolver;
+use super::tcp_serv::{serve_connection, DEFAULT_IDLE_TIMEOUT_SEC};
+
+/// How long a client gets to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The ALPN protocol id of DNS over TLS
+const DOT_ALPN: &[u8] = b"dot";
+
+/// DNS over TLS Server listen (RFC 7858)
+/// once the handshake is done, connections behave exactly like plain TCP ones
+pub struct TlsServer<I, T> {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    input: Arc<I>,
+    _ph: PhantomData<T>,
+    idle_timeout: Duration,
+    handshake_timeout: Duration,
+}
+
+impl<I, R, T> TlsServer<I, T>
+where
+    I: Fn(SocketAddr, Vec<u8>, T) -> R + Send + Sync + 'static,
+    R: Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send + 'static,
+    T: Sync + Send + Clone + 'static,
+{
+    /// new tls server, certificates are taken from `certs` at the start of every handshake
+    pub async fn new<A: ToSocketAddrs>(
+        addr: A,
+        certs: Arc<CertResolver>,
+        input: I,
+    ) -> io::Result<Self> {
+        let mut config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(io::Error::other)?
+                .with_no_client_auth()
+                .with_cert_resolver(certs);
+        // Clients that offer no ALPN protocols still get through, rustls only turns away
+        // those that offer some but not this one
+        config.alpn_protocols = vec![DOT_ALPN.to_vec()];
+
+        let listener = TcpListener::bind(addr).await?;
+        Ok(TlsServer {
+            listener,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            input: Arc::new(input),
+            _ph: Default::default(),
+            idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SEC),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+        })
+    }
+
+    /// set how long a connection without outstanding queries is kept open
+    #[inline]
+    pub fn set_idle_timeout_sec(mut self, sec: u64) -> TlsServer<I, T> {
+        assert!(sec > 0);
+        self.idle_timeout = Duration::from_secs(sec);
+        self
+    }
+
+    /// start server
+    pub async fn start(&self, inner: T) -> io::Result<()> {
+        loop {
+            let (stream, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    trace!("tls accept error:{err}");
+                    continue;
+                }
+            };
+            if let Err(err) = stream.set_nodelay(true) {
+                trace!("tls set nodelay error:{err}");
+            }
+            trace!("accepted tls connection:{addr}");
+            let acceptor = self.acceptor.clone();
+            let input = self.input.clone();
+            let inner = inner.clone();
+            let idle_timeout = self.idle_timeout;
+            let handshake_timeout = self.handshake_timeout;
+            tokio::spawn(async move {
+                let stream =
+                    match tokio::time::timeout(handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(err)) => {
+                            debug!("tls handshake with:{addr} error:{err}");
+                            return;
+                        }
+                        Err(_) => {
+                            debug!("tls handshake with:{addr} timed out");
+                            return;
+                        }
+                    };
+                if let Err(err) = serve_connection(stream, addr, input, inner, idle_timeout).await {
+                    debug!("tls connection:{addr} error:{err}");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use rustls::{pki_types::ServerName, ClientConfig, RootCertStore};
+    use tokio::{io::AsyncReadExt, net::TcpStream};
+    use tokio_rustls::{client::TlsStream, TlsConnector};
+
+    use super::*;
+    use crate::networking::framing::{read_message, write_message};
+
+    const ONE: (&[u8], &[u8]) = (
+        include_bytes!("testdata/one.pem"),
+        include_bytes!("testdata/one.key"),
+    );
+    const TWO: (&[u8], &[u8]) = (
+        include_bytes!("testdata/two.pem"),
+        include_bytes!("testdata/two.key"),
+    );
+
+    /// Start a server on a local port that echoes every message
+    async fn serve(certs: Arc<CertResolver>, handshake_timeout: Duration) -> SocketAddr {
+        let mut server = TlsServer::new("127.0.0.1:0", certs, |_, message, _: ()| async move {
+            Ok::<_, Box<dyn Error>>(message)
+        })
+        .await
+        .unwrap();
+        server.handshake_timeout = handshake_timeout;
+        let addr = server.listener.local_addr().unwrap();
+        tokio::spawn(async move { server.start(()).await });
+        addr
+    }
+
+    /// Connect trusting both test certificates, offering `alpn`
+    async fn connect(addr: SocketAddr, alpn: &[&[u8]]) -> io::Result<TlsStream<TcpStream>> {
+        let mut roots = RootCertStore::empty();
+        for pem in [ONE.0, TWO.0] {
+            for cert in rustls_pemfile::certs(&mut &pem[..]) {
+                roots.add(cert.unwrap()).unwrap();
+            }
+        }
+        let mut config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|id| id.to_vec()).collect();
+        let tcp = TcpStream::connect(addr).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("dns.test").unwrap(), tcp)
+            .await
+    }
+
+    fn peer_certificate(stream: &TlsStream<TcpStream>) -> Vec<u8> {
+        stream.get_ref().1.peer_certificates().unwrap()[0].to_vec()
+    }
+
+    fn der(pem: &[u8]) -> Vec<u8> {
+        rustls_pemfile::certs(&mut &pem[..])
+            .next()
+            .unwrap()
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn dot_is_negotiated() {
+        let certs = Arc::new(CertResolver::new());
+        certs.set_pem(ONE.0, ONE.1).unwrap();
+        let addr = serve(certs, HANDSHAKE_TIMEOUT).await;
+
+        let mut stream = connect(addr, &[b"h2", DOT_ALPN]).await.unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(DOT_ALPN));
+        write_message(&mut stream, b"query").await.unwrap();
+        assert_eq!(
+            read_message(&mut stream).await.unwrap(),
+            Some(b"query".to_vec())
+        );
+
+        // Clients that don't do ALPN are fine, clients that want something else aren't
+        let stream = connect(addr, &[]).await.unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), None);
+        assert!(connect(addr, &[b"h2"]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn new_certificates_are_used_from_the_next_handshake() {
+        let certs = Arc::new(CertResolver::new());
+        certs.set_pem(ONE.0, ONE.1).unwrap();
+        let addr = serve(certs.clone(), HANDSHAKE_TIMEOUT).await;
+
+        let mut before = connect(addr, &[DOT_ALPN]).await.unwrap();
+        assert_eq!(peer_certificate(&before), der(ONE.0));
+
+        certs.set_pem(TWO.0, TWO.1).unwrap();
+        let after = connect(addr, &[DOT_ALPN]).await.unwrap();
+        assert_eq!(peer_certificate(&after), der(TWO.0));
+
+        // The connection from before carries on with the certificate it got
+        write_message(&mut before, b"query").await.unwrap();
+        assert_eq!(
+            read_message(&mut before).await.unwrap(),
+            Some(b"query".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_handshakes_are_cut_off() {
+        let certs = Arc::new(CertResolver::new());
+        certs.set_pem(ONE.0, ONE.1).unwrap();
+        let addr = serve(certs, Duration::from_millis(100)).await;
+
+        // Connect, but never start the handshake
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        let start = Instant::now();
+        let mut buf = [0; 1];
+        assert_eq!(tcp.read(&mut buf).await.unwrap(), 0);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+}
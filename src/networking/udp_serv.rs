@@ -22,6 +22,7 @@ pub const BUFF_MAX_SIZE: usize = 4096;
 /// UDP Context
 /// each bind will create a
 pub struct UdpContext {
+    #[allow(dead_code)]
     pub id: usize,
     recv: Arc<UdpSocket>,
     pub peers: Mutex<HashMap<SocketAddr, UDPPeer>>,
@@ -177,7 +178,7 @@ fn make_udp_client(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
     } else if addr.is_ipv6() {
         Ok(UdpBuilder::new_v6()?.reuse_address(true)?.bind(addr)?)
     } else {
-        Err(io::Error::new(io::ErrorKind::Other, "not address AF_INET"))
+        Err(io::Error::other("not address AF_INET"))
     }
 }
 
@@ -196,7 +197,7 @@ fn make_udp_client(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
             .reuse_port(true)?
             .bind(addr)?)
     } else {
-        Err(io::Error::new(io::ErrorKind::Other, "not address AF_INET"))
+        Err(io::Error::other("not address AF_INET"))
     }
 }
 
@@ -206,20 +207,12 @@ fn create_udp_socket<A: ToSocketAddrs>(addr: &A) -> io::Result<std::net::UdpSock
         let mut addrs = addr.to_socket_addrs()?;
         let addr = match addrs.next() {
             Some(addr) => addr,
-            None => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "no socket addresses could be resolved",
-                ))
-            }
+            None => return Err(io::Error::other("no socket addresses could be resolved")),
         };
         if addrs.next().is_none() {
             Ok(addr)
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                "more than one address resolved",
-            ))
+            Err(io::Error::other("more than one address resolved"))
         }
     };
     let res = make_udp_client(addr?)?;
@@ -1,15 +1,76 @@
+use std::{collections::HashMap, fmt};
+
 use super::Result;
 
+/// The classic maximum size of a DNS message over UDP
+pub const UDP_MAX_SIZE: usize = 512;
+
+/// The maximum size of a DNS message over a stream transport, limited by the
+/// two byte length prefix
+pub const STREAM_MAX_SIZE: usize = 65535;
+
+/// The longest a name may be on the wire, length bytes included (RFC 1035 section 2.3.4)
+pub const MAX_NAME_LEN: usize = 255;
+
+/// The longest a single label may be
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// Compression pointers only have 14 bits for the offset
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
+/// Writing ran past the end of the buffer, which means the message is too large rather
+/// than malformed
+#[derive(Debug)]
+pub struct EndOfBuffer;
+
+impl fmt::Display for EndOfBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "End of buffer")
+    }
+}
+
+impl std::error::Error for EndOfBuffer {}
+
 pub struct BytePacketBuffer {
-    pub buf: [u8; 512],
+    pub buf: Vec<u8>,
     pub pos: usize,
+    /// Where each name written so far starts, so later names can point at it
+    names: HashMap<String, usize>,
+    /// Write names the way DNSSEC signs them, in lowercase and never compressed
+    canonical: bool,
 }
 
 impl BytePacketBuffer {
     pub fn new() -> BytePacketBuffer {
+        BytePacketBuffer::with_size(UDP_MAX_SIZE)
+    }
+
+    /// Create an empty buffer that can hold a message of up to `size` bytes
+    pub fn with_size(size: usize) -> BytePacketBuffer {
         BytePacketBuffer {
-            buf: [0; 512],
+            buf: vec![0; size],
             pos: 0,
+            names: HashMap::new(),
+            canonical: false,
+        }
+    }
+
+    /// Create an empty buffer for the canonical wire format of records, which is what
+    /// DNSSEC signatures are made over (RFC 4034 section 6.2)
+    pub fn canonical(size: usize) -> BytePacketBuffer {
+        BytePacketBuffer {
+            canonical: true,
+            ..BytePacketBuffer::with_size(size)
+        }
+    }
+
+    /// Create a buffer holding exactly the given message
+    pub fn from_bytes(data: &[u8]) -> BytePacketBuffer {
+        BytePacketBuffer {
+            buf: data.to_vec(),
+            pos: 0,
+            names: HashMap::new(),
+            canonical: false,
         }
     }
 
@@ -29,8 +90,15 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    /// Throw away everything written from `pos` onwards, including any names that later
+    /// writes could otherwise have pointed at
+    pub fn rewind(&mut self, pos: usize) {
+        self.pos = pos;
+        self.names.retain(|_, offset| *offset < pos);
+    }
+
     pub fn read(&mut self) -> Result<u8> {
-        if self.pos >= 512 {
+        if self.pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
         let res = self.buf[self.pos];
@@ -40,14 +108,14 @@ impl BytePacketBuffer {
     }
 
     pub fn get(&mut self, pos: usize) -> Result<u8> {
-        if pos >= 512 {
+        if pos >= self.buf.len() {
             return Err("End of buffer".into());
         }
         Ok(self.buf[pos])
     }
 
     pub fn get_range(&mut self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= 512 {
+        if start + len > self.buf.len() {
             return Err("End of buffer".into());
         }
         Ok(&self.buf[start..start + len])
@@ -73,6 +141,8 @@ impl BytePacketBuffer {
         let mut jumped = false;
 
         let mut delim = "";
+        // Every label counts its length byte, including the empty one at the end
+        let mut name_len = 0;
         let max_jumps = 5;
         let mut jumps_performed = 0;
         loop {
@@ -104,6 +174,16 @@ impl BytePacketBuffer {
                 continue;
             }
 
+            // The other two combinations of the high bits were never put to use
+            if len & 0xC0 != 0 {
+                return Err(format!("Unsupported label type {:#04x}", len & 0xC0).into());
+            }
+
+            name_len += len as usize + 1;
+            if name_len > MAX_NAME_LEN {
+                return Err(format!("Name exceeds {} bytes of length", MAX_NAME_LEN).into());
+            }
+
             pos += 1;
 
             // Names are terminated by an empty label of length 0
@@ -114,7 +194,8 @@ impl BytePacketBuffer {
             outstr.push_str(delim);
 
             let str_buffer = self.get_range(pos, len as usize)?;
-            outstr.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
+            // Kept as sent, the case of a name matters to whoever reads it back
+            outstr.push_str(&format_label(str_buffer));
 
             delim = ".";
 
@@ -129,8 +210,8 @@ impl BytePacketBuffer {
     }
 
     pub fn write(&mut self, val: u8) -> Result<()> {
-        if self.pos >= 512 {
-            return Err("End of buffer".into());
+        if self.pos >= self.buf.len() {
+            return Err(EndOfBuffer.into());
         }
         self.buf[self.pos] = val;
         self.pos += 1;
@@ -159,15 +240,56 @@ impl BytePacketBuffer {
         Ok(())
     }
 
+    /// Write a name, pointing back at an earlier copy of its longest suffix already in
+    /// the buffer instead of repeating it (RFC 1035 section 4.1.4)
     pub fn write_qname(&mut self, qname: &str) -> Result<()> {
-        for label in qname.split('.') {
-            let len = label.len();
-            if len > 0x34 {
-                return Err("Single label exceeds 63 characters of length".into());
+        self.write_name(qname, true)
+    }
+
+    /// Write a name in full, for RDATA that may not be compressed, like the target of a
+    /// SRV record (RFC 2782). Later names can still point at it.
+    pub fn write_qname_uncompressed(&mut self, qname: &str) -> Result<()> {
+        self.write_name(qname, false)
+    }
+
+    fn write_name(&mut self, qname: &str, compress: bool) -> Result<()> {
+        let mut labels = parse_name(qname)?;
+        if self.canonical {
+            labels
+                .iter_mut()
+                .for_each(|label| label.make_ascii_lowercase());
+        }
+        let compress = compress && !self.canonical;
+        let name_len = labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+        if name_len > MAX_NAME_LEN {
+            return Err(format!("Name exceeds {} bytes of length", MAX_NAME_LEN).into());
+        }
+
+        for i in 0..labels.len() {
+            // Only point at the exact same spelling, so the case of every name survives
+            let suffix = labels[i..]
+                .iter()
+                .map(|label| format_label(label))
+                .collect::<Vec<_>>()
+                .join(".");
+            if let Some(&offset) = self.names.get(&suffix).filter(|_| compress) {
+                return self.write_u16(0xC000 | offset as u16);
+            }
+            if self.pos <= MAX_POINTER_OFFSET {
+                self.names.insert(suffix, self.pos);
+            }
+
+            let len = labels[i].len();
+            if len > MAX_LABEL_LEN {
+                return Err(format!(
+                    "Single label exceeds {} characters of length",
+                    MAX_LABEL_LEN
+                )
+                .into());
             }
 
             self.write_u8(len as u8)?;
-            for b in label.as_bytes() {
+            for b in &labels[i] {
                 self.write_u8(*b)?;
             }
         }
@@ -190,3 +312,162 @@ impl BytePacketBuffer {
         Ok(())
     }
 }
+
+/// Render a label in presentation format, escaping dots, backslashes and anything
+/// unprintable so the name can be turned back into the same labels (RFC 1035 section 5.1)
+pub fn format_label(label: &[u8]) -> String {
+    let mut text = String::with_capacity(label.len());
+    for &b in label {
+        match b {
+            b'.' | b'\\' => {
+                text.push('\\');
+                text.push(b as char);
+            }
+            0x21..=0x7E => text.push(b as char),
+            _ => text.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    text
+}
+
+/// Rewrite a name in presentation format the way it reads back off the wire, with escapes
+/// only where they are needed and without the trailing dot
+pub fn canonical_name(name: &str) -> Result<String> {
+    let labels = parse_name(name)?;
+    if labels.iter().any(|label| label.len() > MAX_LABEL_LEN) {
+        return Err(format!(
+            "Single label exceeds {} characters of length",
+            MAX_LABEL_LEN
+        )
+        .into());
+    }
+    if labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1 > MAX_NAME_LEN {
+        return Err(format!("Name exceeds {} bytes of length", MAX_NAME_LEN).into());
+    }
+    Ok(labels
+        .iter()
+        .map(|label| format_label(label))
+        .collect::<Vec<_>>()
+        .join("."))
+}
+
+/// Split a name in presentation format into its labels, undoing `\X` and `\DDD` escapes
+pub fn parse_name(name: &str) -> Result<Vec<Vec<u8>>> {
+    let bytes = name.as_bytes();
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'.' => {
+                // Empty labels are skipped, so the root and a trailing dot need no care
+                if !label.is_empty() {
+                    labels.push(std::mem::take(&mut label));
+                }
+                i += 1;
+            }
+            b'\\' => {
+                let digits = bytes
+                    .get(i + 1..i + 4)
+                    .filter(|d| d.iter().all(u8::is_ascii_digit));
+                if let Some(digits) = digits {
+                    let value = (digits[0] - b'0') as u16 * 100
+                        + (digits[1] - b'0') as u16 * 10
+                        + (digits[2] - b'0') as u16;
+                    let value = u8::try_from(value).map_err(|_| "Escape out of range")?;
+                    label.push(value);
+                    i += 4;
+                } else {
+                    label.push(*bytes.get(i + 1).ok_or("Dangling escape")?);
+                    i += 2;
+                }
+            }
+            b => {
+                label.push(b);
+                i += 1;
+            }
+        }
+    }
+    if !label.is_empty() {
+        labels.push(label);
+    }
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A name taking up exactly `len` bytes on the wire
+    fn name_of_len(len: usize) -> String {
+        let mut labels = Vec::new();
+        // The empty label at the end takes one byte
+        let mut left = len - 1;
+        while left > 0 {
+            let label = (left - 1).min(MAX_LABEL_LEN);
+            labels.push("a".repeat(label));
+            left -= label + 1;
+        }
+        labels.join(".")
+    }
+
+    fn wire(name: &str) -> Vec<u8> {
+        let mut wire = Vec::new();
+        for label in name.split('.') {
+            wire.push(label.len() as u8);
+            wire.extend_from_slice(label.as_bytes());
+        }
+        wire.push(0);
+        wire
+    }
+
+    #[test]
+    fn names_of_255_bytes_are_accepted() {
+        let name = name_of_len(MAX_NAME_LEN);
+        assert_eq!(wire(&name).len(), MAX_NAME_LEN);
+
+        let mut buffer = BytePacketBuffer::with_size(512);
+        buffer.write_qname(&name).unwrap();
+        assert_eq!(&buffer.buf[..buffer.pos()], &wire(&name)[..]);
+
+        let mut read = String::new();
+        BytePacketBuffer::from_bytes(&wire(&name))
+            .read_qname(&mut read)
+            .unwrap();
+        assert_eq!(read, name);
+    }
+
+    #[test]
+    fn names_of_256_bytes_are_rejected() {
+        let name = name_of_len(MAX_NAME_LEN + 1);
+        assert_eq!(wire(&name).len(), MAX_NAME_LEN + 1);
+
+        assert!(BytePacketBuffer::with_size(512).write_qname(&name).is_err());
+        assert!(BytePacketBuffer::from_bytes(&wire(&name))
+            .read_qname(&mut String::new())
+            .is_err());
+    }
+
+    #[test]
+    fn names_are_measured_across_compression_pointers() {
+        // The last label of each name is written first, the rest points back at it
+        for (len, ok) in [(MAX_NAME_LEN, true), (MAX_NAME_LEN + 1, false)] {
+            let name = name_of_len(len);
+            let (first, rest) = name.split_once('.').unwrap();
+            let mut data = wire(rest);
+            let start = data.len();
+            data.push(first.len() as u8);
+            data.extend_from_slice(first.as_bytes());
+            data.extend_from_slice(&[0xC0, 0]);
+
+            let mut buffer = BytePacketBuffer::from_bytes(&data);
+            buffer.seek(start).unwrap();
+            let mut read = String::new();
+            assert_eq!(buffer.read_qname(&mut read).is_ok(), ok);
+            if ok {
+                assert_eq!(read, name);
+                assert_eq!(buffer.pos(), data.len());
+            }
+        }
+    }
+}
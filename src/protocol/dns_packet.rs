@@ -1,8 +1,11 @@
-use std::net::Ipv4Addr;
-
 use super::{
-    byte_packet_buffer::BytePacketBuffer, dns_header::DnsHeader, dns_question::DnsQuestion,
-    dns_record::DnsRecord, query_type::QueryType, Result,
+    byte_packet_buffer::{BytePacketBuffer, EndOfBuffer},
+    dns_header::DnsHeader,
+    dns_question::DnsQuestion,
+    dns_record::DnsRecord,
+    edns::{Edns, OPT_TYPE},
+    query_type::QueryType,
+    Result,
 };
 
 #[derive(Clone, Debug)]
@@ -12,6 +15,7 @@ pub struct DnsPacket {
     pub answers: Vec<DnsRecord>,
     pub authorities: Vec<DnsRecord>,
     pub resources: Vec<DnsRecord>,
+    pub edns: Option<Edns>,
 }
 
 impl DnsPacket {
@@ -22,6 +26,7 @@ impl DnsPacket {
             answers: Vec::new(),
             authorities: Vec::new(),
             resources: Vec::new(),
+            edns: None,
         }
     }
 
@@ -44,6 +49,20 @@ impl DnsPacket {
             result.authorities.push(rec);
         }
         for _ in 0..result.header.resource_entries {
+            // The OPT pseudo-record is pulled out of the additional section, it is only
+            // valid once and with the root as its owner
+            let start = buffer.pos();
+            let mut domain = String::new();
+            buffer.read_qname(&mut domain)?;
+            if buffer.read_u16()? == OPT_TYPE {
+                if !domain.is_empty() || result.edns.is_some() {
+                    return Err("Invalid OPT record".into());
+                }
+                result.edns = Some(Edns::read(buffer)?);
+                continue;
+            }
+            buffer.seek(start)?;
+
             let rec = DnsRecord::read(buffer)?;
             result.resources.push(rec);
         }
@@ -51,89 +70,346 @@ impl DnsPacket {
         Ok(result)
     }
 
-    pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
-        self.header.questions = self.questions.len() as u16;
-        self.header.answers = self.answers.len() as u16;
-        self.header.authoritative_entries = self.authorities.len() as u16;
-        self.header.resource_entries = self.resources.len() as u16;
+    /// Read a packet like `from_buffer`, but refuse anything that doesn't add up
+    ///
+    /// The header counts have to be possible for the size of the message, and nothing may
+    /// follow the last record. Meant for messages from clients, where a malformed query is
+    /// better answered with FORMERR than guessed at.
+    pub fn from_buffer_strict(buffer: &mut BytePacketBuffer) -> Result<DnsPacket> {
+        let start = buffer.pos();
+        let mut header = DnsHeader::new();
+        header.read(buffer)?;
 
+        // A question takes at least 5 bytes and a record at least 11
+        let least = header.questions as usize * 5
+            + (header.answers as usize
+                + header.authoritative_entries as usize
+                + header.resource_entries as usize)
+                * 11;
+        if least > buffer.buf.len() - buffer.pos() {
+            return Err("Header counts exceed the size of the message".into());
+        }
+
+        buffer.seek(start)?;
+        let packet = DnsPacket::from_buffer(buffer)?;
+        if buffer.pos() != buffer.buf.len() {
+            return Err("Trailing data after the last record".into());
+        }
+
+        Ok(packet)
+    }
+
+    /// Write the packet, dropping whole records from the end when they don't fit
+    ///
+    /// Records that are dropped are removed from the packet as well, and if any of them were
+    /// answers or authorities the packet is marked as truncated so the client retries over
+    /// TCP. Missing additional records don't warrant that (RFC 2181 section 9). Room is
+    /// always kept for the OPT record, which has to be present even in truncated answers.
+    pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         self.header.write(buffer)?;
 
         for question in &self.questions {
             question.write(buffer)?;
         }
-        for rec in &self.answers {
-            rec.write(buffer)?;
+
+        let limit = buffer
+            .buf
+            .len()
+            .saturating_sub(self.edns.as_ref().map_or(0, |edns| edns.wire_len()));
+        // How many of the records fit, only running out of room leaves any out, records
+        // that can't be written at all are an error
+        let write_fitting = |records: &[DnsRecord], buffer: &mut BytePacketBuffer| {
+            for (written, rec) in records.iter().enumerate() {
+                let pos = buffer.pos();
+                match rec.write(buffer) {
+                    Ok(_) if buffer.pos() <= limit => continue,
+                    Ok(_) => {}
+                    Err(err) if err.is::<EndOfBuffer>() => {}
+                    Err(err) => return Err(err),
+                }
+                buffer.rewind(pos);
+                return Ok(written);
+            }
+            Ok(records.len())
+        };
+
+        let mut complete = true;
+        for records in [&mut self.answers, &mut self.authorities] {
+            let written = if complete {
+                write_fitting(records, buffer)?
+            } else {
+                0
+            };
+            if written < records.len() {
+                records.truncate(written);
+                complete = false;
+            }
         }
-        for rec in &self.authorities {
-            rec.write(buffer)?;
+        if !complete {
+            self.header.truncated_message = true;
+            self.resources.clear();
+        } else {
+            let written = write_fitting(&self.resources, buffer)?;
+            self.resources.truncate(written);
         }
-        for rec in &self.resources {
-            rec.write(buffer)?;
+
+        if let Some(edns) = &self.edns {
+            edns.write(buffer)?;
         }
 
+        // Now that it's known what made it in, the header can be written with the real counts
+        self.header.questions = self.questions.len() as u16;
+        self.header.answers = self.answers.len() as u16;
+        self.header.authoritative_entries = self.authorities.len() as u16;
+        self.header.resource_entries = (self.resources.len() + self.edns.is_some() as usize) as u16;
+
+        let end = buffer.pos();
+        buffer.seek(0)?;
+        self.header.write(buffer)?;
+        buffer.seek(end)?;
+
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::networking::Protocol;
+    use crate::protocol::{
+        edns::{EdnsOption, BADVERS, EDNS_UDP_SIZE},
+        result_code::ResultCode,
+    };
+
+    /// Write `packet` into a buffer of `size` bytes, and read back what was written
+    fn round_trip(packet: &mut DnsPacket, size: usize) -> (Vec<u8>, DnsPacket) {
+        let mut buffer = BytePacketBuffer::with_size(size);
+        packet.write(&mut buffer).unwrap();
+        let wire = buffer.buf[..buffer.pos()].to_vec();
+        let read = DnsPacket::from_buffer_strict(&mut BytePacketBuffer::from_bytes(&wire)).unwrap();
+        (wire, read)
+    }
 
-    /// It's useful to be able to pick a random A record from a packet. When we
-    /// get multiple IP's for a single name, it doesn't matter which one we
-    /// choose, so in those cases we can now pick one at random.
-    pub fn get_random_a(&self) -> Option<Ipv4Addr> {
-        self.answers.iter().find_map(|record| match record {
-            DnsRecord::A { addr, .. } => Some(*addr),
-            _ => None,
-        })
+    fn response(qname: &str, qtype: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new();
+        packet.header.id = 0x1234;
+        packet.header.response = true;
+        packet
+            .questions
+            .push(DnsQuestion::new(qname.to_string(), qtype));
+        packet
     }
 
-    /// A helper function which returns an iterator over all name servers in
-    /// the authorities section, represented as (domain, host) tuples
-    fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
-        self.authorities
-            .iter()
-            // In practice, these are always NS records in well formed packages.
-            // Convert the NS records to a tuple which has only the data we need
-            // to make it easy to work with.
-            .filter_map(|record| match record {
-                DnsRecord::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
-                _ => None,
+    fn a_records(domain: &str, count: u8) -> Vec<DnsRecord> {
+        (0..count)
+            .map(|i| DnsRecord::A {
+                domain: domain.to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, i),
+                ttl: 300,
             })
-            // Discard servers which aren't authoritative to our query
-            .filter(move |(domain, _)| qname.ends_with(*domain))
+            .collect()
     }
 
-    /// We'll use the fact that name servers often bundle the corresponding
-    /// A records when replying to an NS query to implement a function that
-    /// returns the actual IP for an NS record if possible.
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        // Get an iterator over the nameservers in the authorities section
-        self.get_ns(qname)
-            // Now we need to look for a matching A record in the additional
-            // section. Since we just want the first valid record, we can just
-            // build a stream of matching records.
-            .flat_map(|(_, host)| {
-                self.resources
-                    .iter()
-                    // Filter for A records where the domain match the host
-                    // of the NS record that we are currently processing
-                    .filter_map(move |record| match record {
-                        DnsRecord::A { domain, addr, .. } if domain == host => Some(addr),
-                        _ => None,
-                    })
-            })
-            .copied()
-            // Finally, pick the first valid entry
-            .next()
+    #[test]
+    fn opt_record_round_trips() {
+        let mut packet = response("example", QueryType::A);
+        let mut edns = Edns::new();
+        edns.udp_size = 4096;
+        edns.dnssec_ok = true;
+        let rcode = edns.set_rcode(BADVERS);
+        packet.header.rescode = ResultCode::from_num(rcode);
+        edns.options = vec![
+            EdnsOption {
+                code: 10,
+                data: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            },
+            EdnsOption {
+                code: 12,
+                data: Vec::new(),
+            },
+        ];
+        packet.edns = Some(edns.clone());
+
+        let (wire, read) = round_trip(&mut packet, 512);
+        assert_eq!(read.edns, Some(edns.clone()));
+        assert_eq!(read.header.resource_entries, 1);
+        assert!(read.resources.is_empty());
+        // Owner, type, size, then extended RCODE, version and the DO bit in place of a TTL
+        let opt = &wire[wire.len() - edns.wire_len()..];
+        assert_eq!(&opt[..11], &[0, 0, 41, 0x10, 0, 1, 0, 0x80, 0, 0, 16]);
     }
 
-    /// However, not all name servers are as that nice. In certain cases there won't
-    /// be any A records in the additional section, and we'll have to perform *another*
-    /// lookup in the midst. For this, we introduce a method for returning the host
-    /// name of an appropriate name server.
-    pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
-        // Get an iterator over the nameservers in the authorities section
-        self.get_ns(qname)
-            .map(|(_, host)| host)
-            // Finally, pick the first valid entry
-            .next()
+    #[test]
+    fn responses_fill_the_buffer_the_client_asked_for() {
+        let mut client = Edns::new();
+        client.udp_size = 4096;
+        // Large buffers are still capped, to stay clear of fragmentation
+        let size = Protocol::Udp.max_response_size(Some(&client));
+        assert_eq!(size, EDNS_UDP_SIZE as usize);
+        assert_eq!(Protocol::Udp.max_response_size(None), 512);
+
+        // Around 1000 bytes, too much for a client without EDNS
+        let mut packet = response("www.example", QueryType::A);
+        packet.answers = a_records("www.example", 60);
+        packet.edns = Some(Edns::new());
+        let (wire, read) = round_trip(&mut packet.clone(), size);
+        assert!(wire.len() > 512);
+        assert!(!read.header.truncated_message);
+        assert_eq!(read.answers, packet.answers);
+
+        packet.edns = None;
+        let (_, read) = round_trip(&mut packet, 512);
+        assert!(read.header.truncated_message);
+    }
+
+    #[test]
+    fn oversized_answers_are_trimmed_to_whole_records() {
+        let mut packet = response("www.example", QueryType::A);
+        packet.answers = a_records("www.example", 100);
+        packet.resources = a_records("ns.example", 2);
+        packet.edns = Some(Edns::new());
+
+        let (wire, read) = round_trip(&mut packet, 512);
+        assert!(wire.len() <= 512);
+        assert!(read.header.truncated_message);
+        // What was left out is gone from the packet too, and the rest reads back intact
+        assert!(!packet.answers.is_empty() && packet.answers.len() < 100);
+        assert_eq!(read.answers, packet.answers);
+        assert!(read.resources.is_empty());
+        // The OPT record has to make it even into a truncated answer
+        assert_eq!(read.edns, packet.edns);
+        // And there wasn't room for one more record
+        assert!(wire.len() + 16 > 512);
+    }
+
+    #[test]
+    fn dropping_additional_records_does_not_truncate() {
+        let mut packet = response("www.example", QueryType::A);
+        packet.answers = a_records("www.example", 2);
+        packet.resources = a_records("ns.example", 100);
+
+        let (wire, read) = round_trip(&mut packet, 512);
+        assert!(wire.len() <= 512);
+        assert!(!read.header.truncated_message);
+        assert_eq!(read.answers.len(), 2);
+        assert!(!read.resources.is_empty() && read.resources.len() < 100);
+        assert_eq!(read.resources, packet.resources);
+    }
+
+    #[test]
+    fn records_that_cant_be_written_are_an_error() {
+        let mut packet = response("www.example", QueryType::A);
+        packet.answers = a_records("www.example", 2);
+        packet.answers.push(DnsRecord::A {
+            domain: format!("{}.example", "a".repeat(64)),
+            addr: Ipv4Addr::new(192, 0, 2, 1),
+            ttl: 300,
+        });
+
+        let mut buffer = BytePacketBuffer::with_size(512);
+        let err = packet.write(&mut buffer).unwrap_err();
+        assert!(err.to_string().contains("Single label"), "{}", err);
+        // Nothing was dropped to make it look like the answer got truncated
+        assert_eq!(packet.answers.len(), 3);
+        assert!(!packet.header.truncated_message);
+    }
+
+    #[test]
+    fn owner_and_rdata_names_are_compressed() {
+        let mut packet = response("a.example", QueryType::A);
+        packet.answers = vec![
+            DnsRecord::CNAME {
+                domain: "a.example".to_string(),
+                host: "b.example".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::CNAME {
+                domain: "b.example".to_string(),
+                host: "c.example".to_string(),
+                ttl: 300,
+            },
+            DnsRecord::A {
+                domain: "c.example".to_string(),
+                addr: Ipv4Addr::new(192, 0, 2, 1),
+                ttl: 300,
+            },
+        ];
+
+        let (wire, read) = round_trip(&mut packet.clone(), 512);
+        assert_eq!(read.answers, packet.answers);
+        // The question name is written in full at offset 12, `example` starting at 14
+        let question = 12 + 11 + 4;
+        // Class IN and a TTL of 300
+        let fixed = [0, 1, 0, 0, 1, 0x2C];
+        let expected = [
+            // a.example, pointing at the question
+            &[0xC0, 12][..],
+            &[0, 5],
+            &fixed,
+            &[0, 4, 1, b'b', 0xC0, 14],
+            // b.example, pointing at the target of the first CNAME
+            &[0xC0, (question + 12) as u8],
+            &[0, 5],
+            &fixed,
+            &[0, 4, 1, b'c', 0xC0, 14],
+            // c.example, pointing at the target of the second CNAME
+            &[0xC0, (question + 28) as u8],
+            &[0, 1],
+            &fixed,
+            &[0, 4, 192, 0, 2, 1],
+        ]
+        .concat();
+        assert_eq!(&wire[question..], &expected[..]);
+    }
+
+    #[test]
+    fn unknown_types_and_classes_pass_through_unchanged() {
+        let wire = [
+            &[0x12, 0x34, 0x84, 0, 0, 1, 0, 2, 0, 0, 0, 0][..],
+            // version.bind, TXT, CH
+            b"\x07version\x04bind\x00",
+            &[0, 16, 0, 3],
+            // A TXT record of class CH, whose RDATA can't be assumed to look like IN's
+            &[0xC0, 12, 0, 16, 0, 3, 0, 0, 0, 0, 0, 7, 6],
+            b"mindns",
+            // A type we know nothing about
+            &[0xC0, 12, 0xFF, 0, 0, 1, 0, 0, 0, 60, 0, 3, 1, 2, 3],
+        ]
+        .concat();
+        let mut packet =
+            DnsPacket::from_buffer_strict(&mut BytePacketBuffer::from_bytes(&wire)).unwrap();
+        assert!(matches!(
+            packet.answers[0],
+            DnsRecord::UNKNOWN {
+                qtype: 16,
+                class: 3,
+                ..
+            }
+        ));
+
+        let (written, _) = round_trip(&mut packet, 512);
+        assert_eq!(written, wire);
+    }
+
+    #[test]
+    fn only_one_opt_record_owned_by_the_root_is_accepted() {
+        let mut packet = response("example", QueryType::A);
+        packet.edns = Some(Edns::new());
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let mut wire = buffer.buf[..buffer.pos()].to_vec();
+        let opt = wire[wire.len() - 11..].to_vec();
+
+        let mut twice = wire.clone();
+        twice.extend_from_slice(&opt);
+        twice[11] = 2;
+        assert!(DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&twice)).is_err());
+
+        // Owned by `a` instead of the root
+        let at = wire.len() - 11;
+        wire.splice(at..at + 1, [1, b'a', 0]);
+        assert!(DnsPacket::from_buffer(&mut BytePacketBuffer::from_bytes(&wire)).is_err());
     }
 }
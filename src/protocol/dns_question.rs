@@ -4,17 +4,22 @@ use super::{byte_packet_buffer::BytePacketBuffer, query_type::QueryType, Result}
 pub struct DnsQuestion {
     pub name: String,
     pub qtype: QueryType,
+    pub class: u16,
 }
 
 impl DnsQuestion {
     pub fn new(name: String, qtype: QueryType) -> DnsQuestion {
-        DnsQuestion { name, qtype }
+        DnsQuestion {
+            name,
+            qtype,
+            class: 1,
+        }
     }
 
     pub fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         buffer.read_qname(&mut self.name)?;
         self.qtype = QueryType::from_num(buffer.read_u16()?); // qtype
-        let _ = buffer.read_u16()?; // class
+        self.class = buffer.read_u16()?; // class
 
         Ok(())
     }
@@ -24,7 +29,7 @@ impl DnsQuestion {
 
         let typenum = self.qtype.to_num();
         buffer.write_u16(typenum)?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.class)?;
 
         Ok(())
     }
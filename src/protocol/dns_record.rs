@@ -1,14 +1,30 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-use super::{byte_packet_buffer::BytePacketBuffer, query_type::QueryType, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use super::{
+    byte_packet_buffer::{canonical_name, BytePacketBuffer},
+    dnssec::{
+        from_hex, read_type_bitmap, to_hex, types_from_text, types_to_text, write_type_bitmap,
+        Nsec3Data, RrsigData,
+    },
+    query_type::QueryType,
+    svcb::SvcbData,
+    Result,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[allow(dead_code)]
+// The variants are named after the record types as the RFCs spell them
+#[allow(clippy::upper_case_acronyms)]
 pub enum DnsRecord {
     UNKNOWN {
         domain: String,
         qtype: u16,
-        data_len: u16,
+        /// Records of any class but IN end up here whatever their type, and are passed on
+        /// with the class they came with
+        class: u16,
+        data: Vec<u8>,
         ttl: u32,
     }, // 0
     A {
@@ -26,17 +42,95 @@ pub enum DnsRecord {
         host: String,
         ttl: u32,
     }, // 5
+    SOA {
+        domain: String,
+        m_name: String,
+        r_name: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        ttl: u32,
+    }, // 6
+    PTR {
+        domain: String,
+        host: String,
+        ttl: u32,
+    }, // 12
     MX {
         domain: String,
         priority: u16,
         host: String,
         ttl: u32,
     }, // 15
+    TXT {
+        domain: String,
+        strings: Vec<Vec<u8>>,
+        ttl: u32,
+    }, // 16
     AAAA {
         domain: String,
         addr: Ipv6Addr,
         ttl: u32,
     }, // 28
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        host: String,
+        ttl: u32,
+    }, // 33
+    DS {
+        domain: String,
+        key_tag: u16,
+        algorithm: u8,
+        digest_type: u8,
+        digest: Vec<u8>,
+        ttl: u32,
+    }, // 43
+    RRSIG {
+        domain: String,
+        data: RrsigData,
+        ttl: u32,
+    }, // 46
+    NSEC {
+        domain: String,
+        next: String,
+        types: Vec<u16>,
+        ttl: u32,
+    }, // 47
+    DNSKEY {
+        domain: String,
+        flags: u16,
+        protocol: u8,
+        algorithm: u8,
+        public_key: Vec<u8>,
+        ttl: u32,
+    }, // 48
+    NSEC3 {
+        domain: String,
+        data: Nsec3Data,
+        ttl: u32,
+    }, // 50
+    SVCB {
+        domain: String,
+        data: SvcbData,
+        ttl: u32,
+    }, // 64
+    HTTPS {
+        domain: String,
+        data: SvcbData,
+        ttl: u32,
+    }, // 65
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+        ttl: u32,
+    }, // 257
 }
 
 impl DnsRecord {
@@ -45,12 +139,17 @@ impl DnsRecord {
         buffer.read_qname(&mut domain)?;
 
         let qtype_num = buffer.read_u16()?;
-        let qtype = QueryType::from_num(qtype_num);
-        let _ = buffer.read_u16()?;
+        let class = buffer.read_u16()?;
+        // The layout of RDATA may differ between classes, so only IN is looked into
+        let qtype = match class {
+            1 => QueryType::from_num(qtype_num),
+            _ => QueryType::UNKNOWN(qtype_num),
+        };
         let ttl = buffer.read_u32()?;
         let data_len = buffer.read_u16()?;
 
-        match qtype {
+        let rdata_start = buffer.pos();
+        let record: Result<DnsRecord> = match qtype {
             QueryType::A => {
                 let raw_addr = buffer.read_u32()?;
                 let addr = Ipv4Addr::new(
@@ -100,6 +199,24 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::SOA => {
+                let mut m_name = String::new();
+                buffer.read_qname(&mut m_name)?;
+                let mut r_name = String::new();
+                buffer.read_qname(&mut r_name)?;
+
+                Ok(DnsRecord::SOA {
+                    domain,
+                    m_name,
+                    r_name,
+                    serial: buffer.read_u32()?,
+                    refresh: buffer.read_u32()?,
+                    retry: buffer.read_u32()?,
+                    expire: buffer.read_u32()?,
+                    minimum: buffer.read_u32()?,
+                    ttl,
+                })
+            }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
                 let mut mx = String::new();
@@ -112,17 +229,168 @@ impl DnsRecord {
                     ttl,
                 })
             }
+            QueryType::PTR => {
+                let mut ptr = String::new();
+                buffer.read_qname(&mut ptr)?;
+
+                Ok(DnsRecord::PTR {
+                    domain,
+                    host: ptr,
+                    ttl,
+                })
+            }
+            QueryType::TXT => {
+                let end = buffer.pos() + data_len as usize;
+                let mut strings = Vec::new();
+                while buffer.pos() < end {
+                    let len = buffer.read()? as usize;
+                    strings.push(buffer.get_range(buffer.pos(), len)?.to_vec());
+                    buffer.step(len)?;
+                }
+                if buffer.pos() != end {
+                    return Err("TXT string overruns the record".into());
+                }
+
+                Ok(DnsRecord::TXT {
+                    domain,
+                    strings,
+                    ttl,
+                })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut srv = String::new();
+                buffer.read_qname(&mut srv)?;
+
+                Ok(DnsRecord::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    host: srv,
+                    ttl,
+                })
+            }
+            QueryType::DS => {
+                let key_tag = buffer.read_u16()?;
+                let algorithm = buffer.read()?;
+                let digest_type = buffer.read()?;
+                let Some(len) = (data_len as usize).checked_sub(4) else {
+                    return Err("DS record too short".into());
+                };
+                let digest = buffer.get_range(buffer.pos(), len)?.to_vec();
+                buffer.step(len)?;
+
+                Ok(DnsRecord::DS {
+                    domain,
+                    key_tag,
+                    algorithm,
+                    digest_type,
+                    digest,
+                    ttl,
+                })
+            }
+            QueryType::RRSIG => Ok(DnsRecord::RRSIG {
+                domain,
+                data: RrsigData::read(buffer, data_len)?,
+                ttl,
+            }),
+            QueryType::NSEC => {
+                let end = buffer.pos() + data_len as usize;
+                let mut next = String::new();
+                buffer.read_qname(&mut next)?;
+                let types = read_type_bitmap(buffer, end)?;
+
+                Ok(DnsRecord::NSEC {
+                    domain,
+                    next,
+                    types,
+                    ttl,
+                })
+            }
+            QueryType::DNSKEY => {
+                let flags = buffer.read_u16()?;
+                let protocol = buffer.read()?;
+                let algorithm = buffer.read()?;
+                let Some(len) = (data_len as usize).checked_sub(4) else {
+                    return Err("DNSKEY record too short".into());
+                };
+                let public_key = buffer.get_range(buffer.pos(), len)?.to_vec();
+                buffer.step(len)?;
+
+                Ok(DnsRecord::DNSKEY {
+                    domain,
+                    flags,
+                    protocol,
+                    algorithm,
+                    public_key,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3 => Ok(DnsRecord::NSEC3 {
+                domain,
+                data: Nsec3Data::read(buffer, data_len)?,
+                ttl,
+            }),
+            QueryType::SVCB => Ok(DnsRecord::SVCB {
+                domain,
+                data: SvcbData::read(buffer, data_len)?,
+                ttl,
+            }),
+            QueryType::HTTPS => Ok(DnsRecord::HTTPS {
+                domain,
+                data: SvcbData::read(buffer, data_len)?,
+                ttl,
+            }),
+            QueryType::CAA => {
+                let flags = buffer.read()?;
+                let tag_len = buffer.read()? as usize;
+                let Some(value_len) = (data_len as usize).checked_sub(2 + tag_len) else {
+                    return Err("CAA tag overruns the record".into());
+                };
+                let tag = buffer.get_range(buffer.pos(), tag_len)?;
+                // Tags are made of letters and digits, and can't be empty (RFC 8659 section 4.1)
+                if tag.is_empty() || !tag.iter().all(u8::is_ascii_alphanumeric) {
+                    return Err("CAA tag isn't alphanumeric".into());
+                }
+                let tag = String::from_utf8_lossy(tag).into_owned();
+                buffer.step(tag_len)?;
+                let value = buffer.get_range(buffer.pos(), value_len)?.to_vec();
+                buffer.step(value_len)?;
+
+                Ok(DnsRecord::CAA {
+                    domain,
+                    flags,
+                    tag,
+                    value,
+                    ttl,
+                })
+            }
             QueryType::UNKNOWN(_) => {
+                // Kept as opaque bytes, so it can be passed on untouched (RFC 3597)
+                let data = buffer.get_range(buffer.pos(), data_len as usize)?.to_vec();
                 buffer.step(data_len as usize)?;
 
                 Ok(DnsRecord::UNKNOWN {
                     domain,
                     qtype: qtype_num,
-                    data_len,
+                    class,
+                    data,
                     ttl,
                 })
             }
+        };
+        let record = record?;
+
+        // Every type has to take up exactly the length it announced, anything else means
+        // the record is corrupt or we are misreading it
+        if buffer.pos() != rdata_start + data_len as usize {
+            return Err(format!("{} record doesn't match its RDATA length", qtype).into());
         }
+
+        Ok(record)
     }
 
     pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
@@ -182,6 +450,36 @@ impl DnsRecord {
                 let size = buffer.pos() - (pos + 2);
                 buffer.set_u16(pos, size as u16)?;
             }
+            DnsRecord::SOA {
+                ref domain,
+                ref m_name,
+                ref r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(m_name)?;
+                buffer.write_qname(r_name)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
             DnsRecord::MX {
                 ref domain,
                 priority,
@@ -217,8 +515,231 @@ impl DnsRecord {
                     buffer.write_u16(*octet)?;
                 }
             }
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping record: {:?}", self);
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::TXT {
+                ref domain,
+                ref strings,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                for string in strings {
+                    buffer.write_u8(string.len() as u8)?;
+                    for b in string {
+                        buffer.write_u8(*b)?;
+                    }
+                }
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname_uncompressed(host)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::DS {
+                ref domain,
+                key_tag,
+                algorithm,
+                digest_type,
+                ref digest,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DS.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16((4 + digest.len()) as u16)?;
+
+                buffer.write_u16(key_tag)?;
+                buffer.write_u8(algorithm)?;
+                buffer.write_u8(digest_type)?;
+                for b in digest {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::RRSIG {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::RRSIG.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                data.write(buffer)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::NSEC {
+                ref domain,
+                ref next,
+                ref types,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                buffer.write_qname_uncompressed(next)?;
+                write_type_bitmap(buffer, types)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::DNSKEY {
+                ref domain,
+                flags,
+                protocol,
+                algorithm,
+                ref public_key,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNSKEY.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16((4 + public_key.len()) as u16)?;
+
+                buffer.write_u16(flags)?;
+                buffer.write_u8(protocol)?;
+                buffer.write_u8(algorithm)?;
+                for b in public_key {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::NSEC3 {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                data.write(buffer)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::SVCB {
+                ref domain,
+                ref data,
+                ttl,
+            }
+            | DnsRecord::HTTPS {
+                ref domain,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(self.query_type().to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.pos();
+                buffer.write_u16(0)?;
+
+                data.write(buffer)?;
+
+                let size = buffer.pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            }
+            DnsRecord::CAA {
+                ref domain,
+                flags,
+                ref tag,
+                ref value,
+                ttl,
+            } => {
+                // Its length has to fit in a single byte
+                if tag.len() > 255 {
+                    return Err("CAA tag is longer than 255 bytes".into());
+                }
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CAA.to_num())?;
+                buffer.write_u16(1)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16((2 + tag.len() + value.len()) as u16)?;
+
+                buffer.write_u8(flags)?;
+                buffer.write_u8(tag.len() as u8)?;
+                for b in tag.as_bytes().iter().chain(value) {
+                    buffer.write_u8(*b)?;
+                }
+            }
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                class,
+                ref data,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class)?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                for b in data {
+                    buffer.write_u8(*b)?;
+                }
             }
         }
 
@@ -230,9 +751,522 @@ impl DnsRecord {
             DnsRecord::A { ttl, .. } => ttl,
             DnsRecord::NS { ttl, .. } => ttl,
             DnsRecord::CNAME { ttl, .. } => ttl,
+            DnsRecord::SOA { ttl, .. } => ttl,
+            DnsRecord::PTR { ttl, .. } => ttl,
             DnsRecord::MX { ttl, .. } => ttl,
+            DnsRecord::TXT { ttl, .. } => ttl,
             DnsRecord::AAAA { ttl, .. } => ttl,
+            DnsRecord::SRV { ttl, .. } => ttl,
+            DnsRecord::DS { ttl, .. } => ttl,
+            DnsRecord::RRSIG { ttl, .. } => ttl,
+            DnsRecord::NSEC { ttl, .. } => ttl,
+            DnsRecord::DNSKEY { ttl, .. } => ttl,
+            DnsRecord::NSEC3 { ttl, .. } => ttl,
+            DnsRecord::SVCB { ttl, .. } => ttl,
+            DnsRecord::HTTPS { ttl, .. } => ttl,
+            DnsRecord::CAA { ttl, .. } => ttl,
             DnsRecord::UNKNOWN { ttl, .. } => ttl,
         }
     }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::SVCB { domain, .. }
+            | DnsRecord::HTTPS { domain, .. }
+            | DnsRecord::CAA { domain, .. }
+            | DnsRecord::UNKNOWN { domain, .. } => domain,
+        }
+    }
+
+    pub fn query_type(&self) -> QueryType {
+        match *self {
+            DnsRecord::A { .. } => QueryType::A,
+            DnsRecord::NS { .. } => QueryType::NS,
+            DnsRecord::CNAME { .. } => QueryType::CNAME,
+            DnsRecord::SOA { .. } => QueryType::SOA,
+            DnsRecord::PTR { .. } => QueryType::PTR,
+            DnsRecord::MX { .. } => QueryType::MX,
+            DnsRecord::TXT { .. } => QueryType::TXT,
+            DnsRecord::AAAA { .. } => QueryType::AAAA,
+            DnsRecord::SRV { .. } => QueryType::SRV,
+            DnsRecord::DS { .. } => QueryType::DS,
+            DnsRecord::RRSIG { .. } => QueryType::RRSIG,
+            DnsRecord::NSEC { .. } => QueryType::NSEC,
+            DnsRecord::DNSKEY { .. } => QueryType::DNSKEY,
+            DnsRecord::NSEC3 { .. } => QueryType::NSEC3,
+            DnsRecord::SVCB { .. } => QueryType::SVCB,
+            DnsRecord::HTTPS { .. } => QueryType::HTTPS,
+            DnsRecord::CAA { .. } => QueryType::CAA,
+            DnsRecord::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
+        }
+    }
+
+    /// The record data in presentation format, as it would appear in a zone file
+    pub fn rdata_text(&self) -> String {
+        match self {
+            DnsRecord::A { addr, .. } => addr.to_string(),
+            DnsRecord::AAAA { addr, .. } => addr.to_string(),
+            DnsRecord::NS { host, .. }
+            | DnsRecord::CNAME { host, .. }
+            | DnsRecord::PTR { host, .. } => format!("{}.", host),
+            DnsRecord::MX { priority, host, .. } => format!("{} {}.", priority, host),
+            DnsRecord::TXT { strings, .. } => strings
+                .iter()
+                .map(|string| quote(string))
+                .collect::<Vec<_>>()
+                .join(" "),
+            DnsRecord::SRV {
+                priority,
+                weight,
+                port,
+                host,
+                ..
+            } => format!("{} {} {} {}.", priority, weight, port, host),
+            DnsRecord::DS {
+                key_tag,
+                algorithm,
+                digest_type,
+                digest,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                key_tag,
+                algorithm,
+                digest_type,
+                to_hex(digest)
+            ),
+            DnsRecord::RRSIG { data, .. } => data.to_text(),
+            DnsRecord::NSEC { next, types, .. } if types.is_empty() => format!("{}.", next),
+            DnsRecord::NSEC { next, types, .. } => {
+                format!("{}. {}", next, types_to_text(types))
+            }
+            DnsRecord::DNSKEY {
+                flags,
+                protocol,
+                algorithm,
+                public_key,
+                ..
+            } => format!(
+                "{} {} {} {}",
+                flags,
+                protocol,
+                algorithm,
+                STANDARD.encode(public_key)
+            ),
+            DnsRecord::NSEC3 { data, .. } => data.to_text(),
+            DnsRecord::SVCB { data, .. } | DnsRecord::HTTPS { data, .. } => data.to_text(),
+            DnsRecord::CAA {
+                flags, tag, value, ..
+            } => format!("{} {} {}", flags, tag, quote(value)),
+            DnsRecord::SOA {
+                m_name,
+                r_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{}. {}. {} {} {} {} {}",
+                m_name, r_name, serial, refresh, retry, expire, minimum
+            ),
+            DnsRecord::UNKNOWN { data, .. } if data.is_empty() => "\\# 0".to_string(),
+            DnsRecord::UNKNOWN { data, .. } => {
+                let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("\\# {} {}", data.len(), hex)
+            }
+        }
+    }
+
+    /// Build a record from its presentation format, as it would appear in a zone file
+    ///
+    /// The generic `\# <length> <hex>` form (RFC 3597) is accepted for every type, which is
+    /// the only way to give the data of a type we don't know.
+    pub fn from_text(
+        domain: &str,
+        qtype: QueryType,
+        text: &str,
+        ttl: u32,
+    ) -> std::result::Result<DnsRecord, String> {
+        let text = text.trim();
+        if let Some(generic) = text.strip_prefix("\\#") {
+            let mut parts = generic.split_whitespace();
+            let len = parts
+                .next()
+                .and_then(|len| len.parse::<u16>().ok())
+                .ok_or_else(|| format!("Missing RDATA length in {}", text))?;
+            let hex: String = parts.collect();
+            if hex.len() != len as usize * 2 || !hex.is_ascii() {
+                return Err(format!("RDATA length doesn't match the data in {}", text));
+            }
+            let data = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                .collect::<std::result::Result<Vec<u8>, _>>()
+                .map_err(|_| format!("Invalid hex in {}", text))?;
+            return DnsRecord::from_rdata(domain, qtype, &data, ttl);
+        }
+
+        if let QueryType::UNKNOWN(_) = qtype {
+            return Err(format!(
+                "{} records have to be given in the generic \\# form",
+                qtype
+            ));
+        }
+        DnsRecord::parse_text(domain, qtype, text, ttl)
+            .ok_or_else(|| format!("Invalid {} data {}", qtype, text))
+    }
+
+    fn parse_text(domain: &str, qtype: QueryType, text: &str, ttl: u32) -> Option<DnsRecord> {
+        let domain = domain.to_string();
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let count = |n: usize| Some(()).filter(|_| fields.len() == n);
+        let name = |i: usize| fields.get(i).and_then(|name| canonical_name(name).ok());
+        let num = |i: usize| fields.get(i).and_then(|num| num.parse::<u32>().ok());
+        let num16 = |i: usize| num(i).and_then(|num| u16::try_from(num).ok());
+
+        let record = match qtype {
+            QueryType::A => DnsRecord::A {
+                domain,
+                addr: text.parse().ok()?,
+                ttl,
+            },
+            QueryType::AAAA => DnsRecord::AAAA {
+                domain,
+                addr: text.parse().ok()?,
+                ttl,
+            },
+            QueryType::NS => DnsRecord::NS {
+                domain,
+                host: count(1).and(name(0))?,
+                ttl,
+            },
+            QueryType::CNAME => DnsRecord::CNAME {
+                domain,
+                host: count(1).and(name(0))?,
+                ttl,
+            },
+            QueryType::PTR => DnsRecord::PTR {
+                domain,
+                host: count(1).and(name(0))?,
+                ttl,
+            },
+            QueryType::MX => DnsRecord::MX {
+                domain,
+                priority: count(2).and(num16(0))?,
+                host: name(1)?,
+                ttl,
+            },
+            QueryType::SOA => DnsRecord::SOA {
+                domain,
+                m_name: count(7).and(name(0))?,
+                r_name: name(1)?,
+                serial: num(2)?,
+                refresh: num(3)?,
+                retry: num(4)?,
+                expire: num(5)?,
+                minimum: num(6)?,
+                ttl,
+            },
+            QueryType::SRV => DnsRecord::SRV {
+                domain,
+                priority: count(4).and(num16(0))?,
+                weight: num16(1)?,
+                port: num16(2)?,
+                host: name(3)?,
+                ttl,
+            },
+            QueryType::TXT => {
+                // Anything longer than a single string can hold is split over several
+                let mut strings: Vec<Vec<u8>> = parse_strings(text)?
+                    .iter()
+                    .flat_map(|string| match string.is_empty() {
+                        true => vec![Vec::new()],
+                        false => string.chunks(255).map(|chunk| chunk.to_vec()).collect(),
+                    })
+                    .collect();
+                // A TXT record holds at least one string, even if it is empty
+                if strings.is_empty() {
+                    strings.push(Vec::new());
+                }
+                DnsRecord::TXT {
+                    domain,
+                    strings,
+                    ttl,
+                }
+            }
+            QueryType::DS => DnsRecord::DS {
+                domain,
+                key_tag: num16(0)?,
+                algorithm: fields.get(1)?.parse().ok()?,
+                digest_type: fields.get(2)?.parse().ok()?,
+                // Long digests are often split over several fields
+                digest: from_hex(&fields[3..].concat())?,
+                ttl,
+            },
+            QueryType::RRSIG => DnsRecord::RRSIG {
+                domain,
+                data: RrsigData::from_text(text)?,
+                ttl,
+            },
+            QueryType::NSEC => DnsRecord::NSEC {
+                domain,
+                next: name(0)?,
+                types: types_from_text(&fields[1..])?,
+                ttl,
+            },
+            QueryType::DNSKEY => DnsRecord::DNSKEY {
+                domain,
+                flags: num16(0)?,
+                protocol: fields.get(1)?.parse().ok()?,
+                algorithm: fields.get(2)?.parse().ok()?,
+                public_key: STANDARD.decode(fields[3..].concat()).ok()?,
+                ttl,
+            },
+            QueryType::NSEC3 => DnsRecord::NSEC3 {
+                domain,
+                data: Nsec3Data::from_text(text)?,
+                ttl,
+            },
+            QueryType::SVCB => DnsRecord::SVCB {
+                domain,
+                data: SvcbData::from_text(text)?,
+                ttl,
+            },
+            QueryType::HTTPS => DnsRecord::HTTPS {
+                domain,
+                data: SvcbData::from_text(text)?,
+                ttl,
+            },
+            QueryType::CAA => {
+                let (flags, rest) = text.split_once(char::is_whitespace)?;
+                let (tag, value) = rest.trim_start().split_once(char::is_whitespace)?;
+                if tag.len() > 255 || !tag.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                    return None;
+                }
+                DnsRecord::CAA {
+                    domain,
+                    flags: flags.parse().ok()?,
+                    tag: tag.to_string(),
+                    value: parse_strings(value)?.concat(),
+                    ttl,
+                }
+            }
+            QueryType::UNKNOWN(_) => return None,
+        };
+        Some(record)
+    }
+
+    /// Build a record from its wire format RDATA
+    fn from_rdata(
+        domain: &str,
+        qtype: QueryType,
+        data: &[u8],
+        ttl: u32,
+    ) -> std::result::Result<DnsRecord, String> {
+        // Wrapped in a record with the root as owner, and read back like any other
+        let mut wire = vec![0];
+        wire.extend_from_slice(&qtype.to_num().to_be_bytes());
+        wire.extend_from_slice(&1u16.to_be_bytes());
+        wire.extend_from_slice(&ttl.to_be_bytes());
+        wire.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        wire.extend_from_slice(data);
+
+        let mut buffer = BytePacketBuffer::from_bytes(&wire);
+        let mut record = DnsRecord::read(&mut buffer)
+            .map_err(|err| format!("Invalid {} RDATA: {}", qtype, err))?;
+        if buffer.pos() != wire.len() {
+            return Err(format!("Invalid {} RDATA: trailing data", qtype));
+        }
+        record.set_domain(domain);
+        Ok(record)
+    }
+
+    pub fn set_domain(&mut self, new_domain: &str) {
+        match self {
+            DnsRecord::A { domain, .. }
+            | DnsRecord::NS { domain, .. }
+            | DnsRecord::CNAME { domain, .. }
+            | DnsRecord::SOA { domain, .. }
+            | DnsRecord::PTR { domain, .. }
+            | DnsRecord::MX { domain, .. }
+            | DnsRecord::TXT { domain, .. }
+            | DnsRecord::AAAA { domain, .. }
+            | DnsRecord::SRV { domain, .. }
+            | DnsRecord::DS { domain, .. }
+            | DnsRecord::RRSIG { domain, .. }
+            | DnsRecord::NSEC { domain, .. }
+            | DnsRecord::DNSKEY { domain, .. }
+            | DnsRecord::NSEC3 { domain, .. }
+            | DnsRecord::SVCB { domain, .. }
+            | DnsRecord::HTTPS { domain, .. }
+            | DnsRecord::CAA { domain, .. }
+            | DnsRecord::UNKNOWN { domain, .. } => *domain = new_domain.to_string(),
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            DnsRecord::A { ttl, .. }
+            | DnsRecord::NS { ttl, .. }
+            | DnsRecord::CNAME { ttl, .. }
+            | DnsRecord::SOA { ttl, .. }
+            | DnsRecord::PTR { ttl, .. }
+            | DnsRecord::MX { ttl, .. }
+            | DnsRecord::TXT { ttl, .. }
+            | DnsRecord::AAAA { ttl, .. }
+            | DnsRecord::SRV { ttl, .. }
+            | DnsRecord::DS { ttl, .. }
+            | DnsRecord::RRSIG { ttl, .. }
+            | DnsRecord::NSEC { ttl, .. }
+            | DnsRecord::DNSKEY { ttl, .. }
+            | DnsRecord::NSEC3 { ttl, .. }
+            | DnsRecord::SVCB { ttl, .. }
+            | DnsRecord::HTTPS { ttl, .. }
+            | DnsRecord::CAA { ttl, .. }
+            | DnsRecord::UNKNOWN { ttl, .. } => *ttl = new_ttl,
+        }
+    }
+}
+
+/// Quote a character string for presentation format, escaping anything unprintable
+pub(super) fn quote(string: &[u8]) -> String {
+    let mut quoted = String::from("\"");
+    for &b in string {
+        match b {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(b as char);
+            }
+            0x20..=0x7E => quoted.push(b as char),
+            _ => quoted.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Parse a sequence of presentation format character strings, quoted or bare, with `\X`
+/// and `\DDD` escapes
+pub(super) fn parse_strings(text: &str) -> Option<Vec<Vec<u8>>> {
+    let bytes = text.as_bytes();
+    let mut strings = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == bytes.len() {
+            break;
+        }
+
+        let quoted = bytes[i] == b'"';
+        if quoted {
+            i += 1;
+        }
+        let mut string = Vec::new();
+        loop {
+            match bytes.get(i) {
+                None if quoted => return None,
+                None => break,
+                Some(b'"') if quoted => {
+                    i += 1;
+                    break;
+                }
+                Some(b) if !quoted && b.is_ascii_whitespace() => break,
+                Some(b'\\') => {
+                    let digits = bytes
+                        .get(i + 1..i + 4)
+                        .filter(|d| d.iter().all(u8::is_ascii_digit));
+                    if let Some(digits) = digits {
+                        let value = std::str::from_utf8(digits).ok()?.parse::<u16>().ok()?;
+                        string.push(u8::try_from(value).ok()?);
+                        i += 4;
+                    } else {
+                        string.push(*bytes.get(i + 1)?);
+                        i += 2;
+                    }
+                }
+                Some(b) => {
+                    string.push(*b);
+                    i += 1;
+                }
+            }
+        }
+        strings.push(string);
+    }
+    Some(strings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txt_strings(text: &str) -> Vec<Vec<u8>> {
+        match DnsRecord::from_text("example", QueryType::TXT, text, 300).unwrap() {
+            DnsRecord::TXT { strings, .. } => strings,
+            record => panic!("Expected a TXT record, got {:?}", record),
+        }
+    }
+
+    #[test]
+    fn empty_txt_data_is_a_single_empty_string() {
+        assert_eq!(txt_strings(""), vec![Vec::<u8>::new()]);
+        assert_eq!(txt_strings("   "), vec![Vec::<u8>::new()]);
+        assert_eq!(txt_strings("\"\""), vec![Vec::<u8>::new()]);
+        assert_eq!(
+            txt_strings("\"\" two \"\""),
+            vec![Vec::new(), b"two".to_vec(), Vec::new()]
+        );
+
+        // Which is a single zero length byte on the wire
+        let record = DnsRecord::from_text("example", QueryType::TXT, "", 300).unwrap();
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        assert_eq!(&buffer.buf[buffer.pos() - 3..buffer.pos()], &[0, 1, 0]);
+    }
+
+    #[test]
+    fn long_txt_data_is_split_into_strings() {
+        let strings = txt_strings(&"a".repeat(300));
+        assert_eq!(strings.len(), 2);
+        assert_eq!(strings[0].len(), 255);
+        assert_eq!(strings[1].len(), 45);
+    }
+
+    #[test]
+    fn caa_tags_longer_than_a_byte_can_count_are_refused() {
+        let text = format!("0 {} \"ca.example\"", "a".repeat(256));
+        assert!(DnsRecord::from_text("example", QueryType::CAA, &text, 300).is_err());
+
+        let record = DnsRecord::CAA {
+            domain: "example".to_string(),
+            flags: 0,
+            tag: "a".repeat(256),
+            value: b"ca.example".to_vec(),
+            ttl: 300,
+        };
+        let mut buffer = BytePacketBuffer::new();
+        let err = record.write(&mut buffer).unwrap_err();
+        assert!(err.to_string().contains("CAA tag"), "{}", err);
+
+        let text = format!("0 {} \"ca.example\"", "a".repeat(255));
+        let record = DnsRecord::from_text("example", QueryType::CAA, &text, 300).unwrap();
+        record.write(&mut BytePacketBuffer::new()).unwrap();
+    }
 }
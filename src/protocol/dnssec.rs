@@ -0,0 +1,382 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, NaiveDateTime};
+
+use super::{
+    byte_packet_buffer::{canonical_name, BytePacketBuffer},
+    query_type::QueryType,
+    Result,
+};
+
+/// The RDATA of an RRSIG record (RFC 4034 section 3)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RrsigData {
+    pub type_covered: u16,
+    pub algorithm: u8,
+    /// The number of labels in the owner name that was signed, fewer than the owner
+    /// actually has means the record was expanded from a wildcard
+    pub labels: u8,
+    pub original_ttl: u32,
+    /// Seconds since the epoch, compared with serial number arithmetic (RFC 1982)
+    pub expiration: u32,
+    pub inception: u32,
+    pub key_tag: u16,
+    /// The zone whose key made the signature
+    pub signer: String,
+    pub signature: Vec<u8>,
+}
+
+impl RrsigData {
+    pub fn read(buffer: &mut BytePacketBuffer, data_len: u16) -> Result<RrsigData> {
+        let end = buffer.pos() + data_len as usize;
+        let type_covered = buffer.read_u16()?;
+        let algorithm = buffer.read()?;
+        let labels = buffer.read()?;
+        let original_ttl = buffer.read_u32()?;
+        let expiration = buffer.read_u32()?;
+        let inception = buffer.read_u32()?;
+        let key_tag = buffer.read_u16()?;
+        let mut signer = String::new();
+        buffer.read_qname(&mut signer)?;
+        let Some(len) = end.checked_sub(buffer.pos()) else {
+            return Err("RRSIG signer overruns the record".into());
+        };
+        let signature = buffer.get_range(buffer.pos(), len)?.to_vec();
+        buffer.step(len)?;
+
+        Ok(RrsigData {
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            signer,
+            signature,
+        })
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        self.write_unsigned(buffer)?;
+        for b in &self.signature {
+            buffer.write_u8(*b)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write everything but the signature itself, which is the start of the data the
+    /// signature covers (RFC 4034 section 3.1.8.1)
+    pub fn write_unsigned(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_u16(self.type_covered)?;
+        buffer.write_u8(self.algorithm)?;
+        buffer.write_u8(self.labels)?;
+        buffer.write_u32(self.original_ttl)?;
+        buffer.write_u32(self.expiration)?;
+        buffer.write_u32(self.inception)?;
+        buffer.write_u16(self.key_tag)?;
+        // Names in DNSSEC records are never compressed (RFC 4034 section 3.1.7)
+        buffer.write_qname_uncompressed(&self.signer)?;
+
+        Ok(())
+    }
+
+    /// Parse the presentation format, like
+    /// `A 13 2 300 20261101000000 20261001000000 12345 example.com. <base64>`
+    pub fn from_text(text: &str) -> Option<RrsigData> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() < 8 {
+            return None;
+        }
+        Some(RrsigData {
+            type_covered: fields[0].parse::<QueryType>().ok()?.to_num(),
+            algorithm: fields[1].parse().ok()?,
+            labels: fields[2].parse().ok()?,
+            original_ttl: fields[3].parse().ok()?,
+            expiration: parse_time(fields[4])?,
+            inception: parse_time(fields[5])?,
+            key_tag: fields[6].parse().ok()?,
+            signer: canonical_name(fields[7]).ok()?,
+            // Long base64 is often split over several fields
+            signature: STANDARD.decode(fields[8..].concat()).ok()?,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        format!(
+            "{} {} {} {} {} {} {} {}. {}",
+            QueryType::from_num(self.type_covered),
+            self.algorithm,
+            self.labels,
+            self.original_ttl,
+            format_time(self.expiration),
+            format_time(self.inception),
+            self.key_tag,
+            self.signer,
+            STANDARD.encode(&self.signature)
+        )
+    }
+}
+
+/// The RDATA of an NSEC3 record (RFC 5155 section 3)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Nsec3Data {
+    pub hash_algorithm: u8,
+    pub flags: u8,
+    pub iterations: u16,
+    pub salt: Vec<u8>,
+    /// The hash of the next name in the zone, in hash order
+    pub next_hashed: Vec<u8>,
+    pub types: Vec<u16>,
+}
+
+impl Nsec3Data {
+    pub fn read(buffer: &mut BytePacketBuffer, data_len: u16) -> Result<Nsec3Data> {
+        let end = buffer.pos() + data_len as usize;
+        let hash_algorithm = buffer.read()?;
+        let flags = buffer.read()?;
+        let iterations = buffer.read_u16()?;
+        let salt_len = buffer.read()? as usize;
+        let salt = buffer.get_range(buffer.pos(), salt_len)?.to_vec();
+        buffer.step(salt_len)?;
+        let hash_len = buffer.read()? as usize;
+        if hash_len == 0 {
+            return Err("NSEC3 without a next hashed owner".into());
+        }
+        let next_hashed = buffer.get_range(buffer.pos(), hash_len)?.to_vec();
+        buffer.step(hash_len)?;
+        let types = read_type_bitmap(buffer, end)?;
+
+        Ok(Nsec3Data {
+            hash_algorithm,
+            flags,
+            iterations,
+            salt,
+            next_hashed,
+            types,
+        })
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_u8(self.hash_algorithm)?;
+        buffer.write_u8(self.flags)?;
+        buffer.write_u16(self.iterations)?;
+        buffer.write_u8(self.salt.len() as u8)?;
+        for b in &self.salt {
+            buffer.write_u8(*b)?;
+        }
+        buffer.write_u8(self.next_hashed.len() as u8)?;
+        for b in &self.next_hashed {
+            buffer.write_u8(*b)?;
+        }
+        write_type_bitmap(buffer, &self.types)
+    }
+
+    /// The covered range may hold unsigned delegations (RFC 5155 section 6)
+    pub fn opt_out(&self) -> bool {
+        self.flags & 1 == 1
+    }
+
+    /// Parse the presentation format, like `1 0 0 - 2VPTU5TIMAMQTTGL4LUU9KG21E0AOR3S A RRSIG`
+    pub fn from_text(text: &str) -> Option<Nsec3Data> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() < 5 {
+            return None;
+        }
+        let salt = match fields[3] {
+            "-" => Vec::new(),
+            salt => from_hex(salt).filter(|salt| !salt.is_empty() && salt.len() < 256)?,
+        };
+        let next_hashed =
+            base32hex_decode(fields[4]).filter(|hash| !hash.is_empty() && hash.len() < 256)?;
+
+        Some(Nsec3Data {
+            hash_algorithm: fields[0].parse().ok()?,
+            flags: fields[1].parse().ok()?,
+            iterations: fields[2].parse().ok()?,
+            salt,
+            next_hashed,
+            types: types_from_text(&fields[5..])?,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let salt = match self.salt.is_empty() {
+            true => "-".to_string(),
+            false => to_hex(&self.salt),
+        };
+        let mut text = format!(
+            "{} {} {} {} {}",
+            self.hash_algorithm,
+            self.flags,
+            self.iterations,
+            salt,
+            base32hex_encode(&self.next_hashed)
+        );
+        if !self.types.is_empty() {
+            text.push(' ');
+            text.push_str(&types_to_text(&self.types));
+        }
+        text
+    }
+}
+
+/// Read the type bitmap of NSEC and NSEC3 records, running up to `end`
+/// (RFC 4034 section 4.1.2)
+pub fn read_type_bitmap(buffer: &mut BytePacketBuffer, end: usize) -> Result<Vec<u16>> {
+    let mut types = Vec::new();
+    let mut last_window = None;
+    while buffer.pos() < end {
+        let window = buffer.read()?;
+        let len = buffer.read()? as usize;
+        if last_window.is_some_and(|last| last >= window) || !(1..=32).contains(&len) {
+            return Err("Invalid type bitmap".into());
+        }
+        last_window = Some(window);
+        let bits = buffer.get_range(buffer.pos(), len)?;
+        for (i, byte) in bits.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(((window as u16) << 8) | (i * 8 + bit) as u16);
+                }
+            }
+        }
+        buffer.step(len)?;
+    }
+    if buffer.pos() != end {
+        return Err("Type bitmap overruns the record".into());
+    }
+    Ok(types)
+}
+
+/// Write a type bitmap, `types` has to be sorted
+pub fn write_type_bitmap(buffer: &mut BytePacketBuffer, types: &[u16]) -> Result<()> {
+    let mut types = types.iter().peekable();
+    while let Some(&first) = types.peek() {
+        let window = (first >> 8) as u8;
+        let mut bits = [0u8; 32];
+        let mut len = 0;
+        while let Some(&&qtype) = types.peek() {
+            if (qtype >> 8) as u8 != window {
+                break;
+            }
+            let low = (qtype & 0xFF) as usize;
+            bits[low / 8] |= 0x80 >> (low % 8);
+            len = low / 8 + 1;
+            types.next();
+        }
+        buffer.write_u8(window)?;
+        buffer.write_u8(len as u8)?;
+        for b in &bits[..len] {
+            buffer.write_u8(*b)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn types_to_text(types: &[u16]) -> String {
+    types
+        .iter()
+        .map(|qtype| QueryType::from_num(*qtype).to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse a list of type mnemonics, sorted and without duplicates like the bitmap holds them
+pub fn types_from_text(fields: &[&str]) -> Option<Vec<u16>> {
+    let mut types = fields
+        .iter()
+        .map(|qtype| qtype.parse::<QueryType>().ok().map(QueryType::to_num))
+        .collect::<Option<Vec<_>>>()?;
+    types.sort_unstable();
+    types.dedup();
+    Some(types)
+}
+
+/// The key tag of a DNSKEY, computed over its RDATA (RFC 4034 appendix B)
+pub fn key_tag(rdata: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for (i, b) in rdata.iter().enumerate() {
+        sum += match i % 2 {
+            0 => (*b as u32) << 8,
+            _ => *b as u32,
+        };
+    }
+    sum += (sum >> 16) & 0xFFFF;
+    (sum & 0xFFFF) as u16
+}
+
+/// Signature times in presentation format are `YYYYMMDDHHmmSS` in UTC
+fn format_time(time: u32) -> String {
+    DateTime::from_timestamp(time as i64, 0)
+        .map(|time| time.format("%Y%m%d%H%M%S").to_string())
+        .unwrap_or_else(|| time.to_string())
+}
+
+/// Accept `YYYYMMDDHHmmSS`, or the plain number of seconds that older zone files use
+fn parse_time(text: &str) -> Option<u32> {
+    if text.len() == 14 && text.bytes().all(|b| b.is_ascii_digit()) {
+        let time = NaiveDateTime::parse_from_str(text, "%Y%m%d%H%M%S").ok()?;
+        return u32::try_from(time.and_utc().timestamp()).ok();
+    }
+    text.parse().ok()
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+const BASE32HEX: &[u8; 32] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+/// Base32 with the extended hex alphabet and no padding, as used for NSEC3 hashes
+/// (RFC 4648 section 7)
+pub fn base32hex_encode(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for b in data {
+        bits = (bits << 8) | *b as u32;
+        count += 8;
+        while count >= 5 {
+            count -= 5;
+            text.push(BASE32HEX[((bits >> count) & 0x1F) as usize] as char);
+        }
+    }
+    if count > 0 {
+        text.push(BASE32HEX[((bits << (5 - count)) & 0x1F) as usize] as char);
+    }
+    text
+}
+
+pub fn base32hex_decode(text: &str) -> Option<Vec<u8>> {
+    // Lengths that can't come out of encoding whole bytes
+    if matches!(text.len() % 8, 1 | 3 | 6) {
+        return None;
+    }
+    let mut data = Vec::with_capacity(text.len() * 5 / 8);
+    let mut bits: u32 = 0;
+    let mut count = 0;
+    for c in text.bytes() {
+        let value = BASE32HEX
+            .iter()
+            .position(|d| *d == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u32;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+        }
+    }
+    Some(data)
+}
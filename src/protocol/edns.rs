@@ -0,0 +1,117 @@
+use super::{byte_packet_buffer::BytePacketBuffer, Result};
+
+/// The record type of the OPT pseudo-record
+pub const OPT_TYPE: u16 = 41;
+
+/// The UDP payload size we advertise and accept, small enough to avoid IP fragmentation
+/// on any common path (DNS Flag Day 2020)
+pub const EDNS_UDP_SIZE: u16 = 1232;
+
+/// The extended RCODE sent back when a client asks for an EDNS version we don't speak
+pub const BADVERS: u16 = 16;
+
+/// A single option carried in the OPT record
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// The EDNS(0) OPT pseudo-record (RFC 6891)
+///
+/// It rides in the additional section, but describes the message rather than any name, so
+/// it lives on the packet instead of among the other records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edns {
+    /// The largest UDP payload the sender can reassemble
+    pub udp_size: u16,
+    /// The upper 8 bits of the 12 bit RCODE, the lower 4 live in the header
+    pub extended_rcode: u8,
+    pub version: u8,
+    /// The sender understands DNSSEC records (RFC 3225)
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new() -> Edns {
+        Edns {
+            udp_size: EDNS_UDP_SIZE,
+            extended_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    /// Read the rest of an OPT record, after its owner name and type
+    pub fn read(buffer: &mut BytePacketBuffer) -> Result<Edns> {
+        let udp_size = buffer.read_u16()?;
+        let ttl = buffer.read_u32()?;
+        let data_len = buffer.read_u16()? as usize;
+
+        let end = buffer.pos() + data_len;
+        let mut options = Vec::new();
+        while buffer.pos() < end {
+            let code = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            let data = buffer.get_range(buffer.pos(), len)?.to_vec();
+            buffer.step(len)?;
+            options.push(EdnsOption { code, data });
+        }
+        if buffer.pos() != end {
+            return Err("EDNS option overruns the OPT record".into());
+        }
+
+        Ok(Edns {
+            udp_size,
+            extended_rcode: (ttl >> 24) as u8,
+            version: (ttl >> 16) as u8,
+            dnssec_ok: (ttl & 0x8000) > 0,
+            options,
+        })
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        // The owner name is always the root
+        buffer.write_u8(0)?;
+        buffer.write_u16(OPT_TYPE)?;
+        buffer.write_u16(self.udp_size)?;
+        buffer.write_u32(
+            ((self.extended_rcode as u32) << 24)
+                | ((self.version as u32) << 16)
+                | ((self.dnssec_ok as u32) << 15),
+        )?;
+
+        let pos = buffer.pos();
+        buffer.write_u16(0)?;
+
+        for option in &self.options {
+            buffer.write_u16(option.code)?;
+            buffer.write_u16(option.data.len() as u16)?;
+            for b in &option.data {
+                buffer.write_u8(*b)?;
+            }
+        }
+
+        let size = buffer.pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)?;
+
+        Ok(())
+    }
+
+    /// The number of bytes the record takes up on the wire
+    pub fn wire_len(&self) -> usize {
+        11 + self
+            .options
+            .iter()
+            .map(|option| 4 + option.data.len())
+            .sum::<usize>()
+    }
+
+    /// Set the full 12 bit RCODE, returning the part that belongs in the header
+    pub fn set_rcode(&mut self, rcode: u16) -> u8 {
+        self.extended_rcode = (rcode >> 4) as u8;
+        (rcode & 0x0F) as u8
+    }
+}
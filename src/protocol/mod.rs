@@ -3,8 +3,11 @@ pub mod dns_header;
 pub mod dns_packet;
 pub mod dns_question;
 pub mod dns_record;
+pub mod dnssec;
+pub mod edns;
 pub mod query_type;
 pub mod result_code;
+pub mod svcb;
 
 pub type Error = Box<dyn std::error::Error>;
 pub type Result<T> = anyhow::Result<T, Error>;
@@ -1,11 +1,27 @@
+use std::{fmt, str::FromStr};
+
 #[derive(PartialEq, Eq, Debug, Clone, Hash, Copy)]
+// The variants are named after the record types as the RFCs spell them
+#[allow(clippy::upper_case_acronyms)]
 pub enum QueryType {
     UNKNOWN(u16),
-    A,     // 1
-    NS,    // 2
-    CNAME, // 5
-    MX,    // 15
-    AAAA,  // 28
+    A,      // 1
+    NS,     // 2
+    CNAME,  // 5
+    SOA,    // 6
+    PTR,    // 12
+    MX,     // 15
+    TXT,    // 16
+    AAAA,   // 28
+    SRV,    // 33
+    DS,     // 43
+    RRSIG,  // 46
+    NSEC,   // 47
+    DNSKEY, // 48
+    NSEC3,  // 50
+    SVCB,   // 64
+    HTTPS,  // 65
+    CAA,    // 257
 }
 
 impl QueryType {
@@ -15,8 +31,20 @@ impl QueryType {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
+            QueryType::TXT => 16,
             QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::DS => 43,
+            QueryType::RRSIG => 46,
+            QueryType::NSEC => 47,
+            QueryType::DNSKEY => 48,
+            QueryType::NSEC3 => 50,
+            QueryType::SVCB => 64,
+            QueryType::HTTPS => 65,
+            QueryType::CAA => 257,
         }
     }
 
@@ -25,9 +53,65 @@ impl QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            43 => QueryType::DS,
+            46 => QueryType::RRSIG,
+            47 => QueryType::NSEC,
+            48 => QueryType::DNSKEY,
+            50 => QueryType::NSEC3,
+            64 => QueryType::SVCB,
+            65 => QueryType::HTTPS,
+            257 => QueryType::CAA,
             _ => QueryType::UNKNOWN(num),
         }
     }
 }
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryType::UNKNOWN(x) => write!(f, "TYPE{}", x),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = String;
+
+    /// Parse a type mnemonic such as `AAAA`, the generic `TYPE28` form (RFC 3597), or a
+    /// plain number
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let upper = s.to_ascii_uppercase();
+        let num = match upper.as_str() {
+            "A" => 1,
+            "NS" => 2,
+            "CNAME" => 5,
+            "SOA" => 6,
+            "PTR" => 12,
+            "MX" => 15,
+            "TXT" => 16,
+            "AAAA" => 28,
+            "SRV" => 33,
+            "DS" => 43,
+            "RRSIG" => 46,
+            "NSEC" => 47,
+            "DNSKEY" => 48,
+            "NSEC3" => 50,
+            "SVCB" => 64,
+            "HTTPS" => 65,
+            "CAA" => 257,
+            other => other
+                .strip_prefix("TYPE")
+                .unwrap_or(other)
+                .parse::<u16>()
+                .map_err(|_| format!("Unknown record type {}", s))?,
+        };
+        Ok(QueryType::from_num(num))
+    }
+}
@@ -1,4 +1,6 @@
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+// The variants are named after the RCODEs as the RFCs spell them
+#[allow(clippy::upper_case_acronyms)]
 pub enum ResultCode {
     NOERROR = 0,
     FORMERR = 1,
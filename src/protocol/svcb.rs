@@ -0,0 +1,415 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use super::{
+    byte_packet_buffer::{canonical_name, BytePacketBuffer},
+    dns_record::{parse_strings, quote},
+    Result,
+};
+
+/// A single SvcParam of a SVCB or HTTPS record (RFC 9460 section 7)
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SvcParam {
+    Mandatory(Vec<u16>),     // 0
+    Alpn(Vec<Vec<u8>>),      // 1
+    NoDefaultAlpn,           // 2
+    Port(u16),               // 3
+    Ipv4Hint(Vec<Ipv4Addr>), // 4
+    Ech(Vec<u8>),            // 5
+    Ipv6Hint(Vec<Ipv6Addr>), // 6
+    Unknown(u16, Vec<u8>),
+}
+
+impl SvcParam {
+    pub fn key(&self) -> u16 {
+        match *self {
+            SvcParam::Mandatory(_) => 0,
+            SvcParam::Alpn(_) => 1,
+            SvcParam::NoDefaultAlpn => 2,
+            SvcParam::Port(_) => 3,
+            SvcParam::Ipv4Hint(_) => 4,
+            SvcParam::Ech(_) => 5,
+            SvcParam::Ipv6Hint(_) => 6,
+            SvcParam::Unknown(key, _) => key,
+        }
+    }
+
+    fn key_name(key: u16) -> String {
+        match key {
+            0 => "mandatory".to_string(),
+            1 => "alpn".to_string(),
+            2 => "no-default-alpn".to_string(),
+            3 => "port".to_string(),
+            4 => "ipv4hint".to_string(),
+            5 => "ech".to_string(),
+            6 => "ipv6hint".to_string(),
+            _ => format!("key{}", key),
+        }
+    }
+
+    fn key_from_name(name: &str) -> Option<u16> {
+        match name {
+            "mandatory" => Some(0),
+            "alpn" => Some(1),
+            "no-default-alpn" => Some(2),
+            "port" => Some(3),
+            "ipv4hint" => Some(4),
+            "ech" => Some(5),
+            "ipv6hint" => Some(6),
+            _ => name.strip_prefix("key")?.parse().ok(),
+        }
+    }
+
+    /// Decode the wire format value of the param with `key`
+    fn from_wire(key: u16, data: &[u8]) -> Option<SvcParam> {
+        let param = match key {
+            0 if !data.is_empty() && data.len().is_multiple_of(2) => SvcParam::Mandatory(
+                data.chunks(2)
+                    .map(|key| u16::from_be_bytes([key[0], key[1]]))
+                    .collect(),
+            ),
+            1 => {
+                let mut ids = Vec::new();
+                let mut rest = data;
+                while let Some((&len, tail)) = rest.split_first() {
+                    if len == 0 || tail.len() < len as usize {
+                        return None;
+                    }
+                    let (id, tail) = tail.split_at(len as usize);
+                    // Protocol ids are bytes, nothing says they have to be text
+                    ids.push(id.to_vec());
+                    rest = tail;
+                }
+                if ids.is_empty() {
+                    return None;
+                }
+                SvcParam::Alpn(ids)
+            }
+            2 if data.is_empty() => SvcParam::NoDefaultAlpn,
+            3 if data.len() == 2 => SvcParam::Port(u16::from_be_bytes([data[0], data[1]])),
+            4 if !data.is_empty() && data.len().is_multiple_of(4) => SvcParam::Ipv4Hint(
+                data.chunks(4)
+                    .map(|addr| Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]))
+                    .collect(),
+            ),
+            5 => SvcParam::Ech(data.to_vec()),
+            6 if !data.is_empty() && data.len().is_multiple_of(16) => SvcParam::Ipv6Hint(
+                data.chunks(16)
+                    .map(|addr| Ipv6Addr::from(<[u8; 16]>::try_from(addr).unwrap()))
+                    .collect(),
+            ),
+            0..=6 => return None,
+            _ => SvcParam::Unknown(key, data.to_vec()),
+        };
+        Some(param)
+    }
+
+    /// Encode the value of the param in wire format
+    fn to_wire(&self) -> Vec<u8> {
+        match self {
+            SvcParam::Mandatory(keys) => keys.iter().flat_map(|key| key.to_be_bytes()).collect(),
+            SvcParam::Alpn(ids) => ids
+                .iter()
+                .flat_map(|id| std::iter::once(id.len() as u8).chain(id.iter().copied()))
+                .collect(),
+            SvcParam::NoDefaultAlpn => Vec::new(),
+            SvcParam::Port(port) => port.to_be_bytes().to_vec(),
+            SvcParam::Ipv4Hint(addrs) => addrs.iter().flat_map(|addr| addr.octets()).collect(),
+            SvcParam::Ech(config) => config.clone(),
+            SvcParam::Ipv6Hint(addrs) => addrs.iter().flat_map(|addr| addr.octets()).collect(),
+            SvcParam::Unknown(_, data) => data.clone(),
+        }
+    }
+
+    /// Parse a param in presentation format, like `alpn=h2,h3` or `no-default-alpn`
+    fn from_text(text: &str) -> Option<SvcParam> {
+        let (name, value) = text.split_once('=').unwrap_or((text, ""));
+        let key = SvcParam::key_from_name(name)?;
+        // Values may be quoted, the list separators are the same either way
+        let value = if value.starts_with('"') {
+            parse_strings(value)?.concat()
+        } else {
+            value.as_bytes().to_vec()
+        };
+        let value = String::from_utf8(value).ok();
+        let list = || value.as_deref().map(|value| value.split(','));
+
+        let param = match key {
+            0 => SvcParam::Mandatory(
+                list()?
+                    .map(SvcParam::key_from_name)
+                    .collect::<Option<_>>()?,
+            ),
+            1 => SvcParam::Alpn(alpn_ids(text.split_once('=')?.1)?),
+            2 => SvcParam::NoDefaultAlpn,
+            3 => SvcParam::Port(value?.parse().ok()?),
+            4 => SvcParam::Ipv4Hint(
+                list()?
+                    .map(|addr| addr.parse().ok())
+                    .collect::<Option<_>>()?,
+            ),
+            5 => SvcParam::Ech(STANDARD.decode(value?).ok()?),
+            6 => SvcParam::Ipv6Hint(
+                list()?
+                    .map(|addr| addr.parse().ok())
+                    .collect::<Option<_>>()?,
+            ),
+            _ => SvcParam::Unknown(key, value?.into_bytes()),
+        };
+        // Going through the wire format catches values that can't be encoded, like an
+        // empty alpn list
+        SvcParam::from_wire(key, &param.to_wire()).filter(|decoded| *decoded == param)
+    }
+
+    fn to_text(&self) -> String {
+        let name = SvcParam::key_name(self.key());
+        let join = |items: Vec<String>| items.join(",");
+        match self {
+            SvcParam::Mandatory(keys) => format!(
+                "{}={}",
+                name,
+                join(keys.iter().map(|key| SvcParam::key_name(*key)).collect())
+            ),
+            SvcParam::Alpn(ids) => format!(
+                "{}={}",
+                name,
+                join(ids.iter().map(|id| escape_alpn_id(id)).collect())
+            ),
+            SvcParam::NoDefaultAlpn => name,
+            SvcParam::Port(port) => format!("{}={}", name, port),
+            SvcParam::Ipv4Hint(addrs) => format!(
+                "{}={}",
+                name,
+                join(addrs.iter().map(|addr| addr.to_string()).collect())
+            ),
+            SvcParam::Ech(config) => format!("{}={}", name, STANDARD.encode(config)),
+            SvcParam::Ipv6Hint(addrs) => format!(
+                "{}={}",
+                name,
+                join(addrs.iter().map(|addr| addr.to_string()).collect())
+            ),
+            SvcParam::Unknown(_, data) => format!("{}={}", name, quote(data)),
+        }
+    }
+}
+
+/// The RDATA shared by SVCB and HTTPS records
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SvcbData {
+    /// 0 makes this an alias for `target`, anything else is a service endpoint
+    pub priority: u16,
+    pub target: String,
+    pub params: Vec<SvcParam>,
+}
+
+impl SvcbData {
+    pub fn read(buffer: &mut BytePacketBuffer, data_len: u16) -> Result<SvcbData> {
+        let end = buffer.pos() + data_len as usize;
+        let priority = buffer.read_u16()?;
+        let mut target = String::new();
+        buffer.read_qname(&mut target)?;
+
+        let mut params: Vec<SvcParam> = Vec::new();
+        while buffer.pos() < end {
+            let key = buffer.read_u16()?;
+            let len = buffer.read_u16()? as usize;
+            // Keys have to be strictly increasing, which also rules out duplicates
+            if params.last().is_some_and(|last| last.key() >= key) {
+                return Err("SvcParams out of order".into());
+            }
+            let data = buffer.get_range(buffer.pos(), len)?;
+            let param = SvcParam::from_wire(key, data)
+                .ok_or_else(|| format!("Invalid {} SvcParam", SvcParam::key_name(key)))?;
+            buffer.step(len)?;
+            params.push(param);
+        }
+        if buffer.pos() != end {
+            return Err("SvcParam overruns the record".into());
+        }
+
+        Ok(SvcbData {
+            priority,
+            target,
+            params,
+        })
+    }
+
+    pub fn write(&self, buffer: &mut BytePacketBuffer) -> Result<()> {
+        buffer.write_u16(self.priority)?;
+        // The target is never compressed (RFC 9460 section 2.2)
+        buffer.write_qname_uncompressed(&self.target)?;
+
+        let mut params: Vec<&SvcParam> = self.params.iter().collect();
+        params.sort_by_key(|param| param.key());
+        for param in params {
+            let data = param.to_wire();
+            buffer.write_u16(param.key())?;
+            buffer.write_u16(data.len() as u16)?;
+            for b in data {
+                buffer.write_u8(b)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parse the presentation format, like `1 . alpn=h2,h3 ipv4hint=192.0.2.1`
+    pub fn from_text(text: &str) -> Option<SvcbData> {
+        let mut fields = split_fields(text).into_iter();
+        let priority = fields.next()?.parse().ok()?;
+        let target = canonical_name(fields.next()?).ok()?;
+        let mut params = fields
+            .map(SvcParam::from_text)
+            .collect::<Option<Vec<_>>>()?;
+        params.sort_by_key(|param| param.key());
+        if params.windows(2).any(|pair| pair[0].key() == pair[1].key()) {
+            return None;
+        }
+
+        Some(SvcbData {
+            priority,
+            target,
+            params,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{} {}.", self.priority, self.target);
+        if self.target.is_empty() {
+            // The root is written as a lone dot
+            text = format!("{} .", self.priority);
+        }
+        for param in &self.params {
+            text.push(' ');
+            text.push_str(&param.to_text());
+        }
+        text
+    }
+}
+
+/// Parse the comma separated protocol ids of an alpn value, a comma inside an id has to
+/// be escaped, and any byte can be given as `\DDD`
+fn alpn_ids(value: &str) -> Option<Vec<Vec<u8>>> {
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+    let bytes = value.as_bytes();
+    let mut ids = vec![Vec::new()];
+    let mut i = 0;
+    while let Some(&b) = bytes.get(i) {
+        match b {
+            b'\\' => {
+                let digits = bytes
+                    .get(i + 1..i + 4)
+                    .filter(|d| d.iter().all(u8::is_ascii_digit));
+                let id = ids.last_mut()?;
+                if let Some(digits) = digits {
+                    let value = std::str::from_utf8(digits).ok()?.parse::<u16>().ok()?;
+                    id.push(u8::try_from(value).ok()?);
+                    i += 4;
+                } else {
+                    id.push(*bytes.get(i + 1)?);
+                    i += 2;
+                }
+            }
+            b',' => {
+                ids.push(Vec::new());
+                i += 1;
+            }
+            b => {
+                ids.last_mut()?.push(b);
+                i += 1;
+            }
+        }
+    }
+    Some(ids)
+}
+
+/// Write a protocol id so `alpn_ids` reads it back the same, with commas and anything
+/// unprintable escaped
+fn escape_alpn_id(id: &[u8]) -> String {
+    let mut escaped = String::new();
+    for &b in id {
+        match b {
+            b'\\' | b'"' => {
+                escaped.push('\\');
+                escaped.push(b as char);
+            }
+            0x21..=0x7E if b != b',' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    escaped
+}
+
+/// Split on whitespace, except inside quotes, so `key65000="a b"` stays together
+fn split_fields(text: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if let Some(start) = start.take() {
+                    fields.push(&text[start..i]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(start) = start {
+        fields.push(&text[start..]);
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alpn_ids_are_kept_as_bytes() {
+        // h2 and an id that isn't UTF-8
+        let wire = [0, 1, 0, 0, 1, 0, 6, 2, b'h', b'2', 2, 0xFF, b'x'];
+        let mut buffer = BytePacketBuffer::from_bytes(&wire);
+        let data = SvcbData::read(&mut buffer, wire.len() as u16).unwrap();
+        assert_eq!(
+            data.params,
+            vec![SvcParam::Alpn(vec![b"h2".to_vec(), vec![0xFF, b'x']])]
+        );
+
+        let mut written = BytePacketBuffer::new();
+        data.write(&mut written).unwrap();
+        assert_eq!(&written.buf[..written.pos()], &wire);
+    }
+
+    #[test]
+    fn alpn_ids_round_trip_through_text() {
+        let data = SvcbData {
+            priority: 1,
+            target: String::new(),
+            params: vec![SvcParam::Alpn(vec![
+                b"h2".to_vec(),
+                b"a,b".to_vec(),
+                vec![0xFF, b'"'],
+            ])],
+        };
+        let text = data.to_text();
+        assert_eq!(text, r#"1 . alpn=h2,a\044b,\255\""#);
+        assert_eq!(SvcbData::from_text(&text), Some(data));
+
+        let quoted = SvcbData::from_text(r#"1 . alpn="h2,h3""#).unwrap();
+        assert_eq!(
+            quoted.params,
+            vec![SvcParam::Alpn(vec![b"h2".to_vec(), b"h3".to_vec()])]
+        );
+    }
+}
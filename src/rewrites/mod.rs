@@ -3,14 +3,57 @@ use std::{net::IpAddr, sync::Arc};
 use dashmap::DashMap;
 use serde_derive::Deserialize;
 use tokio::sync::Mutex;
-use tracing::info;
+use tracing::{error, info};
 
-use crate::protocol::dns_record::DnsRecord;
+use crate::protocol::{dns_record::DnsRecord, query_type::QueryType};
+
+/// TTL given to rewritten records
+const REWRITE_TTL: u32 = 500;
+
+/// How long a missing record type of a rewritten host may be cached, when no SOA rewrite
+/// says otherwise
+const NEGATIVE_TTL: u32 = 60;
 
 #[derive(Clone, Deserialize)]
 pub struct RewriteRule {
     pub host: String,
-    pub ip: IpAddr,
+    #[serde(flatten)]
+    pub value: RewriteValue,
+}
+
+/// What a host is rewritten to, either an address or a record of any type in its zone
+/// file form, e.g. `type: TYPE65534` with `value: \# 3 abcdef`
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+pub enum RewriteValue {
+    Ip {
+        ip: IpAddr,
+    },
+    Record {
+        #[serde(rename = "type")]
+        qtype: String,
+        value: String,
+    },
+}
+
+impl RewriteRule {
+    pub fn record(&self) -> Result<DnsRecord, String> {
+        match &self.value {
+            RewriteValue::Ip { ip: IpAddr::V4(ip) } => Ok(DnsRecord::A {
+                domain: self.host.to_string(),
+                addr: *ip,
+                ttl: REWRITE_TTL,
+            }),
+            RewriteValue::Ip { ip: IpAddr::V6(ip) } => Ok(DnsRecord::AAAA {
+                domain: self.host.to_string(),
+                addr: *ip,
+                ttl: REWRITE_TTL,
+            }),
+            RewriteValue::Record { qtype, value } => {
+                DnsRecord::from_text(&self.host, qtype.parse()?, value, REWRITE_TTL)
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -19,8 +62,10 @@ pub struct Rewrites {
 }
 
 pub struct RewritesData {
-    pub rewrites: DashMap<String, DnsRecord>,
+    pub rewrites: DashMap<String, Vec<DnsRecord>>,
     pub from_k8s: Mutex<Vec<String>>,
+    /// Every domain above a rewritten host, with how many rewritten hosts it has below it
+    pub ancestors: DashMap<String, usize>,
 }
 
 impl Rewrites {
@@ -29,36 +74,48 @@ impl Rewrites {
             data: Arc::new(RewritesData {
                 rewrites: DashMap::new(),
                 from_k8s: Mutex::new(Vec::new()),
+                ancestors: DashMap::new(),
             }),
         }
     }
 
     pub async fn add_rewrite(&self, rule: &RewriteRule) {
-        info!("Adding rewrite for {} -> {}", rule.host, rule.ip);
-        self.data.rewrites.insert(
-            rule.host.to_string(),
-            match rule.ip {
-                IpAddr::V4(ip) => DnsRecord::A {
-                    domain: rule.host.to_string(),
-                    addr: ip,
-                    ttl: 500,
-                },
-                IpAddr::V6(ip) => DnsRecord::AAAA {
-                    domain: rule.host.to_string(),
-                    addr: ip,
-                    ttl: 500,
-                },
-            },
+        let record = match rule.record() {
+            Ok(record) => record,
+            Err(err) => {
+                error!("Invalid rewrite for {}: {}", rule.host, err);
+                return;
+            }
+        };
+        info!(
+            "Adding rewrite for {} -> {} {}",
+            rule.host,
+            record.query_type(),
+            record.rdata_text()
         );
+        let host = rule.host.to_ascii_lowercase();
+        let mut records = self.data.rewrites.entry(host.clone()).or_insert_with(|| {
+            for ancestor in ancestors(&host) {
+                *self.data.ancestors.entry(ancestor.to_string()).or_default() += 1;
+            }
+            Vec::new()
+        });
+        if !records.contains(&record) {
+            records.push(record);
+        }
     }
 
     pub async fn add_k8s_rewrites(&self, rules: Vec<RewriteRule>) {
         // remove all rewrites from k8s
         let existing = self.data.from_k8s.lock().await;
-        self.data.rewrites.retain(|k, _| !existing.contains(k));
+        for host in existing.iter() {
+            if self.data.rewrites.remove(host).is_some() {
+                self.forget_ancestors(host);
+            }
+        }
         let mut new = Vec::new();
         for rule in &rules {
-            new.push(rule.host.clone());
+            new.push(rule.host.to_ascii_lowercase());
             self.add_rewrite(rule).await;
         }
         drop(existing);
@@ -70,15 +127,175 @@ impl Rewrites {
         );
     }
 
+    #[allow(dead_code)]
     pub async fn remove_rewrite(&self, host: &str) {
-        self.data.rewrites.remove(host);
+        let host = host.to_ascii_lowercase();
+        if self.data.rewrites.remove(&host).is_some() {
+            self.forget_ancestors(&host);
+        }
+    }
+
+    /// The rewritten records of `qtype` for `host`, or `None` if the host isn't rewritten
+    ///
+    /// A rewritten host without records of that type gets an empty list, so it can be
+    /// answered without data instead of being looked up upstream. A CNAME answers for
+    /// every type. Hosts are matched case-insensitively.
+    pub async fn get_rewrite(&self, host: &str, qtype: QueryType) -> Option<Vec<DnsRecord>> {
+        self.data
+            .rewrites
+            .get(&host.to_ascii_lowercase())
+            .map(|records| {
+                records
+                    .iter()
+                    .filter(|rec| {
+                        let rtype = rec.query_type();
+                        rtype == qtype || rtype == QueryType::CNAME
+                    })
+                    .cloned()
+                    .collect()
+            })
+    }
+
+    /// The types of all records rewritten for `host`
+    pub async fn record_types(&self, host: &str) -> Vec<QueryType> {
+        self.data
+            .rewrites
+            .get(&host.to_ascii_lowercase())
+            .map(|records| records.iter().map(DnsRecord::query_type).collect())
+            .unwrap_or_default()
+    }
+
+    /// Whether a rewritten host lies below `host`, which makes it exist even without
+    /// records of its own
+    pub async fn has_subdomains(&self, host: &str) -> bool {
+        self.data.ancestors.contains_key(&host.to_ascii_lowercase())
+    }
+
+    /// Count a removed host out of the domains above it
+    fn forget_ancestors(&self, host: &str) {
+        for ancestor in ancestors(host) {
+            self.data.ancestors.remove_if_mut(ancestor, |_, count| {
+                *count -= 1;
+                *count == 0
+            });
+        }
     }
 
-    pub async fn get_rewrite(&self, host: &str) -> Option<DnsRecord> {
-        self.data.rewrites.get(host).map(|r| r.value().clone())
+    /// The SOA to send along when a rewritten host has no records of the asked type (RFC 2308)
+    ///
+    /// A SOA rewrite for the host or the closest domain above it is used if there is one,
+    /// otherwise one is made up with the host as its own zone.
+    pub async fn negative_soa(&self, host: &str) -> DnsRecord {
+        let host = host.to_ascii_lowercase();
+        let mut zone = host.as_str();
+        loop {
+            let soa = self.data.rewrites.get(zone).and_then(|records| {
+                records
+                    .iter()
+                    .find(|rec| rec.query_type() == QueryType::SOA)
+                    .cloned()
+            });
+            if let Some(soa) = soa {
+                return soa;
+            }
+            match zone.split_once('.') {
+                Some((_, parent)) => zone = parent,
+                None => break,
+            }
+        }
+
+        DnsRecord::SOA {
+            domain: host.to_string(),
+            m_name: host.to_string(),
+            r_name: format!("hostmaster.{}", host),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: NEGATIVE_TTL,
+            ttl: NEGATIVE_TTL,
+        }
     }
 
-    pub async fn get_rewrites(&self) -> DashMap<String, DnsRecord> {
+    #[allow(dead_code)]
+    pub async fn get_rewrites(&self) -> DashMap<String, Vec<DnsRecord>> {
         self.data.rewrites.clone()
     }
 }
+
+/// Every domain strictly above `host`
+fn ancestors(host: &str) -> impl Iterator<Item = &str> {
+    host.match_indices('.').map(move |(i, _)| &host[i + 1..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(host: &str, ip: &str) -> RewriteRule {
+        RewriteRule {
+            host: host.to_string(),
+            value: RewriteValue::Ip {
+                ip: ip.parse().unwrap(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn names_above_rewrites_have_subdomains() {
+        let rewrites = Rewrites::new();
+        rewrites.add_rewrite(&rule("a.b.lan", "192.0.2.1")).await;
+        rewrites.add_rewrite(&rule("a.b.lan", "2001:db8::1")).await;
+        for host in ["b.lan", "B.Lan", "lan"] {
+            assert!(rewrites.has_subdomains(host).await, "{}", host);
+        }
+        for host in ["a.b.lan", "c.lan", "b", ""] {
+            assert!(!rewrites.has_subdomains(host).await, "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn replaced_k8s_rewrites_leave_no_subdomains_behind() {
+        let rewrites = Rewrites::new();
+        rewrites.add_rewrite(&rule("static.lan", "192.0.2.1")).await;
+        rewrites
+            .add_k8s_rewrites(vec![
+                rule("web.apps.lan", "192.0.2.2"),
+                rule("api.apps.lan", "192.0.2.3"),
+            ])
+            .await;
+        assert!(rewrites.has_subdomains("apps.lan").await);
+
+        rewrites
+            .add_k8s_rewrites(vec![rule("web.other.lan", "192.0.2.2")])
+            .await;
+        assert!(!rewrites.has_subdomains("apps.lan").await);
+        assert!(rewrites.has_subdomains("other.lan").await);
+        assert!(rewrites.has_subdomains("lan").await);
+
+        rewrites.add_k8s_rewrites(Vec::new()).await;
+        assert!(!rewrites.has_subdomains("other.lan").await);
+        // The static rewrite still holds it up
+        assert_eq!(*rewrites.data.ancestors.get("lan").unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn removed_rewrites_leave_no_subdomains_behind() {
+        let rewrites = Rewrites::new();
+        rewrites.add_rewrite(&rule("a.b.lan", "192.0.2.1")).await;
+        rewrites.add_rewrite(&rule("c.lan", "192.0.2.2")).await;
+
+        rewrites.remove_rewrite("A.B.lan").await;
+        assert!(rewrites
+            .get_rewrite("a.b.lan", QueryType::A)
+            .await
+            .is_none());
+        assert!(!rewrites.has_subdomains("b.lan").await);
+        assert_eq!(*rewrites.data.ancestors.get("lan").unwrap(), 1);
+
+        // Removing it again doesn't count it out twice
+        rewrites.remove_rewrite("a.b.lan").await;
+        assert!(rewrites.has_subdomains("lan").await);
+        assert_eq!(rewrites.get_rewrites().await.len(), 1);
+    }
+}